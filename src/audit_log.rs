@@ -0,0 +1,455 @@
+//! Local audit log.
+//!
+//! The audit events of connections, file transfers and alarms are written as JSON lines
+//! to `audit.jsonl` in the audit log directory, by default if no API server is configured.
+//! Each line is `{"seq": n, "prev": "<hash>", "time": <ms since epoch>, "type": "conn" | "file" |
+//! "alarm" | "clipboard", "event": {...}}`, the event is what is posted to the API server.
+//! `seq` and `prev` chain the lines, see [`chain`].
+//!
+//! The file is rotated to `audit-<time>.jsonl` when it exceeds the max size, or on a new day,
+//! and the oldest rotated files are removed when they are too many or too old.
+//! On unix, the lines can also be sent to syslog (journald on systemd).
+
+use hbb_common::{config::Config, get_time, log};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+pub mod chain;
+pub mod outbox;
+
+/// "Y" always, "N" never, otherwise only if no API server is configured.
+pub const OPTION_LOCAL_AUDIT_LOG: &str = "local-audit-log";
+pub const OPTION_AUDIT_LOG_DIR: &str = "audit-log-dir";
+/// In MB.
+pub const OPTION_AUDIT_LOG_MAX_SIZE: &str = "audit-log-max-size";
+pub const OPTION_AUDIT_LOG_MAX_FILES: &str = "audit-log-max-files";
+/// Rotated files older than this are removed, 0 is never.
+pub const OPTION_AUDIT_LOG_RETENTION_DAYS: &str = "audit-log-retention-days";
+/// "Y" to send to syslog too.
+pub const OPTION_AUDIT_LOG_SYSLOG: &str = "audit-log-syslog";
+
+const FILE_NAME: &str = "audit.jsonl";
+const ROTATED_PREFIX: &str = "audit-";
+const ROTATED_SUFFIX: &str = ".jsonl";
+const DEFAULT_MAX_SIZE_MB: u64 = 10;
+const DEFAULT_MAX_FILES: usize = 10;
+const DEFAULT_RETENTION_DAYS: u64 = 90;
+const DAY_MS: i64 = 24 * 3600 * 1000;
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<mpsc::Sender<(i64, String, Value)>>> = Default::default();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Settings {
+    dir: PathBuf,
+    max_size: u64,
+    // rotated files kept
+    max_files: usize,
+    retention: Option<Duration>,
+    syslog: bool,
+}
+
+impl Settings {
+    fn load() -> Self {
+        let parse = |name: &str, default: u64| {
+            Config::get_option(name)
+                .trim()
+                .parse::<u64>()
+                .unwrap_or(default)
+        };
+        let retention_days = parse(OPTION_AUDIT_LOG_RETENTION_DAYS, DEFAULT_RETENTION_DAYS);
+        Self {
+            dir: dir(),
+            max_size: parse(OPTION_AUDIT_LOG_MAX_SIZE, DEFAULT_MAX_SIZE_MB).max(1) << 20,
+            max_files: parse(OPTION_AUDIT_LOG_MAX_FILES, DEFAULT_MAX_FILES as _) as _,
+            retention: (retention_days > 0)
+                .then(|| Duration::from_secs(retention_days * 24 * 3600)),
+            syslog: Config::get_option(OPTION_AUDIT_LOG_SYSLOG) == "Y",
+        }
+    }
+}
+
+pub fn dir() -> PathBuf {
+    let dir = Config::get_option(OPTION_AUDIT_LOG_DIR);
+    if dir.is_empty() {
+        Config::path("audit")
+    } else {
+        PathBuf::from(dir)
+    }
+}
+
+/// Whether the events are written locally, `has_api_server` is whether they are posted.
+pub fn is_enabled(has_api_server: bool) -> bool {
+    match Config::get_option(OPTION_LOCAL_AUDIT_LOG).as_str() {
+        "Y" => true,
+        "N" => false,
+        _ => !has_api_server,
+    }
+}
+
+/// Write an audit event of `typ` in the background.
+pub fn record(typ: &str, event: &Value) {
+    let time = get_time();
+    let mut sender = SENDER.lock().unwrap();
+    if sender.is_none() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || write_loop(rx));
+        *sender = Some(tx);
+    }
+    if let Some(tx) = sender.as_ref() {
+        tx.send((time, typ.to_owned(), event.clone())).ok();
+    }
+}
+
+fn write_loop(rx: mpsc::Receiver<(i64, String, Value)>) {
+    let mut writer: Option<Writer> = None;
+    while let Ok((time, typ, event)) = rx.recv() {
+        let settings = Settings::load();
+        let syslog = settings.syslog;
+        if writer.as_ref().map(|w| &w.settings) != Some(&settings) {
+            writer = match Writer::open(settings) {
+                Ok(w) => Some(w),
+                Err(err) => {
+                    log::error!("Failed to open audit log: {}", err);
+                    None
+                }
+            };
+        }
+        let line = match writer.as_mut().map(|w| w.append(time, &typ, &event)) {
+            Some(Ok(line)) => Some(line),
+            Some(Err(err)) => {
+                log::error!("Failed to write audit log: {}", err);
+                writer = None;
+                None
+            }
+            None => None,
+        };
+        if syslog {
+            let line = line.unwrap_or_else(|| {
+                json!({
+                    "time": time,
+                    "type": typ,
+                    "event": event,
+                })
+                .to_string()
+            });
+            send_syslog(&line);
+        }
+    }
+}
+
+struct Writer {
+    settings: Settings,
+    file: File,
+    size: u64,
+    // day of the first line in the file, days since epoch
+    day: i64,
+    head: chain::Head,
+}
+
+impl Writer {
+    fn open(settings: Settings) -> std::io::Result<Self> {
+        fs::create_dir_all(&settings.dir)?;
+        let path = settings.dir.join(FILE_NAME);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let meta = file.metadata()?;
+        let day = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|t| t.as_millis() as i64 / DAY_MS)
+            .unwrap_or_else(|| get_time() / DAY_MS);
+        let w = Self {
+            size: meta.len(),
+            day,
+            head: chain::Head::find(&settings.dir),
+            settings,
+            file,
+        };
+        w.prune();
+        Ok(w)
+    }
+
+    fn append(&mut self, time: i64, typ: &str, event: &Value) -> std::io::Result<String> {
+        let line = self.head.append(time, typ, event);
+        self.write(time, &line)?;
+        self.head.save(&self.settings.dir)?;
+        Ok(line)
+    }
+
+    fn write(&mut self, time: i64, line: &str) -> std::io::Result<()> {
+        let day = time / DAY_MS;
+        if self.size > 0 && (self.size >= self.settings.max_size || day != self.day) {
+            self.rotate(time)?;
+        }
+        if self.size == 0 {
+            self.day = day;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self, time: i64) -> std::io::Result<()> {
+        let dir = &self.settings.dir;
+        let mut rotated = dir.join(format!("{}{}{}", ROTATED_PREFIX, time, ROTATED_SUFFIX));
+        let mut n = 0;
+        while rotated.exists() {
+            n += 1;
+            rotated = dir.join(format!(
+                "{}{}.{}{}",
+                ROTATED_PREFIX, time, n, ROTATED_SUFFIX
+            ));
+        }
+        fs::rename(dir.join(FILE_NAME), rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(FILE_NAME))?;
+        self.size = 0;
+        self.prune();
+        Ok(())
+    }
+
+    // The oldest files only, the chain starts after the last removed line.
+    fn prune(&self) {
+        let dir = &self.settings.dir;
+        let files = rotated_files(dir);
+        let excess = files.len().saturating_sub(self.settings.max_files);
+        for (i, path) in files.iter().enumerate() {
+            let expired = self.settings.retention.map_or(false, |retention| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.elapsed().ok())
+                    .map_or(false, |age| age > retention)
+            });
+            if i >= excess && !expired {
+                break;
+            }
+            log::info!("Remove audit log {}", path.display());
+            let start = chain::Head::last_in(path);
+            if let Err(err) = fs::remove_file(path) {
+                log::error!("Failed to remove audit log {}: {}", path.display(), err);
+                break;
+            }
+            if let Some(start) = start {
+                if let Err(err) = start.save_start(dir) {
+                    log::error!("Failed to save the start of the audit log chain: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Rotated files, the oldest first.
+fn rotated_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<(i64, String, PathBuf)> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    let time = name
+                        .strip_prefix(ROTATED_PREFIX)?
+                        .strip_suffix(ROTATED_SUFFIX)?
+                        .split('.')
+                        .next()?
+                        .parse::<i64>()
+                        .ok()?;
+                    Some((time, name, e.path()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files.into_iter().map(|(_, _, path)| path).collect()
+}
+
+#[cfg(unix)]
+fn send_syslog(line: &str) {
+    use std::os::unix::net::UnixDatagram;
+    // LOG_AUTH | LOG_INFO
+    const PRIORITY: u8 = 4 * 8 + 6;
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let msg = format!("<{}>rustdesk-audit: {}", PRIORITY, line);
+    for path in ["/dev/log", "/var/run/syslog"] {
+        if socket.send_to(msg.as_bytes(), path).is_ok() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn send_syslog(_line: &str) {}
+
+/// The last `n` lines, from the rotated files too if the current file has fewer.
+fn last_lines(dir: &Path, n: usize) -> Vec<String> {
+    let mut files = rotated_files(dir);
+    files.push(dir.join(FILE_NAME));
+    let mut lines = Vec::new();
+    for path in files.iter().rev() {
+        if lines.len() >= n {
+            break;
+        }
+        let Ok(text) = fs::read_to_string(path) else {
+            continue;
+        };
+        let mut file_lines: Vec<String> = text.lines().map(|x| x.to_owned()).collect();
+        let keep = file_lines.len().min(n - lines.len());
+        file_lines.drain(..file_lines.len() - keep);
+        file_lines.append(&mut lines);
+        lines = file_lines;
+    }
+    lines
+}
+
+fn follow(path: &Path) -> std::io::Result<()> {
+    let mut pos = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    loop {
+        std::thread::sleep(Duration::from_millis(500));
+        let Ok(mut file) = File::open(path) else {
+            continue;
+        };
+        let len = file.metadata()?.len();
+        if len < pos {
+            // rotated
+            pos = 0;
+        }
+        if len == pos {
+            continue;
+        }
+        file.seek(SeekFrom::Start(pos))?;
+        let mut reader = BufReader::new(file.take(len - pos));
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            if !line.ends_with('\n') {
+                // incomplete, read it again next time
+                break;
+            }
+            pos += line.len() as u64;
+            print!("{}", line);
+            line.clear();
+        }
+        std::io::stdout().flush().ok();
+    }
+}
+
+const USAGE: &str = "Usage: rustdesk --audit-log <command>
+
+Commands:
+    tail [-n <lines>] [-f]
+        Print the last lines (10 by default) of the local audit log, and the new
+        lines as they are written if -f is given.
+    verify
+        Check that no line of the local audit log is modified or removed, except
+        the oldest files removed by rotation.
+        Exit code: 0 intact, 1 modified or removed lines found.";
+
+/// Entry of `rustdesk --audit-log ...`, `args` does not include `--audit-log` itself.
+///
+/// Returns the process exit code.
+pub fn run_cli(args: &[String]) -> i32 {
+    match args.first().map(|x| x.as_str()) {
+        Some("tail") => tail(&args[1..]),
+        Some("verify") if args.len() == 1 => match chain::verify(&dir()) {
+            Ok(report) => {
+                println!(
+                    "OK, seq {} to {}, {} lines before the chain",
+                    report.first_seq, report.last_seq, report.unchained
+                );
+                0
+            }
+            Err(err) => {
+                println!("FAILED, {}", err);
+                1
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+fn tail(args: &[String]) -> i32 {
+    let mut n = 10;
+    let mut follow_mode = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-f" => follow_mode = true,
+            "-n" => {
+                i += 1;
+                match args.get(i).and_then(|x| x.parse::<usize>().ok()) {
+                    Some(v) => n = v,
+                    None => {
+                        eprintln!("{}", USAGE);
+                        return 2;
+                    }
+                }
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+        i += 1;
+    }
+    let dir = dir();
+    for line in last_lines(&dir, n) {
+        println!("{}", line);
+    }
+    if follow_mode {
+        if let Err(err) = follow(&dir.join(FILE_NAME)) {
+            eprintln!("{}", err);
+            return 1;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-audit-test-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let settings = Settings {
+            dir: dir.clone(),
+            max_size: 100,
+            max_files: 2,
+            retention: None,
+            syslog: false,
+        };
+        let mut w = Writer::open(settings).unwrap();
+        let time = get_time();
+        for i in 0..10 {
+            w.write(
+                time + i,
+                &format!("{{\"n\":{},\"pad\":\"{}\"}}", i, "x".repeat(40)),
+            )
+            .unwrap();
+        }
+        assert_eq!(rotated_files(&dir).len(), 2);
+        let lines = last_lines(&dir, 3);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("{\"n\":9,"));
+        assert!(lines[0].starts_with("{\"n\":7,"));
+        w.write(time + DAY_MS, "{}").unwrap();
+        assert_eq!(last_lines(&dir, 10).last().unwrap(), "{}");
+        assert_eq!(fs::read_to_string(dir.join(FILE_NAME)).unwrap(), "{}\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,255 @@
+//! Hash chain of the local audit log.
+//!
+//! Every line has `seq`, increasing by one from 1, and `prev`, the hex SHA-256 of the previous
+//! line, empty for the first one. The chain continues across rotated files.
+//! `audit.head` holds the seq and the hash of the last line, so that removing lines at the end
+//! is detected too. When the retention policy removes rotated files, `audit.start` records the
+//! seq and the hash of the last removed line, the chain must start right after it.
+//!
+//! The hashes are not keyed: this detects lines edited, removed or inserted by mistake or by
+//! a partial edit, not a writer who rewrites the files and recomputes the hashes, `audit.head`
+//! and `audit.start`. Send the lines to syslog or to the API server to keep a copy out of reach.
+
+use super::{rotated_files, FILE_NAME};
+use hbb_common::{
+    bail,
+    sha2::{Digest, Sha256},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const HEAD_FILE_NAME: &str = "audit.head";
+const START_FILE_NAME: &str = "audit.start";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct Head {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl Head {
+    pub fn load(dir: &Path) -> Option<Self> {
+        Self::read(dir, HEAD_FILE_NAME)
+    }
+
+    /// The last line removed by the retention policy.
+    pub fn load_start(dir: &Path) -> Option<Self> {
+        Self::read(dir, START_FILE_NAME)
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<Self> {
+        let text = fs::read_to_string(dir.join(name)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    /// The head of the last chained line of the files, if there is no head file.
+    pub fn find(dir: &Path) -> Self {
+        if let Some(head) = Self::load(dir) {
+            return head;
+        }
+        files(dir)
+            .iter()
+            .rev()
+            .find_map(|path| Self::last_in(path))
+            .or_else(|| Self::load_start(dir))
+            .unwrap_or_default()
+    }
+
+    /// The last chained line of the file.
+    pub fn last_in(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        text.lines().rev().find_map(|line| {
+            Some(Self {
+                seq: parse_seq(line)?,
+                hash: hash(line),
+            })
+        })
+    }
+
+    pub fn save(&self, dir: &Path) -> std::io::Result<()> {
+        self.write(dir, HEAD_FILE_NAME)
+    }
+
+    pub fn save_start(&self, dir: &Path) -> std::io::Result<()> {
+        self.write(dir, START_FILE_NAME)
+    }
+
+    fn write(&self, dir: &Path, name: &str) -> std::io::Result<()> {
+        let tmp = dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(&tmp, dir.join(name))
+    }
+
+    /// Make the next line, and move the head to it.
+    pub fn append(&mut self, time: i64, typ: &str, event: &Value) -> String {
+        let line = json!({
+            "seq": self.seq + 1,
+            "prev": self.hash,
+            "time": time,
+            "type": typ,
+            "event": event,
+        })
+        .to_string();
+        self.seq += 1;
+        self.hash = hash(&line);
+        line
+    }
+}
+
+fn hash(line: &str) -> String {
+    hex::encode(Sha256::digest(line.as_bytes()))
+}
+
+fn parse_seq(line: &str) -> Option<u64> {
+    serde_json::from_str::<Value>(line).ok()?["seq"].as_u64()
+}
+
+// All files, the oldest first.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = rotated_files(dir);
+    files.push(dir.join(FILE_NAME));
+    files
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// The seq of the first line, greater than 1 if older files were removed.
+    pub first_seq: u64,
+    pub last_seq: u64,
+    /// Lines written before the chain was enabled.
+    pub unchained: usize,
+}
+
+/// Verify the chain of the audit log in `dir`.
+pub fn verify(dir: &Path) -> ResultType<Report> {
+    let mut report = Report::default();
+    let start = Head::load_start(dir);
+    let mut last: Option<Head> = None;
+    for path in files(dir) {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) if path.ends_with(FILE_NAME) => continue,
+            Err(err) => bail!("{}: {}", path.display(), err),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let at = || format!("{}:{}", path.display(), i + 1);
+            let v: Value = match serde_json::from_str(line) {
+                Ok(v) => v,
+                Err(_) => bail!("{}: not a JSON line", at()),
+            };
+            let (Some(seq), Some(prev)) = (v["seq"].as_u64(), v["prev"].as_str()) else {
+                if last.is_some() {
+                    bail!("{}: no seq or prev", at());
+                }
+                report.unchained += 1;
+                continue;
+            };
+            match last.as_ref() {
+                Some(last) => {
+                    if seq != last.seq + 1 {
+                        bail!("{}: seq {} follows {}", at(), seq, last.seq);
+                    }
+                    if prev != last.hash {
+                        bail!("{}: the previous line is modified", at());
+                    }
+                }
+                None => {
+                    let start = start.clone().unwrap_or_default();
+                    if seq != start.seq + 1 || prev != start.hash {
+                        bail!(
+                            "{}: the chain starts at seq {}, lines before it are removed",
+                            at(),
+                            seq
+                        );
+                    }
+                    report.first_seq = seq;
+                }
+            }
+            last = Some(Head {
+                seq,
+                hash: hash(line),
+            });
+        }
+    }
+    let last = last.or(start).unwrap_or_default();
+    match Head::load(dir) {
+        Some(head) if head == last => {}
+        Some(head) if head.seq > last.seq => {
+            bail!(
+                "lines after seq {} up to {} are removed",
+                last.seq,
+                head.seq
+            )
+        }
+        Some(_) => bail!("the last line (seq {}) is modified", last.seq),
+        None if last.seq > 0 => bail!("{} is missing", HEAD_FILE_NAME),
+        None => {}
+    }
+    report.last_seq = last.seq;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-chain-test-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut head = Head::default();
+        let mut lines = vec![r#"{"time":0,"type":"conn","event":{}}"#.to_owned()];
+        for i in 0..3 {
+            lines.push(head.append(i, "conn", &json!({ "n": i })));
+        }
+        let write = |lines: &[String]| {
+            fs::write(dir.join(FILE_NAME), lines.join("\n") + "\n").unwrap();
+        };
+        write(&lines);
+        head.save(&dir).unwrap();
+        let report = verify(&dir).unwrap();
+        assert_eq!(
+            (report.first_seq, report.last_seq, report.unchained),
+            (1, 3, 1)
+        );
+        assert_eq!(Head::find(&dir), head);
+
+        // edited
+        let mut edited = lines.clone();
+        edited[2] = edited[2].replace("\"n\":1", "\"n\":5");
+        write(&edited);
+        assert!(verify(&dir).is_err());
+        // the last line edited
+        let mut edited = lines.clone();
+        edited[3] = edited[3].replace("\"n\":2", "\"n\":5");
+        write(&edited);
+        assert!(verify(&dir).is_err());
+        // truncated
+        write(&lines[..3]);
+        assert!(verify(&dir).is_err());
+        // removed in the middle
+        write(&[lines[0].clone(), lines[1].clone(), lines[3].clone()]);
+        assert!(verify(&dir).is_err());
+        // the start removed
+        write(&lines[2..]);
+        assert!(verify(&dir).is_err());
+        // the start removed by rotation
+        Head {
+            seq: 1,
+            hash: hash(&lines[1]),
+        }
+        .save_start(&dir)
+        .unwrap();
+        assert_eq!(verify(&dir).unwrap().first_seq, 2);
+        // more lines removed than recorded
+        write(&lines[3..]);
+        assert!(verify(&dir).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,450 @@
+//! Reliable delivery of the audit events to the API server.
+//!
+//! Events are appended to an outbox file before they are posted, and retried with backoff until
+//! the server accepts them with a 2xx. The events of a connection, or of a URL for the events
+//! without a connection, are posted in the order they are recorded, one queue doesn't wait for
+//! the others. Delivered events are removed from the outbox, undelivered ones are posted again
+//! after a restart.
+//!
+//! An event refused with a 4xx other than 408 and 429, or failed [`MAX_ATTEMPTS`] times, is
+//! moved to the dead letter file `outbox.dead.jsonl`, so that it doesn't hold up its queue.
+//!
+//! Every event is posted with the `X-RustDesk-Audit-Seq` header, a sequence number increasing by
+//! one for each event of this device, so the server can detect lost events by gaps, and drop
+//! duplicates, which are possible if the process exits before a delivery is recorded.
+//! If a secret is set with `--audit-hmac-secret`, `X-RustDesk-Audit-Signature: sha256=<hex>` is
+//! the HMAC-SHA256 of `<seq>.<body>` with the secret.
+
+use hbb_common::{
+    config::{load_path, store_path, Config, ENCRYPT_MAX_LEN},
+    futures::future::join_all,
+    log,
+    password_security::{decrypt_str_or_original, encrypt_str_or_original},
+    sha2::Sha256,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{sleep_until, Duration, Instant},
+    },
+    ResultType,
+};
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const HEADER_SEQ: &str = "X-RustDesk-Audit-Seq";
+pub const HEADER_SIGNATURE: &str = "X-RustDesk-Audit-Signature";
+pub const MAX_ATTEMPTS: u32 = 100;
+
+const FILE_NAME: &str = "outbox.jsonl";
+// The first line is the seq up to which all the events are delivered, the next ones are
+// the seqs of the events delivered after it.
+const ACK_FILE_NAME: &str = "outbox.ack";
+const DEAD_FILE_NAME: &str = "outbox.dead.jsonl";
+// The oldest events are dropped beyond this, the server sees the gap.
+const MAX_PENDING: usize = 100_000;
+// The outbox is rewritten without the delivered events once this many are acked.
+const MAX_ACKED: usize = 10_000;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const POST_TIMEOUT: Duration = Duration::from_secs(12);
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<mpsc::UnboundedSender<(String, String)>>> = Default::default();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    url: String,
+    body: String,
+    #[serde(skip)]
+    queue: String,
+}
+
+impl Entry {
+    fn new(seq: u64, url: String, body: String) -> Self {
+        let mut e = Self {
+            seq,
+            url,
+            body,
+            queue: String::new(),
+        };
+        e.set_queue();
+        e
+    }
+
+    // The events of a connection are in order, the other ones by their URL.
+    fn set_queue(&mut self) {
+        let conn_id = serde_json::from_str::<Value>(&self.body)
+            .ok()
+            .and_then(|v| v["conn_id"].as_i64());
+        self.queue = match conn_id {
+            Some(conn_id) => format!("{} {}", self.url, conn_id),
+            None => self.url.clone(),
+        };
+    }
+}
+
+struct Outbox {
+    dir: PathBuf,
+    // By seq.
+    pending: VecDeque<Entry>,
+    next_seq: u64,
+    // None if the outbox can't be written, the events are kept in memory only.
+    file: Option<File>,
+    ack_file: Option<File>,
+    acked: usize,
+}
+
+impl Outbox {
+    fn open(dir: &Path) -> Self {
+        let ack = fs::read_to_string(dir.join(ACK_FILE_NAME)).unwrap_or_default();
+        let mut acks = ack.lines().filter_map(|x| x.trim().parse::<u64>().ok());
+        let low = acks.next().unwrap_or(0);
+        let acked: HashSet<u64> = acks.collect();
+        let pending: VecDeque<Entry> = fs::read_to_string(dir.join(FILE_NAME))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+            .filter(|e| e.seq > low && !acked.contains(&e.seq))
+            .map(|mut e| {
+                e.set_queue();
+                e
+            })
+            .collect();
+        let last_seq = acked
+            .iter()
+            .copied()
+            .chain(pending.back().map(|e| e.seq))
+            .fold(low, u64::max);
+        let mut outbox = Self {
+            dir: dir.to_owned(),
+            pending,
+            next_seq: last_seq + 1,
+            file: None,
+            ack_file: None,
+            acked: 0,
+        };
+        if let Err(err) = outbox.rewrite() {
+            log::error!("Failed to open audit outbox: {}", err);
+        }
+        outbox
+    }
+
+    // Write the pending events only.
+    fn rewrite(&mut self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(FILE_NAME);
+        let tmp = self.dir.join(format!("{}.tmp", FILE_NAME));
+        let mut file = File::create(&tmp)?;
+        for e in self.pending.iter() {
+            writeln!(file, "{}", serde_json::to_string(e)?)?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp, &path)?;
+        let ack_path = self.dir.join(ACK_FILE_NAME);
+        let tmp = self.dir.join(format!("{}.tmp", ACK_FILE_NAME));
+        let mut ack_file = File::create(&tmp)?;
+        let last_seq = self.next_seq - 1;
+        let low = self.pending.front().map_or(last_seq, |e| e.seq - 1);
+        writeln!(ack_file, "{}", low)?;
+        // Keep the last seq, for the seq of the next event.
+        if last_seq > low && self.pending.back().map_or(true, |e| e.seq < last_seq) {
+            writeln!(ack_file, "{}", last_seq)?;
+        }
+        ack_file.sync_data()?;
+        fs::rename(&tmp, &ack_path)?;
+        self.file = Some(OpenOptions::new().append(true).open(&path)?);
+        self.ack_file = Some(OpenOptions::new().append(true).open(&ack_path)?);
+        self.acked = 0;
+        Ok(())
+    }
+
+    fn push(&mut self, url: String, body: String) {
+        let e = Entry::new(self.next_seq, url, body);
+        self.next_seq += 1;
+        if let Some(file) = self.file.as_mut() {
+            let res = serde_json::to_string(&e)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writeln!(file, "{}", line))
+                .and_then(|_| file.sync_data());
+            if let Err(err) = res {
+                log::error!("Failed to write audit outbox: {}", err);
+                self.file = None;
+            }
+        }
+        self.pending.push_back(e);
+        if self.pending.len() > MAX_PENDING {
+            if let Some(seq) = self.pending.front().map(|e| e.seq) {
+                log::error!("Audit outbox is full, drop event {}", seq);
+                self.remove(seq);
+            }
+        }
+    }
+
+    /// The first event of each queue.
+    fn heads(&self) -> Vec<&Entry> {
+        let mut queues = HashSet::new();
+        self.pending
+            .iter()
+            .filter(|e| queues.insert(e.queue.as_str()))
+            .collect()
+    }
+
+    /// The event is delivered, or dropped.
+    fn remove(&mut self, seq: u64) {
+        let Some(index) = self.pending.iter().position(|e| e.seq == seq) else {
+            return;
+        };
+        self.pending.remove(index);
+        self.acked += 1;
+        if self.pending.is_empty() || self.acked >= MAX_ACKED {
+            if let Err(err) = self.rewrite() {
+                log::error!("Failed to write audit outbox: {}", err);
+            }
+            return;
+        }
+        if let Some(file) = self.ack_file.as_mut() {
+            if let Err(err) = writeln!(file, "{}", seq).and_then(|_| file.sync_data()) {
+                log::error!("Failed to write audit outbox: {}", err);
+            }
+        }
+    }
+
+    fn dead_letter(&self, e: &Entry, reason: &str) {
+        let line = json!({ "seq": e.seq, "url": e.url, "body": e.body, "reason": reason });
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DEAD_FILE_NAME))
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(err) = res {
+            log::error!("Failed to write audit dead letter: {}", err);
+        }
+    }
+}
+
+fn dir() -> PathBuf {
+    Config::path("audit-outbox")
+}
+
+// Encrypted like the permanent password, and like it kept out of the options, which are sent
+// to the processes of the user over IPC.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct HmacSecret {
+    secret: String,
+}
+
+fn hmac_secret_path() -> PathBuf {
+    Config::path("audit_hmac_secret.toml")
+}
+
+fn hmac_secret() -> String {
+    let stored = load_path::<HmacSecret>(hmac_secret_path());
+    decrypt_str_or_original(&stored.secret, "00").0
+}
+
+/// An empty secret disables the signature.
+pub fn set_hmac_secret(secret: &str) {
+    let secret = if secret.is_empty() {
+        "".to_owned()
+    } else {
+        encrypt_str_or_original(secret, "00", ENCRYPT_MAX_LEN)
+    };
+    if let Err(err) = store_path(hmac_secret_path(), HmacSecret { secret }) {
+        log::error!("Failed to store the audit HMAC secret: {}", err);
+    }
+}
+
+/// Queue `body` to be posted to `url`.
+pub fn post(url: String, body: String) {
+    let mut sender = SENDER.lock().unwrap();
+    if sender.is_none() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || run(rx));
+        *sender = Some(tx);
+    }
+    if let Some(tx) = sender.as_ref() {
+        tx.send((url, body)).ok();
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(mut rx: mpsc::UnboundedReceiver<(String, String)>) {
+    let mut outbox = Outbox::open(&dir());
+    if !outbox.pending.is_empty() {
+        log::info!("{} audit events to post", outbox.pending.len());
+    }
+    // queue -> (failed attempts of its first event, when it is retried)
+    let mut retries: HashMap<String, (u32, Instant)> = HashMap::new();
+    loop {
+        while let Ok((url, body)) = rx.try_recv() {
+            outbox.push(url, body);
+        }
+        let now = Instant::now();
+        let due: Vec<Entry> = outbox
+            .heads()
+            .into_iter()
+            .filter(|e| retries.get(&e.queue).map_or(true, |r| r.1 <= now))
+            .cloned()
+            .collect();
+        // A queue waiting for the timeout of its head doesn't hold up the others.
+        let results = join_all(due.iter().map(send)).await;
+        for (e, res) in due.into_iter().zip(results) {
+            let attempts = retries.get(&e.queue).map_or(0, |r| r.0) + 1;
+            let reason = match res {
+                Ok(None) => {
+                    outbox.remove(e.seq);
+                    retries.remove(&e.queue);
+                    continue;
+                }
+                Ok(Some(reason)) => reason,
+                Err(err) if attempts < MAX_ATTEMPTS => {
+                    let backoff = backoff(attempts);
+                    log::warn!(
+                        "Failed to post audit event {}, retry in {:?}: {}",
+                        e.seq,
+                        backoff,
+                        err
+                    );
+                    retries.insert(e.queue.clone(), (attempts, Instant::now() + backoff));
+                    continue;
+                }
+                Err(err) => format!("{} attempts, {}", attempts, err),
+            };
+            log::error!("Audit event {} is not delivered: {}", e.seq, reason);
+            outbox.dead_letter(&e, &reason);
+            outbox.remove(e.seq);
+            retries.remove(&e.queue);
+        }
+        // Until the next retry, or a new event.
+        let heads = outbox.heads();
+        if heads.iter().any(|e| !retries.contains_key(&e.queue)) {
+            continue;
+        }
+        let deadline = heads
+            .iter()
+            .filter_map(|e| retries.get(&e.queue).map(|r| r.1))
+            .min()
+            .unwrap_or_else(|| Instant::now() + MAX_BACKOFF);
+        tokio::select! {
+            res = rx.recv() => match res {
+                Some((url, body)) => outbox.push(url, body),
+                None => break,
+            },
+            _ = sleep_until(deadline) => {}
+        }
+    }
+}
+
+/// `Some` with the reason if the server refuses the event, not to be posted again.
+async fn send(e: &Entry) -> ResultType<Option<String>> {
+    let mut req = crate::hbbs_http::create_http_client_async()
+        .post(&e.url)
+        .header("Content-Type", "application/json")
+        .header(HEADER_SEQ, e.seq.to_string());
+    let secret = hmac_secret();
+    if !secret.is_empty() {
+        req = req.header(HEADER_SIGNATURE, signature(&secret, e.seq, &e.body));
+    }
+    let resp = req
+        .body(e.body.clone())
+        .timeout(POST_TIMEOUT)
+        .send()
+        .await?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(None);
+    }
+    // Timeout and Too Many Requests are worth a retry.
+    if status.is_client_error() && status.as_u16() != 408 && status.as_u16() != 429 {
+        return Ok(Some(status.to_string()));
+    }
+    hbb_common::bail!("{}", status);
+}
+
+pub fn signature(secret: &str, seq: u64, body: &str) -> String {
+    let data = format!("{}.{}", seq, body);
+    format!(
+        "sha256={}",
+        hex::encode(hmac_sha256(secret.as_bytes(), data.as_bytes()))
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // Any key length is accepted.
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(key) else {
+        return vec![];
+    };
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // RFC 4231, test case 6, a key longer than the block size
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_outbox() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-outbox-test-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut outbox = Outbox::open(&dir);
+        outbox.push("url".to_owned(), r#"{"conn_id": 1, "a": 1}"#.to_owned());
+        outbox.push("url".to_owned(), r#"{"conn_id": 1, "b": 1}"#.to_owned());
+        outbox.push("url".to_owned(), r#"{"conn_id": 2}"#.to_owned());
+        outbox.push("url2".to_owned(), "d".to_owned());
+        let heads: Vec<_> = outbox.heads().iter().map(|e| e.seq).collect();
+        assert_eq!(heads, [1, 3, 4]);
+        outbox.remove(3);
+        drop(outbox);
+        let mut outbox = Outbox::open(&dir);
+        let seqs: Vec<_> = outbox.pending.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [1, 2, 4]);
+        assert_eq!(outbox.heads()[0].queue, "url 1");
+        outbox.remove(4);
+        drop(outbox);
+        let mut outbox = Outbox::open(&dir);
+        outbox.remove(1);
+        outbox.remove(2);
+        drop(outbox);
+        let mut outbox = Outbox::open(&dir);
+        assert!(outbox.pending.is_empty());
+        outbox.push("url".to_owned(), "e".to_owned());
+        assert_eq!(outbox.heads()[0].seq, 5);
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
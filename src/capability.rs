@@ -0,0 +1,80 @@
+//! The features of this fork, advertised explicitly instead of by the version, which upstream
+//! shares without them.
+//!
+//! The controlled side lists them in `capabilities` of the platform additions of its peer info.
+//! The controlling side of a file transfer answers with its own ones in a block with
+//! [`BLK_ID`], sent only to the peers advertising them, before any job. A feature is only used
+//! if the other side has it, e.g. a stock peer would write the marker blocks of the digests
+//! into the files, or open a shell for an exec.
+
+use hbb_common::message_proto::*;
+use serde_json::Value;
+use std::collections::HashSet;
+
+pub const FILE_DIGEST: &str = "file_digest";
+pub const FILE_DELTA: &str = "file_delta";
+pub const FILE_SYNC: &str = "file_sync";
+pub const TERMINAL_EXEC: &str = "terminal_exec";
+pub const ALL: [&str; 4] = [FILE_DIGEST, FILE_DELTA, FILE_SYNC, TERMINAL_EXEC];
+pub const FILE_TRANSFER: [&str; 3] = [FILE_DIGEST, FILE_DELTA, FILE_SYNC];
+
+pub const PLATFORM_ADDITION: &str = "capabilities";
+pub const BLK_ID: u32 = u32::MAX - 6;
+
+/// The capabilities in the platform additions of a peer info.
+pub fn from_platform_additions(platform_additions: &str) -> HashSet<String> {
+    let additions = serde_json::from_str::<Value>(platform_additions).unwrap_or_default();
+    from_value(&additions[PLATFORM_ADDITION])
+}
+
+fn from_value(value: &Value) -> HashSet<String> {
+    value
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .filter_map(|x| x.as_str())
+                .map(|x| x.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn is_block(block: &FileTransferBlock) -> bool {
+    block.blk_id == BLK_ID
+}
+
+/// The capabilities of a file transfer of the controlling side.
+pub fn new_block() -> Message {
+    let mut fr = FileResponse::new();
+    fr.set_block(FileTransferBlock {
+        data: serde_json::to_vec(&FILE_TRANSFER)
+            .unwrap_or_default()
+            .into(),
+        blk_id: BLK_ID,
+        ..Default::default()
+    });
+    let mut msg = Message::new();
+    msg.set_file_response(fr);
+    msg
+}
+
+pub fn from_block(block: &FileTransferBlock) -> HashSet<String> {
+    from_value(&serde_json::from_slice(&block.data).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        assert!(from_platform_additions("").is_empty());
+        assert!(from_platform_additions(r#"{"is_installed":true}"#).is_empty());
+        let caps = from_platform_additions(r#"{"capabilities":["file_digest","x"]}"#);
+        assert!(caps.contains(FILE_DIGEST) && !caps.contains(FILE_DELTA));
+        let msg = new_block();
+        let block = msg.file_response().block();
+        assert!(is_block(block));
+        assert_eq!(from_block(block).len(), FILE_TRANSFER.len());
+    }
+}
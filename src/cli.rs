@@ -1,4 +1,4 @@
-use crate::client::*;
+use crate::{client::*, port_forward::ForwardOptions};
 use async_trait::async_trait;
 use hbb_common::{
     config::PeerConfig,
@@ -9,28 +9,39 @@ use hbb_common::{
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tokio::{self, sync::mpsc},
-    Stream,
+    ResultType, Stream,
 };
+use password::PasswordSource;
 use std::sync::{Arc, RwLock};
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod exec;
+pub mod headless;
+pub mod password;
+pub mod rules;
+pub mod transfer;
+
 #[derive(Clone)]
 pub struct Session {
     id: String,
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    password_source: PasswordSource,
 }
 
 impl Session {
-    pub fn new(id: &str, sender: mpsc::UnboundedSender<Data>) -> Self {
-        let mut password = "".to_owned();
-        if PeerConfig::load(id).password.is_empty() {
-            password = rpassword::prompt_password("Enter password: ").unwrap();
-        }
+    pub fn new(
+        id: &str,
+        password_source: PasswordSource,
+        sender: mpsc::UnboundedSender<Data>,
+    ) -> ResultType<Self> {
+        let password = get_password(id, &password_source, true)?;
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
+            password_source,
             lc: Default::default(),
         };
         session.lc.write().unwrap().initialize(
@@ -40,6 +51,29 @@ impl Session {
             false,
             None,
             None,
+            None,
+        );
+        Ok(session)
+    }
+
+    /// A session to the same peer with the same password, but its own login config,
+    /// so that forwards with different destinations can run at the same time.
+    fn fork(&self, sender: mpsc::UnboundedSender<Data>) -> Self {
+        let session = Self {
+            id: self.id.clone(),
+            sender,
+            password: self.password.clone(),
+            password_source: self.password_source.clone(),
+            lc: Default::default(),
+        };
+        session.lc.write().unwrap().initialize(
+            self.id.clone(),
+            ConnType::PORT_FORWARD,
+            None,
+            false,
+            None,
+            None,
+            None,
         );
         session
     }
@@ -47,7 +81,7 @@ impl Session {
 
 #[async_trait]
 impl Interface for Session {
-    fn get_login_config_handler(&self) -> Arc<RwLock<LoginConfigHandler>> {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
         return self.lc.clone();
     }
 
@@ -55,14 +89,26 @@ impl Interface for Session {
         match msgtype {
             "input-password" => {
                 self.sender
-                    .send(Data::Login((self.password.clone(), true)))
+                    .send(Data::Login((
+                        "".to_owned(),
+                        "".to_owned(),
+                        self.password.clone(),
+                        true,
+                    )))
                     .ok();
             }
             "re-input-password" => {
                 log::error!("{}: {}", title, text);
-                match rpassword::prompt_password("Enter password: ") {
+                self.password_source.reject(&self.id);
+                if !self.password_source.is_interactive() {
+                    // It can't be asked again, the forwards would wait forever.
+                    eprintln!("Password from {} is rejected", self.password_source);
+                    std::process::exit(transfer::EXIT_CONNECTION);
+                }
+                match self.password_source.get(&self.id) {
                     Ok(password) => {
-                        let login_data = Data::Login((password, true));
+                        let login_data =
+                            Data::Login(("".to_owned(), "".to_owned(), password, true));
                         self.sender.send(login_data).ok();
                     }
                     Err(e) => {
@@ -71,7 +117,7 @@ impl Interface for Session {
                 }
             }
             msg if msg.contains("error") => {
-                log::error!("{}: {}: {}", msgtype, title, text);
+                log::error!("{}: {}: {}: {}", msgtype, title, text, link);
             }
             _ => {
                 log::info!("{}: {}: {}", msgtype, title, text);
@@ -83,6 +129,8 @@ impl Interface for Session {
         handle_login_error(self.lc.clone(), err, self)
     }
 
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
     fn handle_peer_info(&self, pi: PeerInfo) {
         self.lc.write().unwrap().handle_peer_info(&pi);
     }
@@ -125,13 +173,19 @@ impl Interface for Session {
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn connect_test(id: &str, key: String, token: String) {
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
+    let (sender, mut _receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = match Session::new(&id, Default::default(), sender) {
+        Ok(handler) => handler,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        }
+    };
     match crate::client::Client::start(id, &key, &token, ConnType::PORT_FORWARD, handler).await {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
         }
-        Ok((mut stream, direct)) => {
+        Ok(((mut stream, direct, ..), _)) => {
             log::info!("direct: {}", direct);
             // rpassword::prompt_password("Input anything to exit").ok();
             loop {
@@ -144,7 +198,7 @@ pub async fn connect_test(id: &str, key: String, token: String) {
                         Ok(Some(Ok(bytes))) => {
                             if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                                 match msg_in.union {
-                                    Some(message::Union::Hash(hash)) => {
+                                    Some(message::Union::Hash(_hash)) => {
                                         log::info!("Got hash");
                                         break;
                                     }
@@ -168,26 +222,630 @@ pub async fn start_one_port_forward(
     remote_port: i32,
     key: String,
     token: String,
-) {
+    password_source: PasswordSource,
+    opts: ForwardOptions,
+) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = match Session::new(&id, password_source, sender) {
+        Ok(handler) => handler,
+        Err(err) => {
+            eprintln!("{}", err);
+            return transfer::EXIT_CONNECTION;
+        }
+    };
+    run_port_forward(
+        handler,
+        receiver,
+        &key,
+        &token,
+        port,
+        remote_host,
+        remote_port,
+        opts,
+    )
+    .await
+}
+
+/// Run the forwards of a rule file at the same time in this process.
+///
+/// The password of each peer is got once, and all forwards to it are multiplexed through
+/// one authenticated session. Returns the process exit code, an error if any forward fails.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_port_forwards(
+    rules: Vec<rules::Rule>,
+    key: String,
+    token: String,
+    password_source: PasswordSource,
+) -> i32 {
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
-    if let Err(err) = crate::port_forward::listen(
+    let mut code = transfer::EXIT_OK;
+    let mut forwards = Vec::new();
+    for (id, rules) in rules::group_by_peer(rules) {
+        let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+        let session = match Session::new(&id, password_source.clone(), sender) {
+            Ok(session) => session,
+            Err(err) => {
+                eprintln!("{}: {}", id, err);
+                code = transfer::EXIT_CONNECTION;
+                continue;
+            }
+        };
+        let mux = crate::port_forward::mux::Session::start(
+            session.id.clone(),
+            session.password.clone(),
+            session.clone(),
+            receiver,
+            key.clone(),
+            token.clone(),
+            session.lc.clone(),
+        );
+        for rule in rules {
+            // The sender of each forward is kept by its handler, it closes the forward if dropped.
+            let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+            let handler = session.fork(sender);
+            let mut opts = rule.options();
+            opts.session = Some(mux.clone());
+            let (key, token) = (key.clone(), token.clone());
+            forwards.push(async move {
+                run_port_forward(
+                    handler,
+                    receiver,
+                    &key,
+                    &token,
+                    rule.local_port,
+                    rule.remote_host.clone(),
+                    rule.remote_port,
+                    opts,
+                )
+                .await
+            });
+        }
+    }
+    let codes = hbb_common::futures::future::join_all(forwards).await;
+    codes
+        .into_iter()
+        .find(|x| *x != transfer::EXIT_OK)
+        .unwrap_or(code)
+}
+
+async fn run_port_forward(
+    handler: Session,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    port: i32,
+    remote_host: String,
+    remote_port: i32,
+    opts: ForwardOptions,
+) -> i32 {
+    let id = handler.id.clone();
+    let code = if let Err(err) = crate::port_forward::listen(
         handler.id.clone(),
         handler.password.clone(),
         port,
         handler.clone(),
         receiver,
-        &key,
-        &token,
+        key,
+        token,
         handler.lc.clone(),
         remote_host,
         remote_port,
+        opts,
     )
     .await
     {
-        log::error!("Failed to listen on {}: {}", port, err);
+        eprintln!("Failed to forward {}: {}", port, err);
+        transfer::EXIT_CONNECTION
+    } else {
+        transfer::EXIT_OK
+    };
+    log::info!("port forward ({}:{}) exit", id, port);
+    code
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_one_reverse_forward(
+    id: String,
+    bind: String,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+    key: String,
+    token: String,
+    password_source: PasswordSource,
+) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = match Session::new(&id, password_source, sender) {
+        Ok(handler) => handler,
+        Err(err) => {
+            eprintln!("{}", err);
+            return transfer::EXIT_CONNECTION;
+        }
+    };
+    let code = if let Err(err) = crate::port_forward::listen_reverse(
+        handler.id.clone(),
+        handler.password.clone(),
+        bind,
+        remote_port,
+        handler.clone(),
+        receiver,
+        &key,
+        &token,
+        handler.lc.clone(),
+        local_host,
+        local_port,
+    )
+    .await
+    {
+        eprintln!("Failed to reverse forward {}: {}", remote_port, err);
+        transfer::EXIT_CONNECTION
+    } else {
+        transfer::EXIT_OK
+    };
+    log::info!("reverse port forward ({}:{}) exit", id, remote_port);
+    code
+}
+
+const USAGE: &str = "Usage: rustdesk --cli <command> [options]
+
+Commands:
+    connect <id> [password option] [--relay]
+        Log in to <id> without GUI. Session events are written to stdout as
+        line-delimited JSON, commands are read from stdin as line-delimited JSON.
+    push <id> <local-path> <remote-path> [password option] [--relay]
+                                         [--overwrite] [--include-hidden]
+    pull <id> <remote-path> <local-path> [password option] [--relay]
+                                         [--overwrite] [--include-hidden]
+        Copy files or directories over a file transfer session. A destination
+        ending with a path separator is treated as a directory. Existing files
+        are skipped unless --overwrite is given.
+        Exit code: 0 success, 1 connection or login failure, 2 invalid usage,
+        3 transfer failure.
+    sync <id> <local-path> <remote-path> [password option] [--relay] [--pull]
+                                         [--delete] [--dry-run] [--checksum]
+                                         [--exclude <glob>]... [--include-hidden]
+        Make the directory remote-path a copy of local-path, or local-path a copy
+        of remote-path if --pull is given. Only the files missing or differing in
+        size or modified time are copied, or in content too with --checksum. The
+        files missing at the source are deleted with --delete. The files matching
+        an --exclude glob are left alone, * and ? don't match a path separator,
+        ** does. --dry-run only prints what would be copied and deleted.
+        Exit code: as push and pull, 3 if a file can't be deleted.
+    exec <id> [password option] [--relay] [--no-stdin] -- <command> [args...]
+        Run a command on <id> without a shell, over a terminal session. stdin is
+        forwarded to it unless --no-stdin is given, its stdout and stderr are
+        written to stdout and stderr.
+        Exit code: the exit code of the command, 255 if it can't be run.
+    forward <id> <local-port> <remote-host> <remote-port> [password option]
+                                                          [--bind <address>]
+        Forward connections to local-port to remote-host:remote-port reachable
+        from <id>. Prefix remote-host with udp: to forward UDP instead of TCP,
+        e.g. udp:192.168.1.1. local-port is listened on 127.0.0.1 unless --bind
+        is given, e.g. --bind 0.0.0.0 for all interfaces.
+    forward --rules <file> [<id>] [password option]
+        Run all forwards listed in a TOML or JSON rule file at the same time, or
+        only the ones to <id>. Each forward has its own bind address (127.0.0.1
+        by default), and optionally an idle timeout and a connection limit. The
+        password of a peer is got once, and all forwards to it are multiplexed
+        through one authenticated session, which is made again if it is lost.
+    dynamic-forward <id> <local-port> [password option] [--bind <address>]
+        Run a SOCKS5 server on 127.0.0.1:local-port, every connection is forwarded
+        to the destination it requests, reachable from <id>.
+    reverse-forward <id> <remote-port> <local-host> <local-port> [password option]
+                                                                 [--bind <address>]
+        Listen on remote-port of <id>, and forward the connections accepted there
+        to local-host:local-port reachable from this machine. The listener is
+        bound to 127.0.0.1 of <id> unless --bind is given.
+    forward-stats [--json]
+        Print the traffic of the port forwarding to this machine, of each target
+        and each open connection, got from the running service.
+    login-bans [--json]
+        Print the addresses banned for wrong login attempts by the running service.
+    unban <address> | --all
+        Remove the ban of an address, or all bans.
+
+The forward commands print the traffic of each rule and open connection to
+stdout every <seconds> if --stats <seconds> is given. They exit with 1 if they
+fail to connect or log in, or to listen.
+
+Password options, at most one of:
+    --password <password>
+    --password-env <name>           read from an environment variable
+    --password-fd <fd>              read the first line from a file descriptor
+    --password-file <path>          read the first line from a file, which must
+                                    not be accessible by other users
+    --password-command <command>    git-credential style helper, `<command> get`
+                                    reads `protocol=rustdesk` and `host=<id>`
+                                    from stdin and prints `password=<password>`,
+                                    `<command> erase` is run if it is rejected
+Without them, the saved password is used, otherwise it is prompted (push,
+pull, sync, forward) or expected with a login command (connect).";
+
+/// Entry of `rustdesk --cli ...`, `args` does not include `--cli` itself.
+///
+/// Returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some(cmd) = args.first() else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let args = &args[1..];
+    // Options after "--" belong to the remote command.
+    let options = &args[..args.iter().position(|x| x == "--").unwrap_or(args.len())];
+    let has_flag = |flag: &str| options.iter().any(|x| x == flag);
+    let password_source = match PasswordSource::from_args(options) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+    if let Some(interval) = get_option(options, "--stats") {
+        match interval.parse::<u64>() {
+            Ok(interval) if interval > 0 => start_stats_printer(interval),
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+    match cmd.as_str() {
+        "connect" => {
+            let Some(id) = get_positional(args, 0) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let password = match get_password(&id, &password_source, false) {
+                Ok(password) => password,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return transfer::EXIT_CONNECTION;
+                }
+            };
+            headless::connect(id, password, password_source, has_flag("--relay"))
+        }
+        "push" | "pull" => {
+            let (Some(id), Some(from), Some(to)) = (
+                get_positional(args, 0),
+                get_positional(args, 1),
+                get_positional(args, 2),
+            ) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let password = match get_password(&id, &password_source, true) {
+                Ok(password) => password,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return transfer::EXIT_CONNECTION;
+                }
+            };
+            let opts = transfer::TransferOptions {
+                password,
+                password_source,
+                force_relay: has_flag("--relay"),
+                include_hidden: has_flag("--include-hidden"),
+                overwrite: has_flag("--overwrite"),
+            };
+            transfer::transfer(id, from, to, cmd == "push", opts)
+        }
+        "sync" => {
+            let (Some(id), Some(local), Some(remote)) = (
+                get_positional(args, 0),
+                get_positional(args, 1),
+                get_positional(args, 2),
+            ) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let password = match get_password(&id, &password_source, true) {
+                Ok(password) => password,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return transfer::EXIT_CONNECTION;
+                }
+            };
+            let opts = transfer::TransferOptions {
+                password,
+                password_source,
+                force_relay: has_flag("--relay"),
+                include_hidden: has_flag("--include-hidden"),
+                overwrite: false,
+            };
+            let sync_opts = crate::file_sync::SyncOptions {
+                is_remote: has_flag("--pull"),
+                include_hidden: opts.include_hidden,
+                compare_hash: has_flag("--checksum"),
+                delete: has_flag("--delete"),
+                dry_run: has_flag("--dry-run"),
+                excludes: get_options(options, "--exclude"),
+            };
+            transfer::sync(id, local, remote, opts, sync_opts)
+        }
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        "exec" => {
+            let mut positionals = get_positionals(args).into_iter();
+            let Some(id) = positionals.next() else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let command: Vec<String> = positionals.collect();
+            if command.is_empty() {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+            let password = match get_password(&id, &password_source, true) {
+                Ok(password) => password,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return exec::EXIT_ERROR;
+                }
+            };
+            let opts = exec::ExecOptions {
+                password,
+                password_source,
+                force_relay: has_flag("--relay"),
+                no_stdin: has_flag("--no-stdin"),
+            };
+            exec::exec(id, command, opts)
+        }
+        "forward" if has_flag("--rules") => {
+            let Some(path) = get_option(options, "--rules") else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let mut rules = match rules::load(std::path::Path::new(&path)) {
+                Ok(rules) => rules,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return 2;
+                }
+            };
+            if let Some(id) = get_positional(args, 0) {
+                rules.retain(|x| x.peer == id);
+            }
+            if rules.is_empty() {
+                eprintln!("No forward rules in {}", path);
+                return 2;
+            }
+            let key = get_key();
+            let token = hbb_common::config::LocalConfig::get_option("access_token");
+            start_port_forwards(rules, key, token, password_source)
+        }
+        "forward" => {
+            let (Some(id), Some(port), Some(remote_host), Some(remote_port)) = (
+                get_positional(args, 0),
+                get_positional(args, 1).and_then(|x| x.parse::<i32>().ok()),
+                get_positional(args, 2),
+                get_positional(args, 3).and_then(|x| x.parse::<i32>().ok()),
+            ) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let key = get_key();
+            let token = hbb_common::config::LocalConfig::get_option("access_token");
+            let opts = ForwardOptions {
+                bind: get_option(options, "--bind").unwrap_or_default(),
+                ..Default::default()
+            };
+            start_one_port_forward(
+                id,
+                port,
+                remote_host,
+                remote_port,
+                key,
+                token,
+                password_source,
+                opts,
+            )
+        }
+        "dynamic-forward" => {
+            let (Some(id), Some(port)) = (
+                get_positional(args, 0),
+                get_positional(args, 1)
+                    .and_then(|x| x.parse::<i32>().ok())
+                    .filter(|x| *x > 0),
+            ) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let key = get_key();
+            let token = hbb_common::config::LocalConfig::get_option("access_token");
+            // remote port 0 is dynamic forwarding
+            let opts = ForwardOptions {
+                bind: get_option(options, "--bind").unwrap_or_default(),
+                ..Default::default()
+            };
+            start_one_port_forward(
+                id,
+                port,
+                "".to_owned(),
+                0,
+                key,
+                token,
+                password_source,
+                opts,
+            )
+        }
+        "reverse-forward" => {
+            let (Some(id), Some(remote_port), Some(local_host), Some(local_port)) = (
+                get_positional(args, 0),
+                get_positional(args, 1).and_then(|x| x.parse::<i32>().ok()),
+                get_positional(args, 2),
+                get_positional(args, 3).and_then(|x| x.parse::<i32>().ok()),
+            ) else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let bind = get_option(options, "--bind").unwrap_or_default();
+            let key = get_key();
+            let token = hbb_common::config::LocalConfig::get_option("access_token");
+            start_one_reverse_forward(
+                id,
+                bind,
+                remote_port,
+                local_host,
+                local_port,
+                key,
+                token,
+                password_source,
+            )
+        }
+        "forward-stats" => match get_port_forward_stats() {
+            Ok(stats) => {
+                if has_flag("--json") {
+                    println!("{}", serde_json::to_string(&stats).unwrap_or_default());
+                } else {
+                    for s in stats {
+                        println!("{}", s);
+                    }
+                }
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        "login-bans" => match get_login_bans() {
+            Ok(bans) => {
+                if has_flag("--json") {
+                    println!("{}", serde_json::to_string(&bans).unwrap_or_default());
+                } else {
+                    for ban in bans {
+                        println!("{}", ban);
+                    }
+                }
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        "unban" => {
+            let ip = if has_flag("--all") {
+                "".to_owned()
+            } else if let Some(ip) = get_positional(args, 0) {
+                ip
+            } else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            match login_unban(ip.clone()) {
+                Ok(0) if !ip.is_empty() => {
+                    eprintln!("{} is not banned", ip);
+                    1
+                }
+                Ok(n) => {
+                    println!("{} unbanned", n);
+                    0
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    1
+                }
+            }
+        }
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => {
+            eprintln!("Unknown command: {}\n\n{}", cmd, USAGE);
+            2
+        }
+    }
+}
+
+// Options other than the password options which take a value.
+const VALUE_OPTIONS: &[&str] = &["--bind", "--rules", "--stats", "--exclude"];
+
+fn start_stats_printer(interval: u64) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+        for s in crate::server::port_forward_stats::live() {
+            println!("{}", s);
+        }
+    });
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn get_port_forward_stats() -> ResultType<Vec<crate::server::port_forward_stats::Snapshot>> {
+    crate::ipc::get_port_forward_stats(1_000).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn get_login_bans() -> ResultType<Vec<crate::server::login_lockout::Ban>> {
+    crate::ipc::get_login_bans(1_000).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn login_unban(ip: String) -> ResultType<usize> {
+    crate::ipc::login_unban(ip, 1_000).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn get_key() -> String {
+    crate::get_key(false).await
+}
+
+/// Get the password from `source` if it is given explicitly.
+/// Otherwise the saved password is used, and the password is prompted if there is no saved one and `prompt` is true.
+fn get_password(id: &str, source: &PasswordSource, prompt: bool) -> ResultType<String> {
+    if !source.is_interactive() {
+        return source.get(id);
+    }
+    if prompt && PeerConfig::load(id).password.is_empty() {
+        return source.get(id);
+    }
+    Ok("".to_owned())
+}
+
+/// Get the value of option `name`.
+fn get_option(args: &[String], name: &str) -> Option<String> {
+    let i = args.iter().position(|x| x == name)?;
+    args.get(i + 1).cloned()
+}
+
+/// Get all the values of option `name`, which can be given more than once.
+fn get_options(args: &[String], name: &str) -> Vec<String> {
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(|(x, _)| *x == name)
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+/// Get the n-th argument which is neither an option nor an option value.
+fn get_positional(args: &[String], n: usize) -> Option<String> {
+    get_positionals(args).into_iter().nth(n)
+}
+
+/// Get the arguments which are neither options nor option values, all arguments after "--" included.
+fn get_positionals(args: &[String]) -> Vec<String> {
+    let mut positionals = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--" {
+            positionals.extend(args[i + 1..].iter().cloned());
+            break;
+        }
+        if args[i].starts_with("--") {
+            if password::OPTIONS.contains(&args[i].as_str())
+                || VALUE_OPTIONS.contains(&args[i].as_str())
+            {
+                i += 1;
+            }
+        } else {
+            positionals.push(args[i].clone());
+        }
+        i += 1;
     }
-    log::info!("port forward (:{}) exit", port);
+    positionals
 }
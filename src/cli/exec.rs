@@ -0,0 +1,184 @@
+//! Run one command on the peer, `rustdesk --cli exec <id> -- <command> [args...]`.
+//!
+//! stdin is forwarded to the command, its stdout and stderr are written to stdout and stderr,
+//! and the exit code of the command is returned.
+
+use super::{
+    headless::{get_msgbox_error, new_session, start_io_loop, CliHandler},
+    password::PasswordSource,
+};
+use crate::{
+    client::{Data, Interface},
+    terminal_service::exec_stderr_id,
+    ui_session_interface::Session,
+};
+use hbb_common::{log, message_proto::*, rendezvous_proto::ConnType};
+use serde_json::Value;
+use std::{
+    io::{Read, Write},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+/// Returned if the command is not run or its exit code is unknown, like ssh.
+pub const EXIT_ERROR: i32 = 255;
+
+// Far from the ids of the interactive terminals, which may live in the same persistent terminal service.
+const TERMINAL_ID: i32 = 1 << 30;
+
+#[derive(Debug, Default, Clone)]
+pub struct ExecOptions {
+    pub password: String,
+    /// Told when the peer rejects the password.
+    pub password_source: PasswordSource,
+    pub force_relay: bool,
+    /// Do not forward stdin, the command reads EOF from it.
+    pub no_stdin: bool,
+}
+
+fn send_action(session: &Session<CliHandler>, action: TerminalAction) {
+    let mut msg_out = Message::new();
+    msg_out.set_terminal_action(action);
+    session.send(Data::Message(msg_out));
+}
+
+fn send_data(session: &Session<CliHandler>, data: Vec<u8>) {
+    let mut action = TerminalAction::new();
+    action.set_data(TerminalData {
+        terminal_id: TERMINAL_ID,
+        data: bytes::Bytes::from(data),
+        ..Default::default()
+    });
+    send_action(session, action);
+}
+
+/// Forward stdin until EOF, an empty data message closes stdin of the command.
+fn forward_stdin(session: Session<CliHandler>) {
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = vec![0u8; 4096];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => send_data(&session, buf[..n].to_vec()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::error!("Failed to read stdin: {}", e);
+                    break;
+                }
+            }
+        }
+        send_data(&session, vec![]);
+    });
+}
+
+/// Run `command` on `id`.
+///
+/// Returns the exit code of the command, or [`EXIT_ERROR`].
+pub fn exec(id: String, command: Vec<String>, opts: ExecOptions) -> i32 {
+    let (tx, rx) = mpsc::channel::<(String, Value)>();
+    let session = new_session(
+        CliHandler::with_events(tx),
+        id,
+        opts.password.clone(),
+        opts.password_source.clone(),
+        ConnType::TERMINAL,
+        opts.force_relay,
+    );
+    let handle = start_io_loop(&session);
+    let mut result: Option<Result<i32, String>> = None;
+    let mut started = false;
+    while result.is_none() {
+        let (event, v) = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(e) => e,
+            Err(RecvTimeoutError::Timeout) => {
+                if handle.is_finished() {
+                    break;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match event.as_str() {
+            "peer_info" => {
+                let supported = v["capabilities"].as_array().map_or(false, |x| {
+                    x.iter()
+                        .any(|x| x.as_str() == Some(crate::capability::TERMINAL_EXEC))
+                });
+                if !supported {
+                    // Another peer would open a shell and type the command line into it.
+                    result = Some(Err(format!(
+                        "Exec is not supported by the remote side ({})",
+                        v["version"].as_str().unwrap_or_default()
+                    )));
+                }
+            }
+            "connected" => {
+                let mut action = TerminalAction::new();
+                // 0 rows and cols request an exec session instead of a PTY.
+                action.set_open(OpenTerminal {
+                    terminal_id: TERMINAL_ID,
+                    rows: 0,
+                    cols: 0,
+                    ..Default::default()
+                });
+                send_action(&session, action);
+            }
+            "msgbox" => {
+                if let Some(err) = get_msgbox_error(&v) {
+                    result = Some(Err(err));
+                }
+            }
+            "terminal_response" => match v["type"].as_str().unwrap_or_default() {
+                "opened" if v["terminal_id"].as_i64() == Some(TERMINAL_ID as _) => {
+                    if !v["success"].as_bool().unwrap_or_default() {
+                        result = Some(Err(v["message"].as_str().unwrap_or_default().to_owned()));
+                    } else if !started {
+                        started = true;
+                        send_data(&session, serde_json::to_vec(&command).unwrap_or_default());
+                        if opts.no_stdin {
+                            send_data(&session, vec![]);
+                        } else {
+                            forward_stdin(session.clone());
+                        }
+                    }
+                }
+                "data" => {
+                    let terminal_id = v["terminal_id"].as_i64().unwrap_or_default();
+                    let data =
+                        crate::decode64(v["data"].as_str().unwrap_or_default()).unwrap_or_default();
+                    if terminal_id == TERMINAL_ID as i64 {
+                        let mut out = std::io::stdout().lock();
+                        out.write_all(&data).ok();
+                        out.flush().ok();
+                    } else if terminal_id == exec_stderr_id(TERMINAL_ID) as i64 {
+                        std::io::stderr().write_all(&data).ok();
+                    }
+                }
+                "closed" if v["terminal_id"].as_i64() == Some(TERMINAL_ID as _) => {
+                    let exit_code = v["exit_code"].as_i64().unwrap_or_default() as i32;
+                    // -1 if it is killed by the peer.
+                    result = Some(Ok(if exit_code < 0 { EXIT_ERROR } else { exit_code }));
+                }
+                "error" => {
+                    result = Some(Err(v["message"].as_str().unwrap_or_default().to_owned()));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    session.close();
+    handle.join().ok();
+    match result {
+        Some(Ok(exit_code)) => exit_code,
+        Some(Err(err)) => {
+            eprintln!("{}", err);
+            EXIT_ERROR
+        }
+        None => {
+            eprintln!("Connection closed");
+            EXIT_ERROR
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Headless remote control client, `rustdesk --cli connect <id>`.
+//!
+//! Session events are written to stdout as line-delimited JSON, e.g.
+//! `{"event":"peer_info","hostname":"...",...}`, and commands are read from stdin
+//! as line-delimited JSON, e.g. `{"cmd":"login","password":"..."}`.
+
+use super::password::PasswordSource;
+use crate::{
+    client::QualityStatus,
+    ui_session_interface::{io_loop, InvokeUiSession, Session},
+};
+use hbb_common::{log, message_proto::*, rendezvous_proto::ConnType};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+/// Write one event line to stdout.
+///
+/// `fields` must be a json object, `event` is inserted into it.
+pub fn print_event(event: &str, fields: Value) {
+    let mut v = fields;
+    if let Some(m) = v.as_object_mut() {
+        m.insert("event".to_owned(), json!(event));
+    }
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "{}", v).ok();
+    out.flush().ok();
+}
+
+#[derive(Clone, Default)]
+pub struct CliHandler {
+    // Set if the session ends with an error message box.
+    failed: Arc<AtomicBool>,
+    // Events are sent here instead of stdout if set.
+    events: Option<std::sync::mpsc::Sender<(String, Value)>>,
+    // Told when the peer rejects the password, set by `new_session`.
+    id: String,
+    password_source: PasswordSource,
+}
+
+impl CliHandler {
+    pub fn with_events(events: std::sync::mpsc::Sender<(String, Value)>) -> Self {
+        Self {
+            events: Some(events),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    fn emit(&self, event: &str, fields: Value) {
+        match &self.events {
+            Some(tx) => {
+                tx.send((event.to_owned(), fields)).ok();
+            }
+            None => print_event(event, fields),
+        }
+    }
+
+    fn make_displays(displays: &Vec<DisplayInfo>) -> Value {
+        displays
+            .iter()
+            .map(|d| {
+                json!({
+                    "x": d.x,
+                    "y": d.y,
+                    "width": d.width,
+                    "height": d.height,
+                    "name": d.name,
+                    "online": d.online,
+                    "cursor_embedded": d.cursor_embedded,
+                })
+            })
+            .collect()
+    }
+}
+
+impl InvokeUiSession for CliHandler {
+    fn set_cursor_data(&self, _cd: CursorData) {}
+
+    fn set_cursor_id(&self, _id: String) {}
+
+    fn set_cursor_position(&self, _cp: CursorPosition) {}
+
+    fn set_display(&self, x: i32, y: i32, w: i32, h: i32, cursor_embedded: bool) {
+        self.emit(
+            "display",
+            json!({"x": x, "y": y, "width": w, "height": h, "cursor_embedded": cursor_embedded}),
+        );
+    }
+
+    fn switch_display(&self, display: &SwitchDisplay) {
+        self.emit(
+            "switch_display",
+            json!({
+                "display": display.display,
+                "x": display.x,
+                "y": display.y,
+                "width": display.width,
+                "height": display.height,
+            }),
+        );
+    }
+
+    fn set_peer_info(&self, pi: &PeerInfo) {
+        self.emit(
+            "peer_info",
+            json!({
+                "username": pi.username,
+                "hostname": pi.hostname,
+                "platform": pi.platform,
+                "version": pi.version,
+                "sas_enabled": pi.sas_enabled,
+                "current_display": pi.current_display,
+                "displays": Self::make_displays(&pi.displays),
+                "capabilities": crate::capability::from_platform_additions(&pi.platform_additions),
+            }),
+        );
+    }
+
+    fn set_displays(&self, displays: &Vec<DisplayInfo>) {
+        self.emit(
+            "displays",
+            json!({ "displays": Self::make_displays(displays) }),
+        );
+    }
+
+    fn set_platform_additions(&self, data: &str) {
+        let additions = serde_json::from_str::<Value>(data).unwrap_or(json!(data));
+        self.emit("platform_additions", json!({ "value": additions }));
+    }
+
+    fn on_connected(&self, conn_type: ConnType) {
+        self.emit(
+            "connected",
+            json!({ "conn_type": format!("{:?}", conn_type) }),
+        );
+    }
+
+    fn update_privacy_mode(&self) {}
+
+    fn set_permission(&self, name: &str, value: bool) {
+        self.emit("permission", json!({ "name": name, "value": value }));
+    }
+
+    fn close_success(&self) {}
+
+    fn update_quality_status(&self, qs: QualityStatus) {
+        if qs.delay.is_some() || qs.speed.is_some() {
+            self.emit("quality", json!({ "delay": qs.delay, "speed": qs.speed }));
+        }
+    }
+
+    fn set_connection_type(&self, is_secured: bool, direct: bool, stream_type: &str) {
+        self.emit(
+            "connection_ready",
+            json!({ "secure": is_secured, "direct": direct, "stream_type": stream_type }),
+        );
+    }
+
+    fn set_fingerprint(&self, fingerprint: String) {
+        self.emit("fingerprint", json!({ "fingerprint": fingerprint }));
+    }
+
+    fn job_error(&self, id: i32, err: String, file_num: i32) {
+        self.emit(
+            "job_error",
+            json!({ "id": id, "err": err, "file_num": file_num }),
+        );
+    }
+
+    fn job_done(&self, id: i32, file_num: i32) {
+        self.emit("job_done", json!({ "id": id, "file_num": file_num }));
+    }
+
+    fn clear_all_jobs(&self) {}
+
+    fn new_message(&self, msg: String) {
+        self.emit("chat", json!({ "text": msg }));
+    }
+
+    fn update_transfer_list(&self) {}
+
+    fn load_last_job(&self, _cnt: i32, _job_json: &str) {}
+
+    fn update_folder_files(
+        &self,
+        id: i32,
+        entries: &Vec<FileEntry>,
+        path: String,
+        is_local: bool,
+        only_count: bool,
+    ) {
+        self.emit(
+            "folder_files",
+            json!({
+                "id": id,
+                "path": path,
+                "is_local": is_local,
+                "only_count": only_count,
+                "entries": entries.iter().map(|e| json!({
+                    "name": e.name,
+                    "entry_type": e.entry_type.value(),
+                    "size": e.size,
+                    "modified_time": e.modified_time,
+                })).collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    fn sync_report(&self, id: i32, report: &str) {
+        let v = serde_json::from_str::<Value>(report).unwrap_or(json!({ "id": id }));
+        self.emit("sync_report", v);
+    }
+
+    fn confirm_delete_files(&self, _id: i32, _i: i32, _name: String) {}
+
+    fn override_file_confirm(
+        &self,
+        id: i32,
+        file_num: i32,
+        to: String,
+        is_upload: bool,
+        is_identical: bool,
+    ) {
+        self.emit(
+            "override_file_confirm",
+            json!({
+                "id": id,
+                "file_num": file_num,
+                "to": to,
+                "is_upload": is_upload,
+                "is_identical": is_identical,
+            }),
+        );
+    }
+
+    fn update_block_input_state(&self, on: bool) {
+        self.emit("block_input", json!({ "on": on }));
+    }
+
+    fn job_progress(&self, id: i32, file_num: i32, speed: f64, finished_size: f64) {
+        self.emit(
+            "job_progress",
+            json!({
+                "id": id,
+                "file_num": file_num,
+                "speed": speed,
+                "finished_size": finished_size,
+            }),
+        );
+    }
+
+    fn adapt_size(&self) {}
+
+    fn on_rgba(&self, _display: usize, _rgba: &mut scrap::ImageRgb) {}
+
+    fn msgbox(&self, msgtype: &str, title: &str, text: &str, link: &str, retry: bool) {
+        if msgtype.contains("error") && !retry {
+            self.failed.store(true, Ordering::SeqCst);
+        }
+        if msgtype == "re-input-password" {
+            self.password_source.reject(&self.id);
+        }
+        self.emit(
+            "msgbox",
+            json!({
+                "type": msgtype,
+                "title": title,
+                "text": text,
+                "link": link,
+                "retry": retry,
+            }),
+        );
+    }
+
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    fn clipboard(&self, _content: String) {}
+
+    fn cancel_msgbox(&self, tag: &str) {
+        self.emit("cancel_msgbox", json!({ "tag": tag }));
+    }
+
+    fn switch_back(&self, _id: &str) {}
+
+    fn portable_service_running(&self, running: bool) {
+        self.emit("portable_service_running", json!({ "running": running }));
+    }
+
+    fn on_voice_call_started(&self) {}
+
+    fn on_voice_call_closed(&self, _reason: &str) {}
+
+    fn on_voice_call_waiting(&self) {}
+
+    fn on_voice_call_incoming(&self) {}
+
+    fn get_rgba(&self, _display: usize) -> *const u8 {
+        std::ptr::null()
+    }
+
+    fn next_rgba(&self, _display: usize) {}
+
+    #[cfg(all(feature = "vram", feature = "flutter"))]
+    fn on_texture(&self, _display: usize, _texture: *mut std::ffi::c_void) {}
+
+    fn set_multiple_windows_session(&self, sessions: Vec<WindowsSession>) {
+        self.emit(
+            "windows_sessions",
+            json!({
+                "sessions": sessions
+                    .iter()
+                    .map(|s| json!({ "sid": s.sid, "name": s.name }))
+                    .collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    fn set_current_display(&self, disp_idx: i32) {
+        self.emit("current_display", json!({ "display": disp_idx }));
+    }
+
+    #[cfg(feature = "flutter")]
+    fn is_multi_ui_session(&self) -> bool {
+        false
+    }
+
+    fn update_record_status(&self, _start: bool) {}
+
+    fn printer_request(&self, _id: i32, _path: String) {}
+
+    fn handle_screenshot_resp(&self, _sid: String, _msg: String) {}
+
+    fn handle_terminal_response(&self, response: TerminalResponse) {
+        use hbb_common::message_proto::terminal_response::Union;
+        match response.union {
+            Some(Union::Opened(opened)) => self.emit(
+                "terminal_response",
+                json!({
+                    "type": "opened",
+                    "terminal_id": opened.terminal_id,
+                    "success": opened.success,
+                    "message": opened.message,
+                    "pid": opened.pid,
+                    "service_id": opened.service_id,
+                }),
+            ),
+            Some(Union::Data(data)) => {
+                let output = if data.compressed {
+                    hbb_common::compress::decompress(&data.data)
+                } else {
+                    data.data.to_vec()
+                };
+                self.emit(
+                    "terminal_response",
+                    json!({
+                        "type": "data",
+                        "terminal_id": data.terminal_id,
+                        "data": crate::encode64(&output),
+                    }),
+                );
+            }
+            Some(Union::Closed(closed)) => self.emit(
+                "terminal_response",
+                json!({
+                    "type": "closed",
+                    "terminal_id": closed.terminal_id,
+                    "exit_code": closed.exit_code,
+                }),
+            ),
+            Some(Union::Error(error)) => self.emit(
+                "terminal_response",
+                json!({
+                    "type": "error",
+                    "terminal_id": error.terminal_id,
+                    "message": error.message,
+                }),
+            ),
+            None => {}
+            Some(_) => {
+                log::warn!("Unhandled terminal response type");
+            }
+        }
+    }
+}
+
+/// The error of a "msgbox" event that ends a non-interactive session, if any.
+pub(super) fn get_msgbox_error(v: &Value) -> Option<String> {
+    let msgtype = v["type"].as_str().unwrap_or_default();
+    let title = v["title"].as_str().unwrap_or_default();
+    let text = v["text"].as_str().unwrap_or_default();
+    match msgtype {
+        "input-password" => Some("Password required".to_owned()),
+        "re-input-password" => Some(title.to_owned()),
+        "input-2fa" => Some("2FA is not supported".to_owned()),
+        t if t.contains("error") => Some(format!("{}: {}", title, text)),
+        _ => {
+            log::info!("{}: {}: {}", msgtype, title, text);
+            None
+        }
+    }
+}
+
+/// Commands accepted on stdin, one json object per line.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Login {
+        password: String,
+        #[serde(default)]
+        os_username: String,
+        #[serde(default)]
+        os_password: String,
+        #[serde(default)]
+        remember: bool,
+    },
+    #[serde(rename = "2fa")]
+    TwoFactor {
+        code: String,
+        #[serde(default)]
+        trust_this_device: bool,
+    },
+    Chat {
+        text: String,
+    },
+    Type {
+        text: String,
+    },
+    Key {
+        name: String,
+        #[serde(default)]
+        alt: bool,
+        #[serde(default)]
+        ctrl: bool,
+        #[serde(default)]
+        shift: bool,
+        #[serde(default)]
+        command: bool,
+    },
+    SwitchDisplay {
+        display: i32,
+    },
+    Refresh {
+        #[serde(default)]
+        display: i32,
+    },
+    ToggleOption {
+        name: String,
+    },
+    CtrlAltDel,
+    LockScreen,
+    Restart,
+    Close,
+}
+
+fn handle_command(session: &Session<CliHandler>, cmd: Command) {
+    match cmd {
+        Command::Login {
+            password,
+            os_username,
+            os_password,
+            remember,
+        } => session.login(os_username, os_password, password, remember),
+        Command::TwoFactor {
+            code,
+            trust_this_device,
+        } => session.send2fa(code, trust_this_device),
+        Command::Chat { text } => session.send_chat(text),
+        Command::Type { text } => session.input_string(&text),
+        Command::Key {
+            name,
+            alt,
+            ctrl,
+            shift,
+            command,
+        } => session.input_key(&name, false, true, alt, ctrl, shift, command),
+        Command::SwitchDisplay { display } => session.switch_display(display),
+        Command::Refresh { display } => session.refresh_video(display),
+        Command::ToggleOption { name } => session.toggle_option(name),
+        Command::CtrlAltDel => session.ctrl_alt_del(),
+        Command::LockScreen => session.lock_screen(),
+        Command::Restart => session.restart_remote_device(),
+        Command::Close => session.close(),
+    }
+}
+
+pub(super) fn new_session(
+    mut handler: CliHandler,
+    id: String,
+    password: String,
+    password_source: PasswordSource,
+    conn_type: ConnType,
+    force_relay: bool,
+) -> Session<CliHandler> {
+    handler.id = id.clone();
+    handler.password_source = password_source;
+    let session: Session<CliHandler> = Session {
+        password,
+        ui_handler: handler,
+        server_keyboard_enabled: Arc::new(RwLock::new(true)),
+        server_file_transfer_enabled: Arc::new(RwLock::new(true)),
+        server_clipboard_enabled: Arc::new(RwLock::new(true)),
+        ..Default::default()
+    };
+    session
+        .lc
+        .write()
+        .unwrap()
+        .initialize(id, conn_type, None, force_relay, None, None, None);
+    session
+}
+
+/// Run the io loop of `session` in a new thread.
+pub(super) fn start_io_loop(session: &Session<CliHandler>) -> std::thread::JoinHandle<()> {
+    let session = session.clone();
+    std::thread::spawn(move || {
+        let round = session.connection_round_state.lock().unwrap().new_round();
+        io_loop(session, round);
+    })
+}
+
+/// Connect to `id` and drive the session with stdin commands until it is closed.
+///
+/// Returns the process exit code.
+pub fn connect(
+    id: String,
+    password: String,
+    password_source: PasswordSource,
+    force_relay: bool,
+) -> i32 {
+    let session = new_session(
+        CliHandler::default(),
+        id,
+        password,
+        password_source,
+        ConnType::DEFAULT_CONN,
+        force_relay,
+    );
+    let handle = start_io_loop(&session);
+    let cloned = session.clone();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Command>(&line) {
+                Ok(cmd) => handle_command(&cloned, cmd),
+                Err(e) => print_event(
+                    "error",
+                    json!({ "text": format!("Invalid command: {}", e) }),
+                ),
+            }
+        }
+        // stdin closed, nobody can drive the session anymore.
+        cloned.close();
+    });
+    if handle.join().is_err() {
+        log::error!("cli session thread panicked");
+        return 1;
+    }
+    print_event("closed", json!({}));
+    if session.failed() {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"login","password":"abc"}"#).unwrap(),
+            Command::Login {
+                password: "abc".to_owned(),
+                os_username: "".to_owned(),
+                os_password: "".to_owned(),
+                remember: false,
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"2fa","code":"123456"}"#).unwrap(),
+            Command::TwoFactor {
+                code: "123456".to_owned(),
+                trust_this_device: false,
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"ctrl_alt_del"}"#).unwrap(),
+            Command::CtrlAltDel
+        );
+        assert!(serde_json::from_str::<Command>(r#"{"cmd":"unknown"}"#).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Where the cli takes the peer password from.
+//!
+//! `--password <password>`         the literal password
+//! `--password-env <name>`         an environment variable
+//! `--password-fd <fd>`            the first line read from a file descriptor (unix only), not 0-2
+//! `--password-file <path>`        the first line of a file, which must not be accessible by others
+//! `--password-command <command>`  a git-credential style helper, see [`PasswordSource::Command`]
+//!
+//! Without any of them, the password is prompted on the terminal.
+
+use hbb_common::{bail, log, ResultType};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+pub const OPTIONS: &[&str] = &[
+    "--password",
+    "--password-env",
+    "--password-fd",
+    "--password-file",
+    "--password-command",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordSource {
+    Prompt,
+    Literal(String),
+    Env(String),
+    Fd(i32),
+    File(PathBuf),
+    /// The command is run with `get` or `erase` appended, through the shell.
+    /// `protocol=rustdesk` and `host=<id>` lines are written to its stdin.
+    /// For `get`, it prints `password=<password>` or just the password.
+    /// `erase` is called when the password is rejected by the peer.
+    Command(String),
+}
+
+impl Default for PasswordSource {
+    fn default() -> Self {
+        Self::Prompt
+    }
+}
+
+impl PasswordSource {
+    pub fn from_args(args: &[String]) -> ResultType<Self> {
+        let mut res = None;
+        for (i, arg) in args.iter().enumerate() {
+            if !OPTIONS.contains(&arg.as_str()) {
+                continue;
+            }
+            let Some(v) = args.get(i + 1) else {
+                bail!("Missing value of {}", arg);
+            };
+            if res.is_some() {
+                bail!("Only one password option is allowed");
+            }
+            res = Some(match arg.as_str() {
+                "--password" => Self::Literal(v.clone()),
+                "--password-env" => Self::Env(v.clone()),
+                "--password-fd" => match v.parse::<i32>() {
+                    // stdin is the command channel of `connect`, stdout and stderr are ours.
+                    Ok(fd) if fd > 2 => Self::Fd(fd),
+                    Ok(_) => bail!("File descriptors 0-2 can't be used for the password"),
+                    _ => bail!("Invalid file descriptor: {}", v),
+                },
+                "--password-file" => Self::File(PathBuf::from(v)),
+                _ => Self::Command(v.clone()),
+            });
+        }
+        Ok(res.unwrap_or_default())
+    }
+
+    /// Whether the password can be asked again after it is rejected.
+    pub fn is_interactive(&self) -> bool {
+        *self == Self::Prompt
+    }
+
+    pub fn get(&self, id: &str) -> ResultType<String> {
+        let password = match self {
+            Self::Prompt => rpassword::prompt_password("Enter password: ")?,
+            Self::Literal(v) => v.clone(),
+            Self::Env(name) => match std::env::var(name) {
+                Ok(v) => v,
+                Err(_) => bail!("Environment variable {} is not set", name),
+            },
+            Self::Fd(fd) => read_fd_line(*fd)?,
+            Self::File(path) => {
+                check_file_permission(path)?;
+                first_line(&std::fs::read_to_string(path)?)
+            }
+            Self::Command(cmd) => {
+                let output = run_helper(cmd, "get", id)?;
+                parse_helper_output(&output)
+            }
+        };
+        if password.is_empty() && !self.is_interactive() {
+            bail!("Empty password from {}", self);
+        }
+        Ok(password)
+    }
+
+    /// Called when the peer rejects the password.
+    pub fn reject(&self, id: &str) {
+        if let Self::Command(cmd) = self {
+            if let Err(err) = run_helper(cmd, "erase", id) {
+                log::error!("Failed to erase password with helper: {}", err);
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PasswordSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prompt => write!(f, "prompt"),
+            Self::Literal(_) => write!(f, "--password"),
+            Self::Env(name) => write!(f, "environment variable {}", name),
+            Self::Fd(fd) => write!(f, "file descriptor {}", fd),
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Command(cmd) => write!(f, "command {}", cmd),
+        }
+    }
+}
+
+fn first_line(s: &str) -> String {
+    s.lines().next().unwrap_or_default().to_owned()
+}
+
+// Read up to the first newline, the writer may keep the fd open.
+#[cfg(unix)]
+fn read_fd_line(fd: i32) -> ResultType<String> {
+    use std::os::unix::io::FromRawFd;
+    // A duplicate is read and closed, the fd itself stays open for the caller.
+    let dup = unsafe { hbb_common::libc::dup(fd) };
+    if dup < 0 {
+        bail!(
+            "Failed to read file descriptor {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        );
+    }
+    let mut file = unsafe { std::fs::File::from_raw_fd(dup) };
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    // Byte by byte, not to consume what follows the line.
+    while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    Ok(first_line(&String::from_utf8_lossy(&line)))
+}
+
+#[cfg(not(unix))]
+fn read_fd_line(_fd: i32) -> ResultType<String> {
+    bail!("--password-fd is not supported on this platform");
+}
+
+#[cfg(unix)]
+fn check_file_permission(path: &PathBuf) -> ResultType<()> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(path)?;
+    if !meta.is_file() {
+        bail!("{} is not a regular file", path.display());
+    }
+    if meta.uid() != unsafe { hbb_common::libc::geteuid() } {
+        bail!("{} is not owned by the current user", path.display());
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!(
+            "Permissions {:o} for {} are too open, it must not be accessible by others (e.g. 600)",
+            meta.mode() & 0o777,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_file_permission(path: &PathBuf) -> ResultType<()> {
+    // ACLs are not checked on Windows, only make sure it is a regular file.
+    if !std::fs::metadata(path)?.is_file() {
+        bail!("{} is not a regular file", path.display());
+    }
+    Ok(())
+}
+
+fn run_helper(cmd: &str, action: &str, id: &str) -> ResultType<String> {
+    let cmd = format!("{} {}", cmd, action);
+    #[cfg(windows)]
+    let mut command = {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(&cmd);
+        c
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(&cmd);
+        c
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("protocol=rustdesk\nhost={}\n\n", id).as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("{} exited with {}", cmd, output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn parse_helper_output(output: &str) -> String {
+    for line in output.lines() {
+        if let Some(v) = line.strip_prefix("password=") {
+            return v.to_owned();
+        }
+    }
+    if output.lines().count() == 1 && !output.contains('=') {
+        return first_line(output);
+    }
+    "".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_args() {
+        let args = |v: &[&str]| v.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            PasswordSource::from_args(&args(&["id"])).unwrap(),
+            PasswordSource::Prompt
+        );
+        assert_eq!(
+            PasswordSource::from_args(&args(&["id", "--password-env", "PW"])).unwrap(),
+            PasswordSource::Env("PW".to_owned())
+        );
+        assert!(PasswordSource::from_args(&args(&["--password-fd", "x"])).is_err());
+        assert!(PasswordSource::from_args(&args(&["--password-fd", "0"])).is_err());
+        assert_eq!(
+            PasswordSource::from_args(&args(&["--password-fd", "3"])).unwrap(),
+            PasswordSource::Fd(3)
+        );
+        assert!(PasswordSource::from_args(&args(&["--password-file"])).is_err());
+        assert!(
+            PasswordSource::from_args(&args(&["--password", "a", "--password-env", "b"])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_helper_output() {
+        assert_eq!(
+            parse_helper_output("protocol=rustdesk\nhost=123\npassword=abc\n"),
+            "abc"
+        );
+        assert_eq!(parse_helper_output("abc\n"), "abc");
+        assert_eq!(parse_helper_output("username=x\n"), "");
+    }
+}
@@ -0,0 +1,203 @@
+//! Port forward rule files, `rustdesk --cli forward --rules <file>`.
+//!
+//! TOML, or JSON if the file name ends with `.json`:
+//!
+//! ```toml
+//! [[forward]]
+//! peer = "123456789"
+//! local_port = 8080
+//! remote_host = "192.168.1.10"
+//! remote_port = 80
+//! bind = "0.0.0.0"        # optional, 127.0.0.1 by default
+//! idle_timeout = 300      # optional, in seconds, 60 at most for UDP
+//! max_connections = 16    # optional
+//! ```
+//!
+//! `remote_port = 0` is dynamic (SOCKS5) forwarding, and `remote_host` with the `udp:`
+//! prefix is UDP forwarding, the same as the `forward` command. All forwards to a peer are
+//! multiplexed through one port forward connection, see [`crate::server::mux_forward`].
+
+use crate::port_forward::{ForwardOptions, DEFAULT_BIND_ADDRESS};
+use hbb_common::{bail, toml, ResultType};
+use serde_derive::Deserialize;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    forward: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub peer: String,
+    pub local_port: i32,
+    #[serde(default)]
+    pub remote_host: String,
+    #[serde(default)]
+    pub remote_port: i32,
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// In seconds, 0 is no timeout.
+    #[serde(default)]
+    pub idle_timeout: u64,
+    /// 0 is unlimited.
+    #[serde(default)]
+    pub max_connections: usize,
+}
+
+fn default_bind() -> String {
+    DEFAULT_BIND_ADDRESS.to_owned()
+}
+
+impl Rule {
+    pub fn options(&self) -> ForwardOptions {
+        ForwardOptions {
+            bind: self.bind.clone(),
+            idle_timeout: (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout)),
+            max_connections: self.max_connections,
+            session: None,
+        }
+    }
+
+    fn is_udp(&self) -> bool {
+        crate::server::udp_forward::parse_host(&self.remote_host).is_some()
+    }
+
+    fn validate(&mut self) -> ResultType<()> {
+        if self.peer.is_empty() {
+            bail!("peer is missing");
+        }
+        if self.local_port <= 0 || self.local_port > u16::MAX as i32 {
+            bail!("invalid local_port {}", self.local_port);
+        }
+        if self.remote_port < 0 || self.remote_port > u16::MAX as i32 {
+            bail!("invalid remote_port {}", self.remote_port);
+        }
+        if self.remote_port == 0 {
+            // dynamic forwarding
+            self.remote_host.clear();
+        } else if self.remote_host.is_empty() {
+            self.remote_host = "localhost".to_owned();
+        }
+        if self.bind.is_empty() {
+            self.bind = default_bind();
+        }
+        Ok(())
+    }
+}
+
+pub fn parse(text: &str, is_json: bool) -> ResultType<Vec<Rule>> {
+    let file: RuleFile = if is_json {
+        serde_json::from_str(text)?
+    } else {
+        toml::from_str(text)?
+    };
+    let mut rules = file.forward;
+    for (i, rule) in rules.iter_mut().enumerate() {
+        if let Err(err) = rule.validate() {
+            bail!("forward #{}: {}", i + 1, err);
+        }
+    }
+    for (i, a) in rules.iter().enumerate() {
+        if rules[..i]
+            .iter()
+            .any(|b| b.local_port == a.local_port && b.bind == a.bind && b.is_udp() == a.is_udp())
+        {
+            bail!(
+                "forward #{}: {} {}:{} is used more than once",
+                i + 1,
+                if a.is_udp() { "UDP" } else { "TCP" },
+                a.bind,
+                a.local_port
+            );
+        }
+    }
+    Ok(rules)
+}
+
+pub fn load(path: &Path) -> ResultType<Vec<Rule>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => bail!("Failed to read {}: {}", path.display(), err),
+    };
+    let is_json = path
+        .extension()
+        .map_or(false, |x| x.eq_ignore_ascii_case("json"));
+    match parse(&text, is_json) {
+        Ok(rules) => Ok(rules),
+        Err(err) => bail!("Invalid rule file {}: {}", path.display(), err),
+    }
+}
+
+/// Group the rules by peer, so that each peer is logged in with one password.
+pub fn group_by_peer(rules: Vec<Rule>) -> BTreeMap<String, Vec<Rule>> {
+    let mut res: BTreeMap<String, Vec<Rule>> = BTreeMap::new();
+    for rule in rules {
+        res.entry(rule.peer.clone()).or_default().push(rule);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rules = parse(
+            r#"
+[[forward]]
+peer = "123"
+local_port = 8080
+remote_host = "10.0.0.1"
+remote_port = 80
+idle_timeout = 60
+
+[[forward]]
+peer = "123"
+local_port = 1080
+bind = "0.0.0.0"
+max_connections = 4
+"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].bind, "127.0.0.1");
+        assert_eq!(
+            rules[0].options().idle_timeout,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(rules[1].remote_host, "");
+        assert_eq!(rules[1].options().max_connections, 4);
+        assert_eq!(group_by_peer(rules)["123"].len(), 2);
+
+        let rules = parse(
+            r#"{"forward": [{"peer": "1", "local_port": 22, "remote_port": 22}]}"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(rules[0].remote_host, "localhost");
+        assert_eq!(rules[0].options().idle_timeout, None);
+
+        assert!(parse("[[forward]]\npeer = \"1\"\nlocal_port = 0\n", false).is_err());
+        assert!(parse(
+            "[[forward]]\npeer = \"1\"\nlocal_port = 1\nport = 2\n",
+            false
+        )
+        .is_err());
+        assert!(parse(
+            "[[forward]]\npeer = \"1\"\nlocal_port = 1\n[[forward]]\npeer = \"2\"\nlocal_port = 1\n",
+            false
+        )
+        .is_err());
+        assert!(parse(
+            "[[forward]]\npeer = \"1\"\nlocal_port = 53\nremote_port = 53\n[[forward]]\npeer = \"1\"\nlocal_port = 53\nremote_host = \"udp:\"\nremote_port = 53\n",
+            false
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,388 @@
+//! Non-interactive file transfer, `rustdesk --cli push|pull <id> <from> <to>`, and
+//! `rustdesk --cli sync <id> <local> <remote>`.
+
+use super::{
+    headless::{get_msgbox_error, new_session, start_io_loop, CliHandler},
+    password::PasswordSource,
+};
+use crate::{
+    client::{Data, FileManager, Interface},
+    file_sync::SyncOptions,
+};
+use hbb_common::{fs, log, rendezvous_proto::ConnType};
+use serde_json::Value;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_CONNECTION: i32 = 1;
+pub const EXIT_TRANSFER: i32 = 3;
+
+#[derive(Debug, Default, Clone)]
+pub struct TransferOptions {
+    pub password: String,
+    /// Told when the peer rejects the password.
+    pub password_source: PasswordSource,
+    pub force_relay: bool,
+    pub include_hidden: bool,
+    /// Overwrite existing files at the destination, skip them otherwise.
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    files: usize,
+    total_size: u64,
+    finished_size: u64,
+    skipped: usize,
+    skipped_size: u64,
+    // Of the files of the job, by the file number.
+    sizes: Vec<u64>,
+}
+
+impl Summary {
+    /// The files of the job, of a `folder_files` event.
+    fn set_files(&mut self, v: &Value) {
+        let entries = v["entries"].as_array().map(|x| x.as_slice()).unwrap_or(&[]);
+        self.sizes = entries
+            .iter()
+            .map(|e| e["size"].as_u64().unwrap_or(0))
+            .collect();
+        self.files = self.sizes.len();
+        self.total_size = self.sizes.iter().sum();
+    }
+
+    /// The file `file_num` exists at the destination and is not overwritten.
+    fn skip(&mut self, file_num: usize) {
+        self.skipped += 1;
+        self.skipped_size += self.sizes.get(file_num).copied().unwrap_or_default();
+    }
+}
+
+/// Append the file name of `from` to `to` if `to` denotes a directory.
+fn resolve_target(from: &str, to: &str, to_is_dir: bool) -> String {
+    let name = from
+        .trim_end_matches(|c| c == '/' || c == '\\')
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default();
+    if to.ends_with('/') || to.ends_with('\\') {
+        format!("{}{}", to, name)
+    } else if to_is_dir {
+        std::path::Path::new(to)
+            .join(name)
+            .to_string_lossy()
+            .to_string()
+    } else {
+        to.to_owned()
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = size as f64;
+    let mut i = 0;
+    while size >= 1024. && i < UNITS.len() - 1 {
+        size /= 1024.;
+        i += 1;
+    }
+    format!("{:.2}{}", size, UNITS[i])
+}
+
+/// Copy `from` to `to`, `is_upload` is true for local -> remote.
+///
+/// Returns the process exit code.
+pub fn transfer(
+    id: String,
+    from: String,
+    to: String,
+    is_upload: bool,
+    opts: TransferOptions,
+) -> i32 {
+    let (tx, rx) = mpsc::channel::<(String, Value)>();
+    let session = new_session(
+        CliHandler::with_events(tx),
+        id,
+        opts.password.clone(),
+        opts.password_source.clone(),
+        ConnType::FILE_TRANSFER,
+        opts.force_relay,
+    );
+    let handle = start_io_loop(&session);
+    let to = if is_upload {
+        resolve_target(&from, &to, false)
+    } else {
+        resolve_target(&from, &to, std::path::Path::new(&to).is_dir())
+    };
+    let job_id = fs::get_next_job_id();
+    let start = Instant::now();
+    let mut summary = Summary::default();
+    let mut result: Option<Result<(), String>> = None;
+    let mut connected = false;
+    let mut last_progress = Instant::now();
+    while result.is_none() {
+        let (event, v) = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(e) => e,
+            Err(RecvTimeoutError::Timeout) => {
+                if handle.is_finished() {
+                    break;
+                }
+                continue;
+            }
+            // The io loop is finished.
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match event.as_str() {
+            "connected" => {
+                if !connected {
+                    connected = true;
+                    log::info!(
+                        "job {}: {} {} -> {}",
+                        job_id,
+                        if is_upload { "push" } else { "pull" },
+                        from,
+                        to
+                    );
+                    session.send(Data::SendFiles((
+                        job_id,
+                        fs::JobType::Generic,
+                        from.clone(),
+                        to.clone(),
+                        0,
+                        opts.include_hidden,
+                        !is_upload,
+                    )));
+                }
+            }
+            "msgbox" => {
+                if let Some(err) = get_msgbox_error(&v) {
+                    result = Some(Err(err));
+                }
+            }
+            "folder_files" if v["id"].as_i64() == Some(job_id as _) => {
+                summary.set_files(&v);
+            }
+            "override_file_confirm" if v["id"].as_i64() == Some(job_id as _) => {
+                let file_num = v["file_num"].as_i64().unwrap_or_default();
+                if !opts.overwrite {
+                    summary.skip(file_num as _);
+                    eprintln!("skip existing {}", v["to"].as_str().unwrap_or_default());
+                }
+                session.set_confirm_override_file(
+                    job_id,
+                    file_num as _,
+                    opts.overwrite,
+                    false,
+                    is_upload,
+                );
+            }
+            "job_progress" if v["id"].as_i64() == Some(job_id as _) => {
+                summary.finished_size = v["finished_size"].as_f64().unwrap_or_default() as _;
+                if last_progress.elapsed() >= Duration::from_secs(1) {
+                    last_progress = Instant::now();
+                    eprintln!(
+                        "{} / {}, {}/s",
+                        format_size(summary.finished_size),
+                        format_size(summary.total_size),
+                        format_size(v["speed"].as_f64().unwrap_or_default() as _)
+                    );
+                }
+            }
+            "job_done" if v["id"].as_i64() == Some(job_id as _) => {
+                result = Some(Ok(()));
+            }
+            "job_error" if v["id"].as_i64() == Some(job_id as _) => {
+                result = Some(Err(v["err"].as_str().unwrap_or_default().to_owned()));
+            }
+            _ => {}
+        }
+    }
+    session.close();
+    handle.join().ok();
+    let elapsed = start.elapsed().as_secs_f64();
+    match result {
+        Some(Ok(())) => {
+            println!(
+                "{} {} file(s), {} in {:.1}s, {} skipped, {}",
+                if is_upload { "pushed" } else { "pulled" },
+                summary.files - summary.skipped.min(summary.files),
+                format_size(summary.total_size - summary.skipped_size.min(summary.total_size)),
+                elapsed,
+                summary.skipped,
+                format_size(summary.skipped_size)
+            );
+            EXIT_OK
+        }
+        Some(Err(err)) => get_error_code(Some(err), connected),
+        None => get_error_code(None, connected),
+    }
+}
+
+/// Print the error of a transfer which failed, or of a connection closed if `None`,
+/// and return the process exit code.
+fn get_error_code(err: Option<String>, connected: bool) -> i32 {
+    match err {
+        Some(err) if connected => {
+            eprintln!("Transfer failed: {}", err);
+            EXIT_TRANSFER
+        }
+        Some(err) => {
+            eprintln!("Failed to connect: {}", err);
+            EXIT_CONNECTION
+        }
+        None => {
+            eprintln!("Connection closed");
+            if connected {
+                EXIT_TRANSFER
+            } else {
+                EXIT_CONNECTION
+            }
+        }
+    }
+}
+
+fn get_names(v: &Value) -> Vec<&str> {
+    v.as_array()
+        .map(|x| x.iter().filter_map(|x| x.as_str()).collect())
+        .unwrap_or_default()
+}
+
+/// Make `remote` a copy of the directory `local`, or `local` a copy of `remote` if
+/// `sync_opts.is_remote`, see [`crate::file_sync`].
+///
+/// Returns the process exit code.
+pub fn sync(
+    id: String,
+    local: String,
+    remote: String,
+    opts: TransferOptions,
+    sync_opts: SyncOptions,
+) -> i32 {
+    let (tx, rx) = mpsc::channel::<(String, Value)>();
+    let session = new_session(
+        CliHandler::with_events(tx),
+        id,
+        opts.password.clone(),
+        opts.password_source.clone(),
+        ConnType::FILE_TRANSFER,
+        opts.force_relay,
+    );
+    let handle = start_io_loop(&session);
+    let job_id = fs::get_next_job_id();
+    let start = Instant::now();
+    let mut summary = Summary::default();
+    let mut result: Option<Result<Value, String>> = None;
+    let mut connected = false;
+    let mut last_progress = Instant::now();
+    while result.is_none() {
+        let (event, v) = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(e) => e,
+            Err(RecvTimeoutError::Timeout) => {
+                if handle.is_finished() {
+                    break;
+                }
+                continue;
+            }
+            // The io loop is finished.
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match event.as_str() {
+            "connected" => {
+                if !connected {
+                    connected = true;
+                    log::info!(
+                        "job {}: sync {} {} {}",
+                        job_id,
+                        local,
+                        if sync_opts.is_remote { "<-" } else { "->" },
+                        remote
+                    );
+                    session.sync_files(job_id, local.clone(), remote.clone(), sync_opts.clone());
+                }
+            }
+            "msgbox" => {
+                if let Some(err) = get_msgbox_error(&v) {
+                    result = Some(Err(err));
+                }
+            }
+            "folder_files" if v["id"].as_i64() == Some(job_id as _) => {
+                summary.set_files(&v);
+            }
+            "job_progress" if v["id"].as_i64() == Some(job_id as _) => {
+                summary.finished_size = v["finished_size"].as_f64().unwrap_or_default() as _;
+                if last_progress.elapsed() >= Duration::from_secs(1) {
+                    last_progress = Instant::now();
+                    eprintln!(
+                        "{} / {}, {}/s",
+                        format_size(summary.finished_size),
+                        format_size(summary.total_size),
+                        format_size(v["speed"].as_f64().unwrap_or_default() as _)
+                    );
+                }
+            }
+            "sync_report" if v["id"].as_i64() == Some(job_id as _) => {
+                result = Some(Ok(v));
+            }
+            "job_error" if v["id"].as_i64() == Some(job_id as _) => {
+                result = Some(Err(v["err"].as_str().unwrap_or_default().to_owned()));
+            }
+            _ => {}
+        }
+    }
+    session.close();
+    handle.join().ok();
+    let elapsed = start.elapsed().as_secs_f64();
+    let report = match result {
+        Some(Ok(report)) => report,
+        Some(Err(err)) => return get_error_code(Some(err), connected),
+        None => return get_error_code(None, connected),
+    };
+    let (copy, delete) = (get_names(&report["copy"]), get_names(&report["delete"]));
+    if sync_opts.dry_run {
+        for name in copy.iter() {
+            println!("copy {}", name);
+        }
+        for name in delete.iter() {
+            println!("delete {}", name);
+        }
+    }
+    let prefix = if sync_opts.dry_run {
+        "dry run: "
+    } else if report["partial"].as_bool().unwrap_or_default() {
+        "partial: "
+    } else {
+        ""
+    };
+    println!(
+        "{}{} file(s) copied, {} deleted, {} unchanged in {:.1}s",
+        prefix,
+        copy.len(),
+        if sync_opts.delete { delete.len() } else { 0 },
+        report["unchanged"].as_u64().unwrap_or_default(),
+        elapsed
+    );
+    let errors = get_names(&report["errors"]);
+    for err in errors.iter() {
+        eprintln!("{}", err);
+    }
+    if errors.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_TRANSFER
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_target() {
+        assert_eq!(resolve_target("/a/b.txt", "/c/", false), "/c/b.txt");
+        assert_eq!(resolve_target("C:\\a\\b", "D:\\", false), "D:\\b");
+        assert_eq!(resolve_target("/a/b/", "/c/d", false), "/c/d");
+        assert_eq!(resolve_target("/a/b.txt", "/c/d.txt", false), "/c/d.txt");
+    }
+}
@@ -117,6 +117,9 @@ pub const LOGIN_MSG_2FA_WRONG: &str = "Wrong 2FA Code";
 pub const REQUIRE_2FA: &'static str = "2FA Required";
 pub const LOGIN_MSG_NO_PASSWORD_ACCESS: &str = "No Password Access";
 pub const LOGIN_MSG_OFFLINE: &str = "Offline";
+pub const LOGIN_MSG_BUSY: &str = "Too many sessions, the remote side is busy";
+// Followed by the position in the queue.
+pub const LOGIN_MSG_QUEUED: &str = "Queued: ";
 pub const LOGIN_SCREEN_WAYLAND: &str = "Wayland login screen is not supported";
 #[cfg(target_os = "linux")]
 pub const SCRAP_UBUNTU_HIGHER_REQUIRED: &str = "Wayland requires Ubuntu 21.04 or higher version.";
@@ -3322,6 +3325,17 @@ pub fn handle_login_error(
         }
         interface.msgbox("input-2fa", err, "", "");
         true
+    } else if let Some(pos) = err.strip_prefix(LOGIN_MSG_QUEUED) {
+        interface.msgbox(
+            "wait-remote-accept-nook",
+            "Prompt",
+            &format!(
+                "The remote side is busy, waiting for a free session, position {} in the queue...",
+                pos
+            ),
+            "",
+        );
+        true
     } else if LOGIN_ERROR_MAP.contains_key(err) {
         if let Some(msgbox_info) = LOGIN_ERROR_MAP.get(err) {
             interface.msgbox(
@@ -3681,6 +3695,13 @@ pub enum Data {
     ResetDecoder(Option<usize>),
     RenameFile((i32, String, String, bool)),
     TakeScreenshot((i32, String)),
+    /// Mirror the local directory to the remote one, or the remote one to the local one,
+    /// (id, local, remote, options), see `crate::file_sync`.
+    SyncFiles((i32, String, String, crate::file_sync::SyncOptions)),
+    /// The digests of the local files of a sync, (id, digests).
+    SyncDigests((i32, Vec<u8>)),
+    /// The files of a sync downloaded are verified, (id, errors).
+    SyncVerified((i32, Vec<String>)),
 }
 
 /// Keycode for key events.
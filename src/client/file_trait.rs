@@ -1,6 +1,7 @@
 use hbb_common::{fs, log, message_proto::*};
 
 use super::{Data, Interface};
+use crate::file_sync::SyncOptions;
 
 pub trait FileManager: Interface {
     #[cfg(not(any(
@@ -190,4 +191,8 @@ pub trait FileManager: Interface {
     fn rename_file(&self, act_id: i32, path: String, new_name: String, is_remote: bool) {
         self.send(Data::RenameFile((act_id, path, new_name, is_remote)));
     }
+
+    fn sync_files(&self, id: i32, local: String, remote: String, opts: SyncOptions) {
+        self.send(Data::SyncFiles((id, local, remote, opts)));
+    }
 }
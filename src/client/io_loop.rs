@@ -43,7 +43,7 @@ use hbb_common::{
 use hbb_common::{tokio::sync::Mutex as TokioMutex, ResultType};
 use scrap::CodecFormat;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::c_void,
     num::NonZeroI64,
     path::PathBuf,
@@ -64,6 +64,10 @@ pub struct Remote<T: InvokeUiSession> {
     read_jobs: Vec<fs::TransferJob>,
     write_jobs: Vec<fs::TransferJob>,
     remove_jobs: HashMap<i32, RemoveJob>,
+    sent_digests: crate::file_digest::Sent,
+    received_digests: crate::file_digest::Received,
+    received_deltas: crate::file_delta::Receiver,
+    sync_jobs: HashMap<i32, crate::file_sync::SyncJob>,
     timer: crate::RustDeskInterval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
     is_connected: bool,
@@ -87,6 +91,8 @@ struct ParsedPeerInfo {
     idd_impl: String,
     support_view_camera: bool,
     support_terminal: bool,
+    // See `crate::capability`.
+    capabilities: HashSet<String>,
 }
 
 impl ParsedPeerInfo {
@@ -111,6 +117,10 @@ impl<T: InvokeUiSession> Remote<T> {
             read_jobs: Vec::new(),
             write_jobs: Vec::new(),
             remove_jobs: Default::default(),
+            sent_digests: Default::default(),
+            received_digests: Default::default(),
+            received_deltas: Default::default(),
+            sync_jobs: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             last_update_jobs_status: (Instant::now(), Default::default()),
             is_connected: false,
@@ -269,7 +279,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                                if let Err(err) = crate::file_digest::handle_read_jobs(&mut self.read_jobs, &mut peer, &mut self.sent_digests).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
@@ -408,6 +418,28 @@ impl<T: InvokeUiSession> Remote<T> {
     }
 
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
+        if let Some(sync) = self.sync_jobs.get_mut(&id) {
+            if sync.is_removing() {
+                // Reported with the sync.
+                if sync.on_removed(file_num, err) {
+                    if let Some(sync) = self.sync_jobs.remove(&id) {
+                        self.handler.sync_report(id, &sync.report());
+                    }
+                }
+                return;
+            }
+            if sync.is_transferring() {
+                if let Some(err) = err.as_ref() {
+                    sync.on_copy_error(err.clone());
+                    // The peer fails the job, or this side does.
+                    fs::remove_job(id, &mut self.read_jobs);
+                    self.sent_digests.remove(id);
+                }
+                if let Some(sync) = self.sync_jobs.remove(&id) {
+                    self.finish_file_sync(sync);
+                }
+            }
+        }
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
                 let file_num = (file_num + 1) as usize;
@@ -632,6 +664,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 fs::transform_windows_path(&mut files);
                             }
                             let total_size = job.total_size();
+                            self.add_digest_read_job(&job);
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                             allow_err!(
@@ -694,6 +727,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 true,
                             );
                             job.is_last_job = true;
+                            self.add_digest_read_job(&job);
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                         }
@@ -769,11 +803,27 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
             }
             Data::SetConfirmOverrideFile((id, file_num, need_override, remember, is_upload)) => {
+                let support_delta = need_override
+                    && self
+                        .peer_info
+                        .capabilities
+                        .contains(crate::capability::FILE_DELTA);
                 if is_upload {
                     if let Some(job) = fs::get_job(id, &mut self.read_jobs) {
                         if remember {
                             job.set_overwrite_strategy(Some(need_override));
                         }
+                        // Confirmed by the peer when the delta ends.
+                        if support_delta && crate::file_delta::can_send(job, file_num) {
+                            let msg = crate::file_delta::new_block(
+                                id,
+                                file_num,
+                                crate::file_delta::REQUEST_BLK_ID,
+                                vec![],
+                            );
+                            allow_err!(peer.send(&msg).await);
+                            return true;
+                        }
                         job.confirm(&FileTransferSendConfirmRequest {
                             id,
                             file_num,
@@ -791,6 +841,15 @@ impl<T: InvokeUiSession> Remote<T> {
                         if remember {
                             job.set_overwrite_strategy(Some(need_override));
                         }
+                        // Confirmed when the delta ends.
+                        if support_delta {
+                            let sender = self.sender.clone();
+                            if self.received_deltas.start(job, file_num, move |msg| {
+                                sender.send(Data::Message(msg)).ok();
+                            }) {
+                                return true;
+                            }
+                        }
                         let mut msg = Message::new();
                         let mut file_action = FileAction::new();
                         let req = FileTransferSendConfirmRequest {
@@ -858,6 +917,10 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 let _ = fs::remove_job(id, &mut self.read_jobs);
                 self.remove_jobs.remove(&id);
+                self.sent_digests.remove(id);
+                self.received_digests.remove(id);
+                self.received_deltas.remove(id);
+                self.sync_jobs.remove(&id);
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -1001,6 +1064,24 @@ impl<T: InvokeUiSession> Remote<T> {
                 });
                 allow_err!(peer.send(&msg).await);
             }
+            Data::SyncFiles((id, local, remote, opts)) => {
+                self.start_file_sync(id, local, remote, opts, peer).await;
+            }
+            Data::SyncDigests((id, digests)) => {
+                self.on_file_sync_digests(id, digests, true, peer).await;
+            }
+            Data::SyncVerified((id, errors)) => {
+                if let Some(sync) = self.sync_jobs.get_mut(&id) {
+                    if sync.is_transferring() {
+                        let mut errors = errors.into_iter();
+                        let err = errors.next();
+                        for err in errors {
+                            sync.on_copy_error(err);
+                        }
+                        self.handle_job_status(id, -1, err);
+                    }
+                }
+            }
             _ => {}
         }
         true
@@ -1030,6 +1111,325 @@ impl<T: InvokeUiSession> Remote<T> {
         handler.job_progress(job.id(), file_num, speed, job.finished_size() as f64);
     }
 
+    fn add_digest_read_job(&mut self, job: &fs::TransferJob) {
+        if job.r#type == fs::JobType::Generic
+            && self
+                .peer_info
+                .capabilities
+                .contains(crate::capability::FILE_DIGEST)
+        {
+            self.sent_digests.add(job.id());
+        }
+    }
+
+    // The write job `id` is done, check its files against the digests of the peer. Returns
+    // whether it is a sync, answered by `Data::SyncVerified` then.
+    fn verify_file_digests(&mut self, id: i32) -> bool {
+        if crate::file_digest::is_enabled()
+            && self
+                .peer_info
+                .capabilities
+                .contains(crate::capability::FILE_DIGEST)
+        {
+            self.received_digests.expect(id);
+        }
+        let Some(job) = self.received_digests.take(id) else {
+            return false;
+        };
+        let is_sync = self
+            .sync_jobs
+            .get(&id)
+            .map_or(false, |x| x.is_transferring());
+        let retry =
+            !is_sync && crate::file_digest::is_retry_enabled() && self.received_digests.retry(id);
+        let handler = self.handler.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let failed = crate::file_digest::verify(&job).await;
+            if is_sync {
+                let errors = failed.into_iter().map(|x| x.2).collect();
+                sender.send(Data::SyncVerified((id, errors))).ok();
+                return;
+            }
+            if failed.is_empty() {
+                return;
+            }
+            if retry {
+                // The others are skipped as identical.
+                for (_, path, _) in failed.iter() {
+                    std::fs::remove_file(path).ok();
+                }
+                log::info!("Transfer job {} again, for the digest check", id);
+                sender
+                    .send(Data::SendFiles((
+                        id,
+                        fs::JobType::Generic,
+                        job.remote.clone(),
+                        job.local.to_string_lossy().to_string(),
+                        0,
+                        job.show_hidden,
+                        true,
+                    )))
+                    .ok();
+                return;
+            }
+            for (file_num, _, err) in failed {
+                handler.job_error(id, err, file_num);
+            }
+        });
+        is_sync
+    }
+
+    async fn handle_file_delta_block(&mut self, block: FileTransferBlock, peer: &mut Stream) {
+        if block.blk_id == crate::file_delta::SIGNATURE_BLK_ID {
+            // The peer has a copy of the file uploaded.
+            if let Some(job) = fs::get_job(block.id, &mut self.read_jobs) {
+                let sender = self.sender.clone();
+                crate::file_delta::send_delta(
+                    job,
+                    block.file_num,
+                    block.data.to_vec(),
+                    move |msg| {
+                        sender.send(Data::Message(msg)).ok();
+                    },
+                );
+            }
+        } else if let Some(req) = self.received_deltas.on_block(&block).await {
+            if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
+                job.confirm(&req).await;
+            }
+            allow_err!(peer.send(&new_send_confirm(req)).await);
+        }
+    }
+
+    async fn start_file_sync(
+        &mut self,
+        id: i32,
+        local: String,
+        remote: String,
+        opts: crate::file_sync::SyncOptions,
+        peer: &mut Stream,
+    ) {
+        let version = self.handler.lc.read().unwrap().version;
+        // The files are copied or skipped by the confirmations of their digests.
+        if !can_enable_overwrite_detection(version)
+            || (opts.compare_hash
+                && !self
+                    .peer_info
+                    .capabilities
+                    .contains(crate::capability::FILE_SYNC))
+        {
+            let err = "The sync is not supported by the remote side".to_owned();
+            self.handle_job_status(id, -1, Some(err));
+            return;
+        }
+        let local_entries = match fs::get_recursive_files(&local, opts.include_hidden) {
+            Ok(entries) => entries,
+            // Nothing at the destination yet.
+            Err(_) if opts.is_remote && !std::path::Path::new(&local).exists() => Vec::new(),
+            Err(err) => {
+                self.handle_job_status(id, -1, Some(err.to_string()));
+                return;
+            }
+        };
+        log::info!(
+            "New sync job {}, {} {} {}",
+            id,
+            local,
+            if opts.is_remote { "<-" } else { "->" },
+            remote
+        );
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_all_files(ReadAllFiles {
+            id,
+            path: remote.clone(),
+            include_hidden: opts.include_hidden,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        allow_err!(peer.send(&msg_out).await);
+        self.sync_jobs.insert(
+            id,
+            crate::file_sync::SyncJob::new(id, local, remote, opts, local_entries),
+        );
+    }
+
+    async fn on_file_sync_entries(&mut self, id: i32, entries: Vec<FileEntry>, peer: &mut Stream) {
+        let Some(sync) = self.sync_jobs.get_mut(&id) else {
+            return;
+        };
+        let files = sync.on_remote_entries(entries);
+        if files.is_empty() {
+            self.run_file_sync(id, peer).await;
+            return;
+        }
+        let request = crate::file_sync::HashRequest {
+            path: sync.remote.clone(),
+            files: files.clone(),
+        };
+        let data = serde_json::to_vec(&request).unwrap_or_default();
+        allow_err!(peer.send(&crate::file_sync::new_hash_block(id, data)).await);
+        let local = sync.local.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let digests = crate::file_sync::compute_digests(&local, &files).await;
+            sender.send(Data::SyncDigests((id, digests))).ok();
+        });
+    }
+
+    async fn on_file_sync_digests(
+        &mut self,
+        id: i32,
+        digests: Vec<u8>,
+        is_local: bool,
+        peer: &mut Stream,
+    ) {
+        if let Some(sync) = self.sync_jobs.get_mut(&id) {
+            if sync.on_digests(digests, is_local) {
+                self.run_file_sync(id, peer).await;
+            }
+        }
+    }
+
+    // Copy the files of the plan by a transfer job with the ID of the sync.
+    async fn run_file_sync(&mut self, id: i32, peer: &mut Stream) {
+        let Some(sync) = self.sync_jobs.get(&id) else {
+            return;
+        };
+        if sync.opts.dry_run || sync.plan().copy.is_empty() {
+            self.handle_job_status(id, -1, None);
+            return;
+        }
+        let (local, remote) = (sync.local.clone(), sync.remote.clone());
+        let include_hidden = sync.opts.include_hidden;
+        if sync.opts.is_remote {
+            self.write_jobs.push(fs::TransferJob::new_write(
+                id,
+                fs::JobType::Generic,
+                remote.clone(),
+                fs::DataSource::FilePath(PathBuf::from(&local)),
+                0,
+                include_hidden,
+                true,
+                Vec::new(),
+                true,
+            ));
+            allow_err!(
+                peer.send(&fs::new_send(
+                    id,
+                    fs::JobType::Generic,
+                    remote,
+                    0,
+                    include_hidden
+                ))
+                .await
+            );
+            return;
+        }
+        // The peer skips the files of the same size and modified time, whatever their digests.
+        let od = !sync.opts.compare_hash;
+        let mut job = match fs::TransferJob::new_read(
+            id,
+            fs::JobType::Generic,
+            remote.clone(),
+            fs::DataSource::FilePath(PathBuf::from(&local)),
+            0,
+            include_hidden,
+            false,
+            od,
+        ) {
+            Ok(job) => job,
+            Err(err) => {
+                self.sync_jobs.remove(&id);
+                self.handle_job_status(id, -1, Some(err.to_string()));
+                return;
+            }
+        };
+        let files = sync.filter_files(job.files());
+        job.set_files(files);
+        job.total_size = job.files().iter().map(|x| x.size).sum();
+        self.handler
+            .update_folder_files(id, job.files(), local, true, true);
+        #[cfg(not(windows))]
+        let files = job.files().clone();
+        #[cfg(windows)]
+        let mut files = job.files().clone();
+        #[cfg(windows)]
+        if self.handler.peer_platform() != "Windows" {
+            // peer is not windows, need transform \ to /
+            fs::transform_windows_path(&mut files);
+        }
+        let total_size = job.total_size();
+        self.add_digest_read_job(&job);
+        self.read_jobs.push(job);
+        self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+        allow_err!(
+            peer.send(&fs::new_receive(id, remote, 0, files, total_size))
+                .await
+        );
+    }
+
+    // The files of a sync are copied or skipped by its plan, instead of asking.
+    fn confirm_file_sync(&mut self, digest: &FileTransferDigest) -> bool {
+        let Some(sync) = self.sync_jobs.get(&digest.id) else {
+            return false;
+        };
+        let jobs = if digest.is_upload {
+            &mut self.read_jobs
+        } else {
+            &mut self.write_jobs
+        };
+        let Some(job) = fs::get_job(digest.id, jobs) else {
+            return false;
+        };
+        let Some(file) = job.files().get(digest.file_num as usize) else {
+            return false;
+        };
+        let copy = sync.should_copy(&file.name);
+        if !digest.is_upload {
+            job.set_digest(digest.file_size, digest.last_modified);
+        }
+        self.sender
+            .send(Data::SetConfirmOverrideFile((
+                digest.id,
+                digest.file_num,
+                copy,
+                false,
+                digest.is_upload,
+            )))
+            .ok();
+        true
+    }
+
+    // Remove the extraneous files once the copy is done, and report the sync. The removals
+    // of the peer are reported once all are answered.
+    fn finish_file_sync(&mut self, mut sync: crate::file_sync::SyncJob) {
+        if sync.opts.delete && !sync.opts.dry_run && !sync.is_partial() {
+            let names = sync.plan().delete.clone();
+            if sync.opts.is_remote {
+                let sep = std::path::MAIN_SEPARATOR.to_string();
+                for (i, name) in names.iter().enumerate() {
+                    let path = crate::file_sync::join(&sync.local, name, &sep);
+                    let err = std::fs::remove_file(&path).err().map(|x| x.to_string());
+                    sync.on_removed(i as _, err);
+                }
+            } else if !names.is_empty() {
+                let sep = self.handler.get_path_sep(true);
+                for (i, name) in names.iter().enumerate() {
+                    let path = crate::file_sync::join(&sync.remote, name, sep);
+                    self.sender
+                        .send(Data::RemoveFile((sync.id, path, i as _, true)))
+                        .ok();
+                }
+                sync.start_removing();
+                self.sync_jobs.insert(sync.id, sync);
+                return;
+            }
+        }
+        self.handler.sync_report(sync.id, &sync.report());
+    }
+
     fn update_jobs_status(&mut self) {
         let elapsed = self.last_update_jobs_status.0.elapsed().as_millis() as i32;
         if elapsed >= 1000 {
@@ -1332,6 +1732,13 @@ impl<T: InvokeUiSession> Remote<T> {
                         let peer_version = pi.version.clone();
                         let peer_platform = pi.platform.clone();
                         self.set_peer_info(&pi);
+                        if self.handler.is_file_transfer()
+                            && crate::capability::FILE_TRANSFER
+                                .iter()
+                                .any(|x| self.peer_info.capabilities.contains(*x))
+                        {
+                            allow_err!(peer.send(&crate::capability::new_block()).await);
+                        }
                         if self.handler.is_view_camera() {
                             if !self.check_view_camera_support(&peer_version, &peer_platform) {
                                 self.handler.lc.write().unwrap().handle_peer_info(&pi);
@@ -1468,6 +1875,10 @@ impl<T: InvokeUiSession> Remote<T> {
                                     fs::transform_windows_path(&mut entries);
                                 }
                             }
+                            if self.sync_jobs.get(&fd.id).map_or(false, |x| x.is_listing()) {
+                                self.on_file_sync_entries(fd.id, entries, peer).await;
+                                return true;
+                            }
                             self.handler
                                 .update_folder_files(fd.id, &entries, fd.path, false, false);
                             if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
@@ -1479,6 +1890,9 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Digest(digest)) => {
+                            if self.confirm_file_sync(&digest) {
+                                return true;
+                            }
                             if digest.is_upload {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.read_jobs) {
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
@@ -1610,7 +2024,43 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Block(block)) => {
+                            if crate::file_digest::is_digest_block(&block) {
+                                self.received_digests.on_digest(&block);
+                                return true;
+                            }
+                            if crate::file_sync::is_hash_block(&block) {
+                                self.on_file_sync_digests(
+                                    block.id,
+                                    block.data.to_vec(),
+                                    false,
+                                    peer,
+                                )
+                                .await;
+                                return true;
+                            }
+                            if crate::file_delta::is_delta_block(&block) {
+                                self.handle_file_delta_block(block, peer).await;
+                                return true;
+                            }
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
+                                // The previous file is complete when the blocks of the next one come.
+                                if block.file_num != job.file_num() {
+                                    let file_num = job.file_num();
+                                    if let Err(err) =
+                                        crate::file_scan::scan_received(job, file_num).await
+                                    {
+                                        let err = err.to_string();
+                                        allow_err!(
+                                            peer.send(&fs::new_error(block.id, &err, file_num))
+                                                .await
+                                        );
+                                        self.handle_job_status(block.id, file_num, Some(err));
+                                    }
+                                }
+                                let Some(job) = fs::get_job(block.id, &mut self.write_jobs) else {
+                                    return true;
+                                };
+                                self.received_digests.on_block(job, block.file_num);
                                 if let Err(_err) = job.write(block).await {
                                     // to-do: add "skip" for writing job
                                 }
@@ -1623,9 +2073,19 @@ impl<T: InvokeUiSession> Remote<T> {
                             let mut err: Option<String> = None;
                             let mut job_type = fs::JobType::Generic;
                             let mut printer_data = None;
+                            self.received_deltas.remove(d.id);
                             if let Some(job) = fs::remove_job(d.id, &mut self.write_jobs) {
+                                if let Err(e) =
+                                    crate::file_scan::scan_received(&job, job.file_num()).await
+                                {
+                                    let e = e.to_string();
+                                    allow_err!(
+                                        peer.send(&fs::new_error(d.id, &e, job.file_num())).await
+                                    );
+                                    err = Some(e);
+                                }
                                 job.modify_time();
-                                err = job.job_error();
+                                err = err.or(job.job_error());
                                 job_type = job.r#type;
                                 printer_data = match job.get_buf_data().await {
                                     Ok(d) => d,
@@ -1637,7 +2097,10 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                             match job_type {
                                 fs::JobType::Generic => {
-                                    self.handle_job_status(d.id, d.file_num, err);
+                                    // A sync is finished once its files are verified.
+                                    if !self.verify_file_digests(d.id) || err.is_some() {
+                                        self.handle_job_status(d.id, d.file_num, err);
+                                    }
                                 }
                                 fs::JobType::Printer => {
                                     if let Some(err) = err {
@@ -1673,6 +2136,18 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Error(e)) => {
+                            if let Some(sync) = self.sync_jobs.get(&e.id) {
+                                if sync.is_listing() && !sync.opts.is_remote {
+                                    // Nothing at the destination yet.
+                                    self.on_file_sync_entries(e.id, vec![], peer).await;
+                                    return true;
+                                }
+                                if !sync.is_transferring() && !sync.is_removing() {
+                                    self.sync_jobs.remove(&e.id);
+                                }
+                            }
+                            self.received_digests.remove(e.id);
+                            self.received_deltas.remove(e.id);
                             let job_type = fs::remove_job(e.id, &mut self.write_jobs)
                                 .map(|j| j.r#type)
                                 .unwrap_or(fs::JobType::Generic);
@@ -1942,6 +2417,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     },
                     Some(file_action::Union::SendConfirm(c)) => {
                         if let Some(job) = fs::get_job(c.id, &mut self.read_jobs) {
+                            self.sent_digests.on_confirm(&c);
                             job.confirm(&c).await;
                         }
                     }
@@ -2014,6 +2490,8 @@ impl<T: InvokeUiSession> Remote<T> {
 
     fn set_peer_info(&mut self, pi: &PeerInfo) {
         self.peer_info.platform = pi.platform.clone();
+        self.peer_info.capabilities =
+            crate::capability::from_platform_additions(&pi.platform_additions);
 
         // Check features field for terminal support
         if let Some(features) = pi.features.as_ref() {
@@ -2039,6 +2517,12 @@ impl<T: InvokeUiSession> Remote<T> {
                 .map(|v| v.as_bool())
                 .flatten()
                 .unwrap_or(false);
+            if let Some(profile) = platform_additions
+                .get("permission_profile")
+                .and_then(|v| v.as_str())
+            {
+                log::info!("Permission profile of the session: {}", profile);
+            }
         }
     }
 
@@ -0,0 +1,679 @@
+//! Delta transfer of the files overwriting a copy at the destination, like rsync.
+//!
+//! When the overwrite of a file is confirmed, instead of the whole file:
+//!
+//! 1. The receiver sends the signature of its copy, the rolling checksum and the strong hash
+//!    of each block, in a block with [`SIGNATURE_BLK_ID`]. If the sender confirms the
+//!    overwrite, it asks for the signature with [`REQUEST_BLK_ID`] first.
+//! 2. The sender finds the blocks of the copy in its file, and sends the instructions to
+//!    rebuild it, copying the blocks or writing the bytes changed, with [`DELTA_BLK_ID`],
+//!    then its SHA-256 digest with [`END_BLK_ID`], empty if it fails.
+//! 3. The receiver rebuilds the file next to the copy, and replaces the copy if the digest
+//!    matches. It confirms the file with `skip` then, or with the offset 0 to transfer it in
+//!    full otherwise, as if there is no delta.
+//!
+//! Only the files from 1 MB are sent by delta, to the peers supporting it, see
+//! [`crate::capability`], unless `enable-file-transfer-delta` is `N`.
+
+use hbb_common::{bail, config::Config, fs, log, message_proto::*, tokio, ResultType};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+pub const REQUEST_BLK_ID: u32 = u32::MAX - 1;
+pub const SIGNATURE_BLK_ID: u32 = u32::MAX - 2;
+pub const DELTA_BLK_ID: u32 = u32::MAX - 3;
+pub const END_BLK_ID: u32 = u32::MAX - 4;
+pub const OPTION_ENABLE_FILE_TRANSFER_DELTA: &str = "enable-file-transfer-delta";
+
+const MIN_SIZE: u64 = 1 << 20;
+const MIN_BLOCK_SIZE: u64 = 4 << 10;
+const MAX_BLOCKS: u64 = 1 << 15;
+const STRONG_LEN: usize = 16;
+// The bytes written and copied by the instructions of a delta block.
+const MAX_WORK: usize = 4 << 20;
+const DELTA_SUFFIX: &str = ".delta";
+
+const COPY: u8 = 1;
+const LITERAL: u8 = 2;
+
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_ENABLE_FILE_TRANSFER_DELTA) != "N"
+}
+
+pub fn is_delta_block(block: &FileTransferBlock) -> bool {
+    (END_BLK_ID..=REQUEST_BLK_ID).contains(&block.blk_id)
+}
+
+pub fn new_block(id: i32, file_num: i32, blk_id: u32, data: Vec<u8>) -> Message {
+    let mut fr = FileResponse::new();
+    fr.set_block(FileTransferBlock {
+        id,
+        file_num,
+        data: data.into(),
+        blk_id,
+        ..Default::default()
+    });
+    let mut msg = Message::new();
+    msg.set_file_response(fr);
+    msg
+}
+
+pub fn confirm_request(id: i32, file_num: i32, skip: bool) -> FileTransferSendConfirmRequest {
+    FileTransferSendConfirmRequest {
+        id,
+        file_num,
+        union: Some(if skip {
+            file_transfer_send_confirm_request::Union::Skip(true)
+        } else {
+            file_transfer_send_confirm_request::Union::OffsetBlk(0)
+        }),
+        ..Default::default()
+    }
+}
+
+/// Whether the file `file_num` of the read `job` is large enough to be sent by delta.
+pub fn can_send(job: &fs::TransferJob, file_num: i32) -> bool {
+    is_enabled()
+        && job
+            .files()
+            .get(file_num as usize)
+            .map_or(false, |x| x.size >= MIN_SIZE)
+}
+
+/// The bytes of the file written by the LITERAL instructions of a delta block, the bytes
+/// copied are from the copy at the destination.
+pub fn literal_len(block: &FileTransferBlock) -> u64 {
+    if block.blk_id != DELTA_BLK_ID {
+        return 0;
+    }
+    let mut data = &block.data[..];
+    let mut len = 0;
+    while let Some((op, rest)) = data.split_first() {
+        match (*op, rest.get(..4)) {
+            (COPY, _) if rest.len() >= 8 => data = &rest[8..],
+            (LITERAL, Some(x)) => {
+                let n = u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as u64;
+                len += n;
+                data = rest.get(4 + n as usize..).unwrap_or_default();
+            }
+            // Invalid, refused by the receiver.
+            _ => break,
+        }
+    }
+    len
+}
+
+/// The path of the file `file_num` of `job`.
+pub fn file_path(job: &fs::TransferJob, file_num: i32) -> Option<PathBuf> {
+    let fs::DataSource::FilePath(dir) = &job.data_source else {
+        return None;
+    };
+    let file = job.files().get(file_num as usize)?;
+    Some(fs::TransferJob::join(dir, &file.name))
+}
+
+fn block_size(file_size: u64) -> u64 {
+    (file_size / MAX_BLOCKS + 1)
+        .next_power_of_two()
+        .max(MIN_BLOCK_SIZE)
+}
+
+fn weak(data: &[u8]) -> (u32, u32) {
+    let n = data.len() as u32;
+    let mut a = 0u32;
+    let mut b = 0u32;
+    for (i, x) in data.iter().enumerate() {
+        a = a.wrapping_add(*x as u32);
+        b = b.wrapping_add((n - i as u32).wrapping_mul(*x as u32));
+    }
+    (a & 0xffff, b & 0xffff)
+}
+
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut res = [0; STRONG_LEN];
+    res.copy_from_slice(&Sha256::digest(data)[..STRONG_LEN]);
+    res
+}
+
+/// The signature of the full blocks of `path`: the block size, then the rolling checksum and
+/// the strong hash of each block.
+pub fn signature(path: &Path) -> ResultType<Vec<u8>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let bs = block_size(file.get_ref().metadata()?.len());
+    let mut res = (bs as u32).to_le_bytes().to_vec();
+    let mut buf = vec![0; bs as usize];
+    loop {
+        let mut n = 0;
+        while n < buf.len() {
+            match file.read(&mut buf[n..])? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        if n < buf.len() {
+            break;
+        }
+        let (a, b) = weak(&buf);
+        res.extend((a | b << 16).to_le_bytes());
+        res.extend(strong(&buf));
+    }
+    Ok(res)
+}
+
+struct Signature {
+    block_size: usize,
+    // rolling checksum -> the indexes and the strong hashes of the blocks
+    blocks: HashMap<u32, Vec<(u32, [u8; STRONG_LEN])>>,
+}
+
+impl Signature {
+    fn parse(data: &[u8]) -> ResultType<Self> {
+        if data.len() < 4 {
+            bail!("empty signature");
+        }
+        let (bs, rest) = data.split_at(4);
+        let block_size = u32::from_le_bytes([bs[0], bs[1], bs[2], bs[3]]) as usize;
+        if block_size == 0 || rest.len() % (4 + STRONG_LEN) != 0 {
+            bail!("invalid signature");
+        }
+        let mut blocks: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, x) in rest.chunks(4 + STRONG_LEN).enumerate() {
+            let mut s = [0; STRONG_LEN];
+            s.copy_from_slice(&x[4..]);
+            blocks
+                .entry(u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .or_default()
+                .push((i as u32, s));
+        }
+        Ok(Self { block_size, blocks })
+    }
+
+    fn find(&self, weak: u32, data: &[u8]) -> Option<u32> {
+        let blocks = self.blocks.get(&weak)?;
+        let s = strong(data);
+        blocks.iter().find(|x| x.1 == s).map(|x| x.0)
+    }
+}
+
+// The instructions of a delta, split in blocks of `MAX_WORK`.
+struct Instructions<F: FnMut(Vec<u8>)> {
+    data: Vec<u8>,
+    work: usize,
+    // (start, count) of the blocks to copy, not yet written
+    copy: Option<(u32, u32)>,
+    block_size: usize,
+    emit: F,
+}
+
+impl<F: FnMut(Vec<u8>)> Instructions<F> {
+    fn copy(&mut self, index: u32) {
+        match self.copy.as_mut() {
+            Some((start, count)) if *start + *count == index => *count += 1,
+            _ => {
+                self.flush_copy();
+                self.copy = Some((index, 1));
+            }
+        }
+        self.work += self.block_size;
+        if self.work >= MAX_WORK {
+            self.flush();
+        }
+    }
+
+    fn literal(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.flush_copy();
+        self.data.push(LITERAL);
+        self.data.extend((bytes.len() as u32).to_le_bytes());
+        self.data.extend(bytes);
+        self.work += bytes.len();
+        if self.work >= MAX_WORK {
+            self.flush();
+        }
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some((start, count)) = self.copy.take() {
+            self.data.push(COPY);
+            self.data.extend(start.to_le_bytes());
+            self.data.extend(count.to_le_bytes());
+        }
+    }
+
+    fn flush(&mut self) {
+        self.flush_copy();
+        if !self.data.is_empty() {
+            (self.emit)(std::mem::take(&mut self.data));
+        }
+        self.work = 0;
+    }
+}
+
+/// The delta of `path` against the `signature` of the copy, in blocks given to `emit`.
+/// Returns the SHA-256 digest of `path`.
+pub fn delta(path: &Path, signature: &[u8], emit: impl FnMut(Vec<u8>)) -> ResultType<Vec<u8>> {
+    let signature = Signature::parse(signature)?;
+    let bs = signature.block_size;
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut out = Instructions {
+        data: vec![],
+        work: 0,
+        copy: None,
+        block_size: bs,
+        emit,
+    };
+    // The bytes from `buf_start` of the file.
+    let mut buf: Vec<u8> = vec![];
+    let mut buf_start = 0usize;
+    let mut eof = false;
+    // The window is [pos, pos + bs), the literal bytes not written are [literal, pos).
+    let mut pos = 0usize;
+    let mut literal = 0usize;
+    let mut rolling: Option<(u32, u32)> = None;
+    loop {
+        if !eof && pos + bs > buf_start + buf.len() {
+            // Drop the bytes written.
+            let consumed = literal.min(pos) - buf_start;
+            if consumed > 0 {
+                buf.drain(..consumed);
+                buf_start += consumed;
+            }
+            let len = buf.len();
+            buf.resize(len + MAX_WORK.max(bs), 0);
+            let n = file.read(&mut buf[len..])?;
+            buf.truncate(len + n);
+            hasher.update(&buf[len..]);
+            eof = n == 0;
+            continue;
+        }
+        if pos + bs > buf_start + buf.len() {
+            break;
+        }
+        let window = &buf[pos - buf_start..pos - buf_start + bs];
+        let (a, b) = *rolling.get_or_insert_with(|| weak(window));
+        if let Some(index) = signature.find(a | b << 16, window) {
+            out.literal(&buf[literal - buf_start..pos - buf_start]);
+            out.copy(index);
+            pos += bs;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+        if pos + bs < buf_start + buf.len() {
+            let old = buf[pos - buf_start] as u32;
+            let new = buf[pos - buf_start + bs] as u32;
+            let a = a.wrapping_sub(old).wrapping_add(new) & 0xffff;
+            let b = b
+                .wrapping_sub((bs as u32).wrapping_mul(old))
+                .wrapping_add(a)
+                & 0xffff;
+            rolling = Some((a, b));
+        } else {
+            rolling = None;
+        }
+        pos += 1;
+        if pos - literal >= MAX_WORK {
+            out.literal(&buf[literal - buf_start..pos - buf_start]);
+            literal = pos;
+        }
+    }
+    out.literal(&buf[literal - buf_start..]);
+    out.flush();
+    Ok(hasher.finalize().to_vec())
+}
+
+// Rebuild a file from its copy and the delta, next to the copy.
+struct Patcher {
+    old: File,
+    out: BufWriter<File>,
+    tmp: PathBuf,
+    target: PathBuf,
+    block_size: u64,
+    // The full blocks of the copy, the ones in its signature.
+    blocks: u64,
+    // The size of the file sent, the bytes written are not allowed beyond.
+    size: u64,
+    written: u64,
+    hasher: Sha256,
+}
+
+impl Patcher {
+    fn new(target: &Path, size: u64) -> ResultType<Self> {
+        let old = File::open(target)?;
+        let len = old.metadata()?.len();
+        let block_size = block_size(len);
+        let tmp = PathBuf::from(format!("{}{}", target.display(), DELTA_SUFFIX));
+        Ok(Self {
+            old,
+            out: BufWriter::new(File::create(&tmp)?),
+            tmp,
+            target: target.to_owned(),
+            block_size,
+            blocks: len / block_size,
+            size,
+            written: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> ResultType<()> {
+        self.written += data.len() as u64;
+        if self.written > self.size {
+            bail!("the delta is larger than the file");
+        }
+        self.out.write_all(data)?;
+        self.hasher.update(data);
+        Ok(())
+    }
+
+    fn apply(&mut self, mut data: &[u8]) -> ResultType<()> {
+        let u32_at = |x: &[u8], i: usize| -> ResultType<u32> {
+            match x.get(i..i + 4) {
+                Some(x) => Ok(u32::from_le_bytes([x[0], x[1], x[2], x[3]])),
+                None => bail!("truncated delta"),
+            }
+        };
+        while let Some((op, rest)) = data.split_first() {
+            match *op {
+                COPY => {
+                    let (start, count) = (u32_at(rest, 0)? as u64, u32_at(rest, 4)? as u64);
+                    if start + count > self.blocks {
+                        bail!("invalid delta, the copy has {} blocks", self.blocks);
+                    }
+                    if self.written + count * self.block_size > self.size {
+                        bail!("the delta is larger than the file");
+                    }
+                    self.old.seek(SeekFrom::Start(start * self.block_size))?;
+                    let mut buf = vec![0; self.block_size as usize];
+                    for _ in 0..count {
+                        self.old.read_exact(&mut buf)?;
+                        self.write(&buf)?;
+                    }
+                    data = &rest[8..];
+                }
+                LITERAL => {
+                    let len = u32_at(rest, 0)? as usize;
+                    let Some(bytes) = rest.get(4..4 + len) else {
+                        bail!("truncated delta");
+                    };
+                    self.write(bytes)?;
+                    data = &rest[4 + len..];
+                }
+                _ => bail!("invalid delta"),
+            }
+        }
+        Ok(())
+    }
+
+    // Check the rebuilt file against the digest of the sender, and scan it.
+    async fn finish(mut self, digest: &[u8]) -> ResultType<Self> {
+        self.out.flush()?;
+        if self.hasher.clone().finalize().as_slice() != digest {
+            bail!("digest mismatch");
+        }
+        // Like the files received in full, removed if rejected.
+        let name = self.target.to_string_lossy().to_string();
+        crate::file_scan::scan_file(self.tmp.clone(), name).await?;
+        Ok(self)
+    }
+
+    fn commit(self, modified_time: u64) -> ResultType<()> {
+        std::fs::rename(&self.tmp, &self.target)?;
+        if modified_time > 0 {
+            let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified_time);
+            if let Err(err) = File::options()
+                .write(true)
+                .open(&self.target)
+                .and_then(|x| x.set_modified(time))
+            {
+                log::warn!(
+                    "Failed to set the modified time of {}: {}",
+                    self.target.display(),
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Patcher {
+    fn drop(&mut self) {
+        // Already moved into place, unless it fails.
+        std::fs::remove_file(&self.tmp).ok();
+    }
+}
+
+/// The delta transfers of a receiver.
+#[derive(Default)]
+pub struct Receiver {
+    // (job ID, file number) -> the patcher, `None` if the delta fails, and the modified time
+    patches: HashMap<(i32, i32), (Option<Patcher>, u64)>,
+    // (job ID, file number) of the files waiting for the sender to confirm the overwrite
+    confirming: HashSet<(i32, i32)>,
+}
+
+impl Receiver {
+    /// The overwrite of the file `file_num` of the job `id` is to be confirmed by the sender,
+    /// the only case it asks for the signature.
+    pub fn on_need_confirm(&mut self, id: i32, file_num: i32) {
+        self.confirming.insert((id, file_num));
+    }
+
+    /// The sender confirms the file without a delta.
+    pub fn on_confirm(&mut self, id: i32, file_num: i32) {
+        self.confirming.remove(&(id, file_num));
+    }
+
+    /// Whether the file is waiting for the confirmation, the request of the signature
+    /// confirms it.
+    pub fn take_confirming(&mut self, id: i32, file_num: i32) -> bool {
+        self.confirming.remove(&(id, file_num))
+    }
+
+    /// Start the delta of the file `file_num` of the write `job` if it has a copy, the
+    /// signature is computed in the background and sent by `emit`.
+    pub fn start(
+        &mut self,
+        job: &fs::TransferJob,
+        file_num: i32,
+        emit: impl Fn(Message) + Send + 'static,
+    ) -> bool {
+        let Some(target) = file_path(job, file_num) else {
+            return false;
+        };
+        if !is_enabled() || std::fs::metadata(&target).map_or(true, |x| x.len() < MIN_SIZE) {
+            return false;
+        }
+        let Some(file) = job.files().get(file_num as usize) else {
+            return false;
+        };
+        let modified_time = file.modified_time;
+        let patcher = match Patcher::new(&target, file.size) {
+            Ok(patcher) => patcher,
+            Err(err) => {
+                log::warn!("Failed to start the delta of {}: {}", target.display(), err);
+                return false;
+            }
+        };
+        let id = job.id();
+        self.patches
+            .insert((id, file_num), (Some(patcher), modified_time));
+        tokio::task::spawn_blocking(move || {
+            let data = signature(&target).unwrap_or_else(|err| {
+                log::warn!(
+                    "Failed to compute the signature of {}: {}",
+                    target.display(),
+                    err
+                );
+                vec![]
+            });
+            emit(new_block(id, file_num, SIGNATURE_BLK_ID, data));
+        });
+        true
+    }
+
+    /// Handle a delta block of the sender, returns the confirmation of the file to send when
+    /// the delta ends.
+    pub async fn on_block(
+        &mut self,
+        block: &FileTransferBlock,
+    ) -> Option<FileTransferSendConfirmRequest> {
+        let key = (block.id, block.file_num);
+        match block.blk_id {
+            DELTA_BLK_ID => {
+                let (patcher, _) = self.patches.get_mut(&key)?;
+                if let Some(p) = patcher.as_mut() {
+                    if let Err(err) = p.apply(&block.data) {
+                        log::warn!(
+                            "Failed to apply the delta of {}: {}",
+                            p.target.display(),
+                            err
+                        );
+                        // The rest is ignored.
+                        *patcher = None;
+                    }
+                }
+                None
+            }
+            END_BLK_ID => {
+                let (patcher, modified_time) = self.patches.remove(&key)?;
+                let res = Self::finish(patcher, &block.data, modified_time).await;
+                let skip = match res {
+                    Ok(target) => {
+                        log::info!("Delta transfer of {} done", target.display());
+                        true
+                    }
+                    Err(err) => {
+                        log::warn!("Delta transfer failed, transferring in full: {}", err);
+                        false
+                    }
+                };
+                Some(confirm_request(block.id, block.file_num, skip))
+            }
+            _ => None,
+        }
+    }
+
+    async fn finish(
+        patcher: Option<Patcher>,
+        digest: &[u8],
+        modified_time: u64,
+    ) -> ResultType<PathBuf> {
+        let Some(patcher) = patcher else {
+            bail!("failed to apply the delta");
+        };
+        if digest.is_empty() {
+            bail!("the sender failed to compute the delta");
+        }
+        let patcher = patcher.finish(digest).await?;
+        let target = patcher.target.clone();
+        patcher.commit(modified_time)?;
+        Ok(target)
+    }
+
+    /// The job is done, cancelled or failed.
+    pub fn remove(&mut self, id: i32) {
+        self.patches.retain(|k, _| k.0 != id);
+        self.confirming.retain(|k| k.0 != id);
+    }
+}
+
+/// Compute the delta of the file `file_num` of the read `job` against the `signature` in the
+/// background, sent by `emit`. An empty signature ends it at once, for the file to be
+/// transferred in full.
+pub fn send_delta(
+    job: &fs::TransferJob,
+    file_num: i32,
+    signature: Vec<u8>,
+    emit: impl Fn(Message) + Send + 'static,
+) {
+    let id = job.id();
+    let path = file_path(job, file_num);
+    tokio::task::spawn_blocking(move || {
+        let digest = match path {
+            Some(path) if !signature.is_empty() => delta(&path, &signature, |data| {
+                emit(new_block(id, file_num, DELTA_BLK_ID, data))
+            })
+            .unwrap_or_else(|err| {
+                log::warn!("Failed to compute the delta of {}: {}", path.display(), err);
+                vec![]
+            }),
+            _ => vec![],
+        };
+        emit(new_block(id, file_num, END_BLK_ID, digest));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let dir = std::env::temp_dir().join("rustdesk_file_delta_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        let mut data: Vec<u8> = (0..(MIN_SIZE as usize * 3))
+            .map(|i| (i * 7 + i / 5000) as u8)
+            .collect();
+        std::fs::write(&old, &data).unwrap();
+        data.drain(1000..1100);
+        data[500_000..500_010].copy_from_slice(b"0123456789");
+        data.splice(2_000_000..2_000_000, vec![1; 12345]);
+        data.extend(b"tail");
+        std::fs::write(&new, &data).unwrap();
+
+        let signature = signature(&old).unwrap();
+        let mut blocks = vec![];
+        let digest = delta(&new, &signature, |x| blocks.push(x)).unwrap();
+        assert_eq!(digest, Sha256::digest(&data).to_vec());
+        assert!(blocks.iter().map(|x| x.len()).sum::<usize>() < data.len() / 10);
+
+        let mut patcher = Patcher::new(&old, data.len() as u64).unwrap();
+        for block in &blocks {
+            patcher.apply(block).unwrap();
+        }
+        patcher.out.flush().unwrap();
+        assert_eq!(patcher.hasher.clone().finalize().to_vec(), digest);
+        patcher.commit(0).unwrap();
+        assert_eq!(std::fs::read(&old).unwrap(), data);
+        let literal: u64 = blocks
+            .iter()
+            .map(|x| {
+                literal_len(&FileTransferBlock {
+                    data: x.clone().into(),
+                    blk_id: DELTA_BLK_ID,
+                    ..Default::default()
+                })
+            })
+            .sum();
+        assert!(literal > 12345 && literal < data.len() as u64 / 10);
+
+        // The blocks to copy out of the copy, or the file larger than its size, are refused.
+        let copy = |start: u32, count: u32| {
+            let mut x = vec![COPY];
+            x.extend(start.to_le_bytes());
+            x.extend(count.to_le_bytes());
+            x
+        };
+        let mut patcher = Patcher::new(&old, data.len() as u64).unwrap();
+        let (blocks, block_size) = (patcher.blocks as u32, patcher.block_size);
+        assert!(patcher.apply(&copy(blocks, 1)).is_err());
+        assert!(patcher.apply(&copy(1, u32::MAX)).is_err());
+        drop(patcher);
+        let mut patcher = Patcher::new(&old, block_size).unwrap();
+        assert!(patcher.apply(&copy(0, 1)).is_ok());
+        assert!(patcher.apply(&copy(1, 1)).is_err());
+        drop(patcher);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
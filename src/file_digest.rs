@@ -0,0 +1,457 @@
+//! Whole-file digests of the file transfers, checked by the receiver.
+//!
+//! The sender hashes the blocks of each file with SHA-256 as it reads them, and sends the
+//! 32 bytes of the digest in a block with [`DIGEST_BLK_ID`] and the file number, right after
+//! the last block of the file. A file resumed at an offset is hashed from the disk instead,
+//! as its start is not read. Once the job is done, the receiver compares them with the digests
+//! of the files it wrote. A mismatch, or a file without a digest, fails the file with a job
+//! error. A download of the controlling side is transferred again, once, if
+//! `file-transfer-retry-on-mismatch` is `Y`.
+//!
+//! The digests are sent to the peers supporting them, see [`crate::capability`].
+//! The receiver does not check them if `enable-file-transfer-digest` is `N`.
+
+use hbb_common::{
+    compress::decompress, config::Config, fs, log, message_proto::*, tokio, ResultType, Stream,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+};
+
+pub const DIGEST_BLK_ID: u32 = u32::MAX;
+pub const OPTION_ENABLE_FILE_TRANSFER_DIGEST: &str = "enable-file-transfer-digest";
+pub const OPTION_RETRY_ON_MISMATCH: &str = "file-transfer-retry-on-mismatch";
+
+const DIGEST_LEN: usize = 32;
+// The jobs waiting for their digests, the oldest ones are dropped.
+const MAX_JOBS: usize = 64;
+
+pub fn is_enabled() -> bool {
+    Config::get_option(OPTION_ENABLE_FILE_TRANSFER_DIGEST) != "N"
+}
+
+pub fn is_retry_enabled() -> bool {
+    Config::get_option(OPTION_RETRY_ON_MISMATCH) == "Y"
+}
+
+fn compute(path: &Path) -> ResultType<[u8; DIGEST_LEN]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// The paths of the files of `job`, by file number.
+pub fn paths(job: &fs::TransferJob) -> Vec<PathBuf> {
+    let fs::DataSource::FilePath(dir) = &job.data_source else {
+        return vec![];
+    };
+    job.files()
+        .iter()
+        .map(|f| fs::TransferJob::join(dir, &f.name))
+        .collect()
+}
+
+/// The digests of `paths`, zeros for the files which can't be read.
+pub async fn compute_all(paths: Vec<PathBuf>) -> Vec<u8> {
+    tokio::task::spawn_blocking(move || {
+        let mut res = Vec::with_capacity(paths.len() * DIGEST_LEN);
+        for path in paths {
+            match compute(&path) {
+                Ok(digest) => res.extend(digest),
+                Err(err) => {
+                    log::warn!(
+                        "Failed to compute the digest of {}: {}",
+                        path.display(),
+                        err
+                    );
+                    res.extend([0; DIGEST_LEN]);
+                }
+            }
+        }
+        res
+    })
+    .await
+    .unwrap_or_default()
+}
+
+pub fn new_block(id: i32, file_num: i32, digest: Vec<u8>) -> Message {
+    let mut fr = FileResponse::new();
+    fr.set_block(FileTransferBlock {
+        id,
+        file_num,
+        data: digest.into(),
+        blk_id: DIGEST_BLK_ID,
+        ..Default::default()
+    });
+    let mut msg = Message::new();
+    msg.set_file_response(fr);
+    msg
+}
+
+pub fn is_digest_block(block: &FileTransferBlock) -> bool {
+    block.blk_id == DIGEST_BLK_ID
+}
+
+/// Put the `digest` of the file `file_num` in `digests`, by file number.
+pub fn set(digests: &mut Vec<u8>, file_num: i32, digest: &[u8]) {
+    let start = file_num.max(0) as usize * DIGEST_LEN;
+    if digest.len() != DIGEST_LEN {
+        return;
+    }
+    if digests.len() < start + DIGEST_LEN {
+        digests.resize(start + DIGEST_LEN, 0);
+    }
+    digests[start..start + DIGEST_LEN].copy_from_slice(digest);
+}
+
+pub fn to_hex(digests: &[u8]) -> Vec<String> {
+    digests
+        .chunks(DIGEST_LEN)
+        .map(|x| x.iter().map(|b| format!("{:02x}", b)).collect())
+        .collect()
+}
+
+#[derive(Default)]
+struct SentJob {
+    // The file being read, and the hash of its blocks.
+    file: Option<(i32, Sha256)>,
+    // By file number, zeros for the files not sent.
+    digests: Vec<u8>,
+}
+
+/// The read jobs of a sender sending the digests.
+#[derive(Default)]
+pub struct Sent {
+    jobs: HashMap<i32, SentJob>,
+    // (job ID, file number)
+    resumed: HashSet<(i32, i32)>,
+}
+
+impl Sent {
+    /// The job `id` sends the digests.
+    pub fn add(&mut self, id: i32) {
+        self.jobs.insert(id, SentJob::default());
+    }
+
+    /// The job is cancelled or failed.
+    pub fn remove(&mut self, id: i32) {
+        self.jobs.remove(&id);
+        self.resumed.retain(|x| x.0 != id);
+    }
+
+    /// A confirm of the receiver, to the read job.
+    pub fn on_confirm(&mut self, req: &FileTransferSendConfirmRequest) {
+        if !self.jobs.contains_key(&req.id) {
+            return;
+        }
+        if let Some(file_transfer_send_confirm_request::Union::OffsetBlk(offset)) = req.union {
+            if offset > 0 {
+                self.resumed.insert((req.id, req.file_num));
+            }
+        }
+    }
+
+    // The digest block of the file read before `block`, if any, and hash `block`.
+    async fn on_block(
+        &mut self,
+        job: &fs::TransferJob,
+        block: &FileTransferBlock,
+    ) -> Option<Message> {
+        let sent = self.jobs.get_mut(&block.id)?;
+        let msg = match sent.file.as_ref() {
+            Some((file_num, _)) if *file_num != block.file_num => self.finish_file(job).await,
+            _ => None,
+        };
+        let sent = self.jobs.get_mut(&block.id)?;
+        let (_, hasher) = sent
+            .file
+            .get_or_insert_with(|| (block.file_num, Sha256::new()));
+        if block.compressed {
+            hasher.update(decompress(&block.data));
+        } else {
+            hasher.update(&block.data);
+        }
+        msg
+    }
+
+    // The digest block of the file being read by `job`.
+    async fn finish_file(&mut self, job: &fs::TransferJob) -> Option<Message> {
+        let id = job.id();
+        let (file_num, hasher) = self.jobs.get_mut(&id)?.file.take()?;
+        let digest: [u8; DIGEST_LEN] = if self.resumed.remove(&(id, file_num)) {
+            let path = paths(job).get(file_num as usize).cloned()?;
+            match tokio::task::spawn_blocking(move || compute(&path)).await {
+                Ok(Ok(digest)) => digest,
+                Ok(Err(err)) => {
+                    log::warn!("Failed to compute the digest of a resumed file: {}", err);
+                    return None;
+                }
+                Err(_) => return None,
+            }
+        } else {
+            hasher.finalize().into()
+        };
+        set(&mut self.jobs.get_mut(&id)?.digests, file_num, &digest);
+        Some(new_block(id, file_num, digest.to_vec()))
+    }
+
+    // The digest block of the last file, and the digests of the job.
+    async fn on_done(&mut self, job: &fs::TransferJob) -> (Option<Message>, Option<Vec<u8>>) {
+        let msg = self.finish_file(job).await;
+        let digests = self.jobs.remove(&job.id()).map(|x| x.digests);
+        self.resumed.retain(|x| x.0 != job.id());
+        (msg, digests)
+    }
+}
+
+/// [`fs::handle_read_jobs`], sending the digest of each file after its last block.
+/// Returns the log of the transfer, and the job IDs and the digests of the jobs done.
+pub async fn handle_read_jobs(
+    jobs: &mut Vec<fs::TransferJob>,
+    stream: &mut Stream,
+    sent: &mut Sent,
+) -> ResultType<(String, Vec<(i32, Vec<u8>)>)> {
+    let mut job_log = Default::default();
+    let mut finished = Vec::new();
+    let mut digests = Vec::new();
+    for job in jobs.iter_mut() {
+        if job.is_last_job {
+            continue;
+        }
+        match job.read(stream).await {
+            Err(err) => {
+                // The file is not sent in full.
+                if let Some(x) = sent.jobs.get_mut(&job.id()) {
+                    x.file = None;
+                }
+                stream
+                    .send(&fs::new_error(job.id(), err, job.file_num()))
+                    .await?;
+            }
+            Ok(Some(block)) => {
+                if let Some(msg) = sent.on_block(job, &block).await {
+                    stream.send(&msg).await?;
+                }
+                stream.send(&fs::new_block(block)).await?;
+            }
+            Ok(None) => {
+                if job.job_completed() {
+                    job_log = fs::serialize_transfer_job(job, true, false, "");
+                    finished.push(job.id());
+                    match job.job_error() {
+                        Some(err) => {
+                            sent.remove(job.id());
+                            job_log = fs::serialize_transfer_job(job, false, false, &err);
+                            stream
+                                .send(&fs::new_error(job.id(), err, job.file_num()))
+                                .await?
+                        }
+                        None => {
+                            let (msg, job_digests) = sent.on_done(job).await;
+                            if let Some(msg) = msg {
+                                stream.send(&msg).await?;
+                            }
+                            if let Some(job_digests) = job_digests {
+                                digests.push((job.id(), job_digests));
+                            }
+                            stream.send(&fs::new_done(job.id(), job.file_num())).await?
+                        }
+                    }
+                }
+                // Otherwise waiting for a confirm.
+            }
+        }
+    }
+    for id in finished {
+        fs::remove_job(id, jobs);
+    }
+    Ok((job_log, digests))
+}
+
+/// A job of the receiver, waiting for its digests.
+#[derive(Debug, Clone, Default)]
+pub struct ReceivedJob {
+    pub id: i32,
+    /// To transfer the job again.
+    pub remote: String,
+    pub local: PathBuf,
+    pub show_hidden: bool,
+    /// The paths of the files written, by file number.
+    pub files: HashMap<i32, PathBuf>,
+    /// The digests of the sender, by file number.
+    pub digests: HashMap<i32, Vec<u8>>,
+}
+
+/// The jobs of a receiver, waiting for their digests.
+#[derive(Debug, Default)]
+pub struct Received {
+    jobs: VecDeque<ReceivedJob>,
+    // The jobs whose sender sends the digests.
+    expected: HashSet<i32>,
+    retried: HashSet<i32>,
+}
+
+impl Received {
+    /// A block of the file `file_num` of the write `job` is received.
+    pub fn on_block(&mut self, job: &fs::TransferJob, file_num: i32) {
+        let fs::DataSource::FilePath(dir) = &job.data_source else {
+            return;
+        };
+        let index = match self.jobs.iter().position(|x| x.id == job.id()) {
+            Some(index) => index,
+            None => {
+                if self.jobs.len() >= MAX_JOBS {
+                    self.jobs.pop_front();
+                }
+                self.jobs.push_back(ReceivedJob {
+                    id: job.id(),
+                    remote: job.remote.clone(),
+                    local: dir.clone(),
+                    show_hidden: job.show_hidden,
+                    files: HashMap::new(),
+                    digests: HashMap::new(),
+                });
+                self.jobs.len() - 1
+            }
+        };
+        let files = &mut self.jobs[index].files;
+        if !files.contains_key(&file_num) {
+            if let Some(file) = job.files().get(file_num as usize) {
+                files.insert(file_num, fs::TransferJob::join(dir, &file.name));
+            }
+        }
+    }
+
+    /// The sender of the job `id` sends the digests, each file written must have one.
+    pub fn expect(&mut self, id: i32) {
+        self.expected.insert(id);
+    }
+
+    /// The digest block of a file.
+    pub fn on_digest(&mut self, block: &FileTransferBlock) {
+        if let Some(job) = self.jobs.iter_mut().find(|x| x.id == block.id) {
+            job.digests.insert(block.file_num, block.data.to_vec());
+        }
+    }
+
+    /// The job is cancelled or failed.
+    pub fn remove(&mut self, id: i32) {
+        self.jobs.retain(|x| x.id != id);
+        self.expected.remove(&id);
+    }
+
+    /// The job is done, `None` if its digests are not expected.
+    pub fn take(&mut self, id: i32) -> Option<ReceivedJob> {
+        let index = self.jobs.iter().position(|x| x.id == id);
+        let job = index.and_then(|index| self.jobs.remove(index));
+        if self.expected.remove(&id) {
+            job
+        } else {
+            None
+        }
+    }
+
+    /// Whether the job can be transferred again, only once.
+    pub fn retry(&mut self, id: i32) -> bool {
+        self.retried.insert(id)
+    }
+}
+
+/// Check the files written by `job` against the digests of the sender, returns the file
+/// numbers, the paths and the errors of the files failing.
+pub async fn verify(job: &ReceivedJob) -> Vec<(i32, PathBuf, String)> {
+    let files = job.files.clone();
+    let digests = job.digests.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut res = vec![];
+        for (file_num, path) in files {
+            // Rejected by the scanner, and reported.
+            if !path.exists() {
+                continue;
+            }
+            let expected = digests
+                .get(&file_num)
+                .filter(|x| x.len() == DIGEST_LEN && x.iter().any(|b| *b != 0));
+            let err = match (expected, compute(&path)) {
+                (None, _) => format!(
+                    "The sender sent no SHA-256 digest of {}, the file is not verified",
+                    path.display()
+                ),
+                (Some(expected), Ok(digest)) if digest[..] == expected[..] => continue,
+                (Some(_), Ok(_)) => mismatch_error(&path),
+                (Some(_), Err(err)) => format!(
+                    "Failed to compute the digest of {}: {}",
+                    path.display(),
+                    err
+                ),
+            };
+            log::warn!("{}", err);
+            res.push((file_num, path, err));
+        }
+        res.sort_by_key(|x| x.0);
+        res
+    })
+    .await
+    .unwrap_or_default()
+}
+
+pub fn mismatch_error(path: &Path) -> String {
+    format!(
+        "The SHA-256 digest of {} does not match the sender's, the file is corrupted",
+        path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute() {
+        let path = std::env::temp_dir().join("rustdesk_file_digest_test");
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = compute(&path).unwrap();
+        assert_eq!(
+            to_hex(&digest),
+            vec!["ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"]
+        );
+        std::fs::remove_file(&path).ok();
+        assert!(compute(&path).is_err());
+        let mut digests = vec![];
+        set(&mut digests, 1, &digest);
+        assert_eq!(digests.len(), 2 * DIGEST_LEN);
+        assert_eq!(&digests[DIGEST_LEN..], &digest[..]);
+        let block = new_block(1, 2, digest.to_vec());
+        let Some(message::Union::FileResponse(fr)) = block.union else {
+            panic!("not a file response");
+        };
+        let Some(file_response::Union::Block(block)) = fr.union else {
+            panic!("not a block");
+        };
+        assert!(is_digest_block(&block));
+        assert_eq!(block.file_num, 2);
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let dir = std::env::temp_dir().join(format!("rustdesk_verify_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut job = ReceivedJob::default();
+        for i in 0..4 {
+            let path = dir.join(i.to_string());
+            std::fs::write(&path, b"abc").unwrap();
+            job.files.insert(i, path);
+        }
+        let digest = compute(&job.files[&0]).unwrap().to_vec();
+        job.digests.insert(0, digest.clone());
+        job.digests.insert(1, vec![0; DIGEST_LEN]);
+        job.digests.insert(2, [&digest[1..], &[0u8][..]].concat());
+        let failed: Vec<i32> = verify(&job).await.into_iter().map(|x| x.0).collect();
+        // zeros, mismatch, missing
+        assert_eq!(failed, [1, 2, 3]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
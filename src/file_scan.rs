@@ -0,0 +1,269 @@
+//! Scanning of the received files, before they are moved into place.
+//!
+//! The options:
+//!
+//! - `file-scan-command`: the scanner and its arguments, e.g. `clamscan --no-summary`, run
+//!   with the path of the received file appended, not by a shell. Quote the parts with
+//!   spaces, e.g. `"C:\Program Files\ClamAV\clamscan.exe" --no-summary`. Empty disables it.
+//! - `file-scan-action`: what is done with a file the scanner exits non-zero for, `delete` by
+//!   default, or `quarantine`.
+//! - `file-scan-quarantine-dir`: where the quarantined files are moved, `quarantine` of the
+//!   config directory by default.
+//! - `file-scan-timeout`: in seconds, 300 by default. A scanner failing to run or timing out
+//!   rejects the file too.
+//!
+//! A file is received to its `.download` file, which is scanned when all its blocks are
+//! written and synced. A rejected file fails with a job error, the other files of the job go on.
+
+use hbb_common::{
+    bail, config::Config, fs, futures::future::join_all, get_time, log, tokio,
+    tokio::task::JoinHandle, ResultType,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+pub const OPTION_FILE_SCAN_COMMAND: &str = "file-scan-command";
+pub const OPTION_FILE_SCAN_ACTION: &str = "file-scan-action";
+pub const OPTION_FILE_SCAN_QUARANTINE_DIR: &str = "file-scan-quarantine-dir";
+pub const OPTION_FILE_SCAN_TIMEOUT: &str = "file-scan-timeout";
+
+const DEFAULT_TIMEOUT: u64 = 300;
+// How long the file may stop growing before all its blocks are written.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Split by spaces, except in double quotes.
+fn split_command(command: &str) -> Vec<String> {
+    let mut res = vec![];
+    let mut arg = String::new();
+    let mut quoted = false;
+    let mut has_arg = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_arg {
+                    res.push(std::mem::take(&mut arg));
+                    has_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        res.push(arg);
+    }
+    res
+}
+
+fn run(args: &[String], path: &Path, timeout: Duration) -> ResultType<()> {
+    let Some((program, args)) = args.split_first() else {
+        bail!("no scanner");
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            bail!("the scanner timed out");
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        bail!("the scanner exited with {}", status);
+    }
+    Ok(())
+}
+
+fn quarantine(path: &Path, name: &str) -> ResultType<PathBuf> {
+    let dir = Config::get_option(OPTION_FILE_SCAN_QUARANTINE_DIR);
+    let dir = if dir.is_empty() {
+        Config::path("quarantine")
+    } else {
+        PathBuf::from(dir)
+    };
+    std::fs::create_dir_all(&dir)?;
+    let file_name = Path::new(name)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let to = dir.join(format!("{}-{}", get_time(), file_name));
+    if std::fs::rename(path, &to).is_err() {
+        // On another device
+        std::fs::copy(path, &to)?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(to)
+}
+
+fn scan(path: &Path, name: &str) -> ResultType<()> {
+    let command = Config::get_option(OPTION_FILE_SCAN_COMMAND);
+    let timeout = Duration::from_secs(
+        Config::get_option(OPTION_FILE_SCAN_TIMEOUT)
+            .parse()
+            .unwrap_or(DEFAULT_TIMEOUT),
+    );
+    let Err(err) = run(&split_command(&command), path, timeout) else {
+        return Ok(());
+    };
+    let action = if Config::get_option(OPTION_FILE_SCAN_ACTION) == "quarantine" {
+        match quarantine(path, name) {
+            Ok(to) => format!("quarantined to {}", to.display()),
+            Err(e) => {
+                log::error!("Failed to quarantine {}: {}", path.display(), e);
+                std::fs::remove_file(path).ok();
+                "deleted".to_owned()
+            }
+        }
+    } else {
+        std::fs::remove_file(path).ok();
+        "deleted".to_owned()
+    };
+    log::warn!("Received file {} is rejected, {}: {}", name, action, err);
+    bail!("{} is rejected by the file scanner, {}", name, action);
+}
+
+pub fn is_enabled() -> bool {
+    !Config::get_option(OPTION_FILE_SCAN_COMMAND)
+        .trim()
+        .is_empty()
+}
+
+// The job writes the blocks in the background, wait until the file has all of them, and sync it.
+fn wait_written(path: &Path, size: u64) -> ResultType<()> {
+    let mut len = 0;
+    let mut changed = Instant::now();
+    loop {
+        let now = std::fs::metadata(path)?.len();
+        if now >= size {
+            break;
+        }
+        if now != len {
+            len = now;
+            changed = Instant::now();
+        } else if changed.elapsed() > WRITE_TIMEOUT {
+            bail!("incomplete, {} of {} bytes", len, size);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .sync_all()?;
+    Ok(())
+}
+
+/// Scan the file `file_num` of the write `job`, once all its blocks are sent to the job.
+///
+/// The returned future does not borrow the job, so that it may be spawned while the job goes on.
+pub fn scan_received(
+    job: &fs::TransferJob,
+    file_num: i32,
+) -> impl Future<Output = ResultType<()>> + Send + 'static {
+    let received = received_file(job, file_num);
+    async move {
+        let Some((path, name, size)) = received else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = wait_written(&path, size) {
+                // Not to be moved into place unscanned.
+                std::fs::remove_file(&path).ok();
+                log::warn!("Received file {} is rejected, deleted: {}", name, err);
+                bail!("{} is rejected, {}", name, err);
+            }
+            scan(&path, &name)
+        })
+        .await?
+    }
+}
+
+// The `.download` file, the name and the size of the file to be scanned.
+fn received_file(job: &fs::TransferJob, file_num: i32) -> Option<(PathBuf, String, u64)> {
+    if !is_enabled() {
+        return None;
+    }
+    let fs::DataSource::FilePath(dir) = &job.data_source else {
+        return None;
+    };
+    let file = job.files().get(file_num as usize)?;
+    let path = fs::TransferJob::join(dir, &file.name);
+    let name = path.to_string_lossy().to_string();
+    let path = PathBuf::from(format!("{}.download", name));
+    // Skipped, or already moved into place.
+    if !path.exists() {
+        return None;
+    }
+    Some((path, name, file.size))
+}
+
+/// The scans spawned for the write jobs, which are to finish before a job is done.
+#[derive(Default)]
+pub struct Pending {
+    jobs: HashMap<i32, Vec<JoinHandle<()>>>,
+}
+
+impl Pending {
+    pub fn spawn(&mut self, id: i32, scan: impl Future<Output = ()> + Send + 'static) {
+        self.jobs.entry(id).or_default().push(tokio::spawn(scan));
+    }
+
+    /// Wait for the scans of the job `id`.
+    pub fn take(&mut self, id: i32) -> impl Future<Output = ()> + Send + 'static {
+        let scans = self.jobs.remove(&id).unwrap_or_default();
+        async move {
+            join_all(scans).await;
+        }
+    }
+
+    /// The job is cancelled, its scans go on detached.
+    pub fn remove(&mut self, id: i32) {
+        self.jobs.remove(&id);
+    }
+}
+
+/// Scan `path`, received for the file `name`.
+pub async fn scan_file(path: PathBuf, name: String) -> ResultType<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    tokio::task::spawn_blocking(move || scan(&path, &name)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command("clamscan  --no-summary"),
+            vec!["clamscan", "--no-summary"]
+        );
+        assert_eq!(
+            split_command(r#""C:\Program Files\scan.exe" -a "" x"#),
+            vec![r"C:\Program Files\scan.exe", "-a", "", "x"]
+        );
+        assert!(split_command(" ").is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+//! Mirror of a directory to the peer or from it, see `client::Data::SyncFiles`.
+//!
+//! The files of the source and the destination are listed and compared. The ones missing at
+//! the destination, or with another size or modified time, are copied by a transfer job with
+//! the ID of the sync, the others are skipped. With `compare_hash`, the files of the same size
+//! are compared by their SHA-256 digests instead of their modified time, the digests of the
+//! peer are asked in a block with [`HASH_BLK_ID`]. With `delete`, the files at the destination
+//! missing at the source are removed once the copy is done, the empty directories are left.
+//!
+//! The excluded files are neither copied nor removed. The patterns are globs, `*` and `?` don't
+//! match `/`, `**` does. A pattern without `/` matches a file or a directory of this name at
+//! any depth, e.g. `*.tmp` or `node_modules`, the other ones match the path from the root,
+//! e.g. `build/**/*.o`.
+//!
+//! What is copied and removed is reported by `sync_report` when the sync is done, once the
+//! peer has answered all the removals, or at once with `dry_run`, nothing being changed then.
+//! If a file fails to be copied, the sync stops and nothing is removed, the report is
+//! `partial` with the errors.
+
+use crate::glob;
+use hbb_common::{fs, message_proto::*};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+pub const HASH_BLK_ID: u32 = u32::MAX - 5;
+
+const DIGEST_LEN: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// From the peer to this side, from this side to the peer otherwise.
+    pub is_remote: bool,
+    pub include_hidden: bool,
+    pub compare_hash: bool,
+    pub delete: bool,
+    pub dry_run: bool,
+    pub excludes: Vec<String>,
+}
+
+/// The request of the digests of the files of the peer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashRequest {
+    pub path: String,
+    pub files: Vec<String>,
+}
+
+pub fn is_hash_block(block: &FileTransferBlock) -> bool {
+    block.blk_id == HASH_BLK_ID
+}
+
+/// The request of the digests, or the digests, 32 bytes per file in the order of the request.
+pub fn new_hash_block(id: i32, data: Vec<u8>) -> Message {
+    let mut fr = FileResponse::new();
+    fr.set_block(FileTransferBlock {
+        id,
+        data: data.into(),
+        blk_id: HASH_BLK_ID,
+        ..Default::default()
+    });
+    let mut msg = Message::new();
+    msg.set_file_response(fr);
+    msg
+}
+
+fn normalize(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// Whether the file `name`, relative to the root, is excluded by one of `patterns`.
+pub fn is_excluded(name: &str, patterns: &[String]) -> bool {
+    let name = normalize(name);
+    let parts: Vec<&str> = name.split('/').filter(|x| !x.is_empty()).collect();
+    patterns.iter().any(|pattern| {
+        let pattern = normalize(pattern);
+        let pattern = pattern.trim_matches('/');
+        if pattern.is_empty() {
+            return false;
+        }
+        if pattern.contains('/') {
+            // The file, or a directory containing it.
+            (1..=parts.len()).any(|n| glob::matches(pattern, &parts[..n].join("/"), Some('/')))
+        } else {
+            parts.iter().any(|x| glob::matches(pattern, x, Some('/')))
+        }
+    })
+}
+
+fn is_file(entry: &FileEntry) -> bool {
+    let t = entry.entry_type.value();
+    t == FileType::File as i32 || t == FileType::FileLink as i32
+}
+
+/// What a sync copies and removes, the names are relative to the roots.
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    pub copy: Vec<String>,
+    pub delete: Vec<String>,
+    pub unchanged: usize,
+    /// Compared by their digests.
+    pub hash: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(source: &[FileEntry], dest: &[FileEntry], opts: &SyncOptions) -> Self {
+        let dest: HashMap<String, &FileEntry> = dest
+            .iter()
+            .filter(|x| is_file(x))
+            .map(|x| (normalize(&x.name), x))
+            .collect();
+        let mut plan = Self::default();
+        let mut names = HashSet::new();
+        for entry in source.iter().filter(|x| is_file(x)) {
+            let name = normalize(&entry.name);
+            if is_excluded(&name, &opts.excludes) {
+                continue;
+            }
+            names.insert(name.clone());
+            match dest.get(&name) {
+                Some(d) if d.size == entry.size && opts.compare_hash => plan.hash.push(name),
+                Some(d) if d.size == entry.size && d.modified_time == entry.modified_time => {
+                    plan.unchanged += 1
+                }
+                _ => plan.copy.push(name),
+            }
+        }
+        if opts.delete {
+            plan.delete = dest
+                .into_keys()
+                .filter(|x| !names.contains(x) && !is_excluded(x, &opts.excludes))
+                .collect();
+            plan.delete.sort();
+        }
+        plan
+    }
+
+    /// The digests of the files in `hash` are in, of this side and of the peer.
+    pub fn compare(&mut self, local: &[u8], remote: &[u8]) {
+        for (i, name) in std::mem::take(&mut self.hash).into_iter().enumerate() {
+            let range = i * DIGEST_LEN..(i + 1) * DIGEST_LEN;
+            match (local.get(range.clone()), remote.get(range)) {
+                // Zeros if it can't be read.
+                (Some(a), Some(b)) if a == b && a.iter().any(|x| *x != 0) => self.unchanged += 1,
+                _ => self.copy.push(name),
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Listing,
+    Hashing {
+        local: Option<Vec<u8>>,
+        remote: Option<Vec<u8>>,
+    },
+    Transferring,
+    // The file numbers of the removals of the peer not answered yet.
+    Removing(HashSet<i32>),
+}
+
+/// A sync of the controlling side.
+#[derive(Debug)]
+pub struct SyncJob {
+    pub id: i32,
+    pub local: String,
+    pub remote: String,
+    pub opts: SyncOptions,
+    local_entries: Vec<FileEntry>,
+    plan: Plan,
+    state: State,
+    // The copy failed.
+    partial: bool,
+    deleted: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl SyncJob {
+    pub fn new(
+        id: i32,
+        local: String,
+        remote: String,
+        opts: SyncOptions,
+        local_entries: Vec<FileEntry>,
+    ) -> Self {
+        Self {
+            id,
+            local,
+            remote,
+            opts,
+            local_entries,
+            plan: Default::default(),
+            state: State::Listing,
+            partial: false,
+            deleted: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn is_listing(&self) -> bool {
+        self.state == State::Listing
+    }
+
+    pub fn is_transferring(&self) -> bool {
+        self.state == State::Transferring
+    }
+
+    pub fn is_removing(&self) -> bool {
+        matches!(self.state, State::Removing(_))
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// The files of the peer are listed, returns the files to compare by their digests.
+    pub fn on_remote_entries(&mut self, entries: Vec<FileEntry>) -> Vec<String> {
+        let local = std::mem::take(&mut self.local_entries);
+        self.plan = if self.opts.is_remote {
+            Plan::new(&entries, &local, &self.opts)
+        } else {
+            Plan::new(&local, &entries, &self.opts)
+        };
+        if self.plan.hash.is_empty() {
+            self.state = State::Transferring;
+        } else {
+            self.state = State::Hashing {
+                local: None,
+                remote: None,
+            };
+        }
+        self.plan.hash.clone()
+    }
+
+    /// The digests of this side, or of the peer, are in. Returns whether the plan is done.
+    pub fn on_digests(&mut self, digests: Vec<u8>, is_local: bool) -> bool {
+        let State::Hashing { local, remote } = &mut self.state else {
+            return false;
+        };
+        if is_local {
+            *local = Some(digests);
+        } else {
+            *remote = Some(digests);
+        }
+        let (Some(local), Some(remote)) = (local, remote) else {
+            return false;
+        };
+        let (local, remote) = (std::mem::take(local), std::mem::take(remote));
+        self.plan.compare(&local, &remote);
+        self.state = State::Transferring;
+        true
+    }
+
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// Whether the file `name` of the transfer job is to be copied.
+    pub fn should_copy(&self, name: &str) -> bool {
+        let name = normalize(name);
+        self.plan.copy.iter().any(|x| *x == name)
+    }
+
+    /// The files of the read job of an upload, the other ones are dropped.
+    pub fn filter_files(&self, files: &[FileEntry]) -> Vec<FileEntry> {
+        let copy: HashSet<&String> = self.plan.copy.iter().collect();
+        files
+            .iter()
+            .filter(|x| copy.contains(&normalize(&x.name)))
+            .cloned()
+            .collect()
+    }
+
+    /// A file failed to be copied, the sync stops.
+    pub fn on_copy_error(&mut self, err: String) {
+        self.partial = true;
+        self.errors.push(format!("Failed to copy: {}", err));
+    }
+
+    /// The removals of `plan().delete` are sent to the peer, the file numbers are the indexes.
+    pub fn start_removing(&mut self) {
+        self.state = State::Removing((0..self.plan.delete.len() as i32).collect());
+    }
+
+    /// The file `file_num` of `plan().delete` is removed, or failed to. Returns whether all
+    /// the removals are answered.
+    pub fn on_removed(&mut self, file_num: i32, err: Option<String>) -> bool {
+        if let State::Removing(pending) = &mut self.state {
+            if !pending.remove(&file_num) {
+                return pending.is_empty();
+            }
+        }
+        if let Some(name) = self.plan.delete.get(file_num as usize) {
+            match err {
+                Some(err) => self
+                    .errors
+                    .push(format!("Failed to delete {}: {}", name, err)),
+                None => self.deleted.push(name.clone()),
+            }
+        }
+        match &self.state {
+            State::Removing(pending) => pending.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// The report of the sync, the files to delete with `dry_run`, the ones deleted otherwise.
+    pub fn report(&self) -> String {
+        json!({
+            "id": self.id,
+            "local": self.local,
+            "remote": self.remote,
+            "is_remote": self.opts.is_remote,
+            "dry_run": self.opts.dry_run,
+            "partial": self.partial,
+            "copy": self.plan.copy,
+            "delete": if self.opts.dry_run { &self.plan.delete } else { &self.deleted },
+            "unchanged": self.plan.unchanged,
+            "errors": self.errors,
+        })
+        .to_string()
+    }
+}
+
+/// `name` relative to `root`, with the separator of the side of `root`.
+pub fn join(root: &str, name: &str, sep: &str) -> String {
+    format!(
+        "{}{}{}",
+        root.trim_end_matches(|c| c == '/' || c == '\\'),
+        sep,
+        name.replace('/', sep)
+    )
+}
+
+/// The digests of `files` in `path`, zeros for the files which can't be read.
+pub async fn compute_digests(path: &str, files: &[String]) -> Vec<u8> {
+    let root = std::path::PathBuf::from(path);
+    let paths = files
+        .iter()
+        .map(|x| fs::TransferJob::join(&root, x))
+        .collect();
+    crate::file_digest::compute_all(paths).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let entry = |name: &str, size, modified_time| FileEntry {
+            name: name.to_owned(),
+            entry_type: FileType::File.into(),
+            size,
+            modified_time,
+            ..Default::default()
+        };
+        let source = vec![
+            entry("a.txt", 1, 1),
+            entry("b.txt", 2, 2),
+            entry("sub\\c.txt", 3, 3),
+            entry("sub/x.tmp", 1, 1),
+            entry("node_modules/m.js", 1, 1),
+        ];
+        let dest = vec![
+            entry("a.txt", 1, 1),
+            entry("b.txt", 2, 5),
+            entry("old.txt", 1, 1),
+            entry("keep.tmp", 1, 1),
+        ];
+        let mut opts = SyncOptions {
+            delete: true,
+            excludes: vec!["*.tmp".to_owned(), "node_modules".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            Plan::new(&source, &dest, &opts),
+            Plan {
+                copy: vec!["b.txt".to_owned(), "sub/c.txt".to_owned()],
+                delete: vec!["old.txt".to_owned()],
+                unchanged: 1,
+                hash: vec![],
+            }
+        );
+        opts.compare_hash = true;
+        let mut plan = Plan::new(&source, &dest, &opts);
+        assert_eq!(plan.hash, vec!["a.txt", "b.txt"]);
+        plan.compare(&[[1; 32], [2; 32]].concat(), &[[1; 32], [3; 32]].concat());
+        assert_eq!(plan.copy, vec!["sub/c.txt", "b.txt"]);
+        assert_eq!(plan.unchanged, 1);
+
+        let patterns = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert!(is_excluded("a/b/c.o", &patterns(&["a/**/*.o"])));
+        assert!(is_excluded("a/c.o", &patterns(&["a/**/*.o"])));
+        assert!(!is_excluded("b/c.o", &patterns(&["a/*.o"])));
+        assert!(is_excluded("build/x/y", &patterns(&["/build"])));
+        assert!(!is_excluded("src/build.rs", &patterns(&["build"])));
+        assert!(is_excluded("x/file1", &patterns(&["file?"])));
+        assert!(!is_excluded(
+            &"a".repeat(5000),
+            &patterns(&["*a*a*a*a*a*a*a*a*a*a**a*a*a*a*b"])
+        ));
+
+        // Reported once all the removals are answered, the failed ones as errors.
+        let dest = vec![entry("x", 1, 1), entry("y", 1, 1), entry("z", 1, 1)];
+        let opts = SyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        let mut sync = SyncJob::new(0, "a".to_owned(), "b".to_owned(), opts, vec![]);
+        assert!(sync.on_remote_entries(dest).is_empty());
+        sync.start_removing();
+        assert!(sync.is_removing());
+        assert!(!sync.on_removed(0, None));
+        assert!(!sync.on_removed(0, Some("again".to_owned())));
+        assert!(!sync.on_removed(2, Some("denied".to_owned())));
+        assert!(sync.on_removed(1, None));
+        let report: serde_json::Value = serde_json::from_str(&sync.report()).unwrap();
+        assert_eq!(report["delete"], json!(["x", "y"]));
+        assert_eq!(report["errors"], json!(["Failed to delete z: denied"]));
+        assert_eq!(report["partial"], json!(false));
+    }
+}
@@ -801,6 +801,14 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn sync_report(&self, id: i32, report: &str) {
+        self.push_event(
+            "sync_report",
+            &[("id", &id.to_string()), ("value", report)],
+            &[],
+        );
+    }
+
     // unused in flutter
     fn update_transfer_list(&self) {}
 
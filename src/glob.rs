@@ -0,0 +1,112 @@
+//! Glob patterns, of the excludes of a sync and of the peer ID lists.
+//!
+//! `?` matches one character and `*` any characters, neither matches the separator if there is
+//! one. `**` matches any characters, the separator too, and `**/` matches no directory too.
+//! The other characters match themselves.
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Char(char),
+    // `?`
+    One,
+    // `*`
+    Star,
+    // `**`
+    AnyStar,
+    // Before the `**` and the separator of `**/`.
+    NoDir,
+}
+
+fn tokens(pattern: &[char], separator: Option<char>) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            '*' if pattern.get(i + 1) == Some(&'*') => {
+                if separator.is_some() && pattern.get(i + 2).copied() == separator {
+                    tokens.push(Token::NoDir);
+                }
+                tokens.push(Token::AnyStar);
+                i += 2;
+                continue;
+            }
+            '*' => tokens.push(Token::Star),
+            '?' => tokens.push(Token::One),
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+// Add the tokens reached without reading a character.
+fn closure(tokens: &[Token], states: &mut [bool]) {
+    for (i, token) in tokens.iter().enumerate() {
+        if !states[i] {
+            continue;
+        }
+        match token {
+            Token::Star | Token::AnyStar => states[i + 1] = true,
+            Token::NoDir => {
+                states[i + 1] = true;
+                states[i + 3] = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether all of `name` matches `pattern`.
+///
+/// Tracks all the pattern positions at once, so the cost is at most the product of the lengths.
+pub fn matches(pattern: &str, name: &str, separator: Option<char>) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let tokens = tokens(&pattern, separator);
+    let mut states = vec![false; tokens.len() + 1];
+    states[0] = true;
+    closure(&tokens, &mut states);
+    for c in name.chars() {
+        let is_separator = Some(c) == separator;
+        let mut next = vec![false; tokens.len() + 1];
+        for (i, token) in tokens.iter().enumerate() {
+            if !states[i] {
+                continue;
+            }
+            match token {
+                Token::Char(x) if *x == c => next[i + 1] = true,
+                Token::One if !is_separator => next[i + 1] = true,
+                Token::Star if !is_separator => next[i] = true,
+                Token::AnyStar => next[i] = true,
+                _ => {}
+            }
+        }
+        closure(&tokens, &mut next);
+        if !next.contains(&true) {
+            return false;
+        }
+        states = next;
+    }
+    states[tokens.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("88*", "881234", None));
+        assert!(matches("team-?-*", "team-a-pc", None));
+        assert!(!matches("team-?-*", "team-ab-pc", None));
+        assert!(matches("*", "", None));
+        assert!(!matches("?", "", None));
+        assert!(matches("a*b", "a/x/b", None));
+        assert!(!matches("a*b", "a/x/b", Some('/')));
+        assert!(matches("a/**/b", "a/x/y/b", Some('/')));
+        assert!(matches("a/**/b", "a/b", Some('/')));
+        assert!(!matches("a/?", "a//", Some('/')));
+        assert!(matches("ü?", "üß", None));
+        let name = "a".repeat(5000);
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b", &name, None));
+    }
+}
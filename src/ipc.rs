@@ -74,6 +74,8 @@ pub enum FS {
         overwrite_detection: bool,
         total_size: u64,
         conn_id: i32,
+        /// The peer sends the digests of the files, see `crate::file_digest`.
+        digests: bool,
     },
     CancelWrite {
         id: i32,
@@ -88,6 +90,19 @@ pub enum FS {
         id: i32,
         file_num: i32,
     },
+    /// The digest of a file of a write job, see `crate::file_digest`.
+    FileDigest {
+        id: i32,
+        file_num: i32,
+        digest: Vec<u8>,
+    },
+    /// A block of the delta of a file uploaded, see `crate::file_delta`.
+    Delta {
+        id: i32,
+        file_num: i32,
+        blk_id: u32,
+        data: Vec<u8>,
+    },
     WriteError {
         id: i32,
         file_num: i32,
@@ -288,6 +303,10 @@ pub enum Data {
     TerminalSessionCount(usize),
     #[cfg(target_os = "windows")]
     PortForwardSessionCount(Option<usize>),
+    PortForwardStats(Option<Vec<crate::server::port_forward_stats::Snapshot>>),
+    LoginBans(Option<Vec<crate::server::login_lockout::Ban>>),
+    /// The address to unban, empty for all, and the number unbanned in the reply.
+    LoginUnban((String, Option<usize>)),
     SocksWs(Option<Box<(Option<config::Socks5Server>, String)>>),
     #[cfg(any(target_os = "windows", target_os = "macos"))]
     Whiteboard((String, crate::whiteboard::CustomEvent)),
@@ -559,6 +578,14 @@ async fn handle(data: Data, stream: &mut Connection) {
                     password::update_temporary_password();
                 } else if name == "permanent-password" {
                     Config::set_permanent_password(&value);
+                } else if name == "view-only-password" {
+                    if !value.is_empty() && value == Config::get_permanent_password() {
+                        log::error!("The view-only password is the same as the permanent password");
+                    } else {
+                        crate::server::permission_profile::set_view_only_password(&value);
+                    }
+                } else if name == "audit-hmac-secret" {
+                    crate::audit_log::outbox::set_hmac_secret(&value);
                 } else if name == "salt" {
                     Config::set_salt(&value);
                 } else if name == "voice-call-input" {
@@ -753,6 +780,18 @@ async fn handle(data: Data, stream: &mut Connection) {
                 // Port forward session count is only a get value.
             }
         },
+        Data::PortForwardStats(None) => {
+            let stats = crate::server::port_forward_stats::live();
+            allow_err!(stream.send(&Data::PortForwardStats(Some(stats))).await);
+        }
+        Data::LoginBans(None) => {
+            let bans = crate::server::login_lockout::list();
+            allow_err!(stream.send(&Data::LoginBans(Some(bans))).await);
+        }
+        Data::LoginUnban((ip, None)) => {
+            let n = crate::server::login_lockout::unban(&ip);
+            allow_err!(stream.send(&Data::LoginUnban((ip, Some(n)))).await);
+        }
         _ => {}
     }
 }
@@ -1021,6 +1060,19 @@ pub fn set_permanent_password(v: String) -> ResultType<()> {
     set_config("permanent-password", v)
 }
 
+/// An empty password disables the view-only password.
+pub fn set_view_only_password(v: String) -> ResultType<()> {
+    if !v.is_empty() && v == get_permanent_password() {
+        bail!("The view-only password must differ from the permanent password");
+    }
+    set_config("view-only-password", v)
+}
+
+/// An empty secret disables the signature of the audit events.
+pub fn set_audit_hmac_secret(v: String) -> ResultType<()> {
+    set_config("audit-hmac-secret", v)
+}
+
 #[cfg(feature = "flutter")]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub fn set_unlock_pin(v: String, translate: bool) -> ResultType<()> {
@@ -1297,6 +1349,37 @@ pub async fn get_port_forward_session_count(ms_timeout: u64) -> ResultType<usize
     bail!("Failed to get port forward session count");
 }
 
+/// The traffic of port forwarding to this machine, see [`crate::server::port_forward_stats`].
+pub async fn get_port_forward_stats(
+    ms_timeout: u64,
+) -> ResultType<Vec<crate::server::port_forward_stats::Snapshot>> {
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::PortForwardStats(None)).await?;
+    if let Some(Data::PortForwardStats(Some(stats))) = c.next_timeout(ms_timeout).await? {
+        return Ok(stats);
+    }
+    bail!("Failed to get port forward stats");
+}
+
+pub async fn get_login_bans(ms_timeout: u64) -> ResultType<Vec<crate::server::login_lockout::Ban>> {
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::LoginBans(None)).await?;
+    if let Some(Data::LoginBans(Some(bans))) = c.next_timeout(ms_timeout).await? {
+        return Ok(bans);
+    }
+    bail!("Failed to get login bans");
+}
+
+/// Unban `ip`, or all addresses if it is empty, returns the number unbanned.
+pub async fn login_unban(ip: String, ms_timeout: u64) -> ResultType<usize> {
+    let mut c = connect(ms_timeout, "").await?;
+    c.send(&Data::LoginUnban((ip, None))).await?;
+    if let Some(Data::LoginUnban((_, Some(n)))) = c.next_timeout(ms_timeout).await? {
+        return Ok(n);
+    }
+    bail!("Failed to unban");
+}
+
 #[cfg(feature = "hwcodec")]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 #[tokio::main(flavor = "current_thread")]
@@ -37,13 +37,24 @@ pub mod flutter;
 pub mod flutter_ffi;
 use common::*;
 mod auth_2fa;
-#[cfg(feature = "cli")]
+mod capability;
+#[cfg(not(any(target_os = "ios")))]
+pub mod audit_log;
+#[cfg(any(
+    feature = "cli",
+    not(any(target_os = "android", target_os = "ios"))
+))]
 pub mod cli;
 #[cfg(not(target_os = "ios"))]
 mod clipboard;
 #[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
 pub mod core_main;
 mod custom_server;
+mod file_delta;
+mod file_digest;
+mod file_scan;
+mod file_sync;
+mod glob;
 mod lang;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod port_forward;
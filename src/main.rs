@@ -47,7 +47,12 @@ fn main() {
         "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
         -c, --connect=[REMOTE_ID] 'test only'
         -k, --key=[KEY] ''
-       -s, --server=[] 'Start server'",
+       -s, --server=[] 'Start server'
+        --password=[PASSWORD] 'The password of the remote side'
+        --password-env=[NAME] 'Read the password from an environment variable'
+        --password-fd=[FD] 'Read the first line of a file descriptor as the password'
+        --password-file=[PATH] 'Read the first line of a file as the password'
+        --password-command=[COMMAND] 'Get the password from a git-credential style helper'",
     );
     let matches = App::new("rustdesk")
         .version(crate::VERSION)
@@ -83,6 +88,20 @@ fn main() {
         }
         common::test_rendezvous_server();
         common::test_nat_type();
+        let mut password_args = vec![];
+        for name in cli::password::OPTIONS {
+            if let Some(v) = matches.value_of(&name[2..]) {
+                password_args.push(name.to_string());
+                password_args.push(v.to_owned());
+            }
+        }
+        let password_source = match cli::password::PasswordSource::from_args(&password_args) {
+            Ok(source) => source,
+            Err(err) => {
+                log::error!("{}", err);
+                return;
+            }
+        };
         let key = matches.value_of("key").unwrap_or("").to_owned();
         let token = LocalConfig::get_option("access_token");
         cli::start_one_port_forward(
@@ -92,6 +111,8 @@ fn main() {
             remote_port,
             key,
             token,
+            password_source,
+            Default::default(),
         );
     } else if let Some(p) = matches.value_of("connect") {
         common::test_rendezvous_server();
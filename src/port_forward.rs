@@ -1,8 +1,16 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::client::*;
+use crate::{client::*, server::port_forward_stats::TunnelStats};
 use hbb_common::{
     allow_err, bail,
+    bytes::{Bytes, BytesMut},
     config::READ_TIMEOUT,
     futures::{SinkExt, StreamExt},
     log,
@@ -10,11 +18,98 @@ use hbb_common::{
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        net::TcpStream,
+        sync::{mpsc, Mutex as TokioMutex},
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
 
+pub mod mux;
+mod socks5;
+mod udp;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+
+/// Options of one forward rule.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardOptions {
+    /// The local address to listen on, 127.0.0.1 if empty. Other users of the machine can
+    /// still connect to it, but not the other machines of the network.
+    pub bind: String,
+    /// Close a forwarded connection, or a UDP flow, without traffic for this long. UDP flows
+    /// are closed after [`crate::server::udp_forward::FLOW_IDLE_TIMEOUT`] at most.
+    pub idle_timeout: Option<Duration>,
+    /// Refuse new connections, or UDP flows, if this many are open. 0 is unlimited.
+    pub max_connections: usize,
+    /// Forward over the streams of this session, instead of a port forward connection
+    /// of every forwarded connection, or of the UDP forward.
+    pub session: Option<mux::Session>,
+}
+
+impl ForwardOptions {
+    fn bind_address(&self, port: i32) -> String {
+        let bind = if self.bind.is_empty() {
+            DEFAULT_BIND_ADDRESS
+        } else {
+            self.bind.as_str()
+        };
+        if bind.contains(':') {
+            format!(
+                "[{}]:{}",
+                bind.trim_start_matches('[').trim_end_matches(']'),
+                port
+            )
+        } else {
+            format!("{}:{}", bind, port)
+        }
+    }
+}
+
+/// The connection a forwarded connection is relayed over.
+enum Tunnel {
+    Stream(Stream),
+    Mux(Framed<TcpStream, BytesCodec>),
+}
+
+impl Tunnel {
+    async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
+        match self {
+            Self::Stream(stream) => stream.send_bytes(bytes).await,
+            Self::Mux(stream) => Ok(stream.send(bytes).await?),
+        }
+    }
+
+    async fn next(&mut self) -> Option<Result<BytesMut, std::io::Error>> {
+        match self {
+            Self::Stream(stream) => stream.next().await,
+            Self::Mux(stream) => stream.next().await,
+        }
+    }
+}
+
+/// Counts the open connections of a rule, the count is decreased when it is dropped.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn try_new(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        let n = count.fetch_add(1, Ordering::SeqCst);
+        if max > 0 && n >= max {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(count.clone()))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -42,6 +137,10 @@ fn run_rdp(port: u16) {
         .ok();
 }
 
+/// Forward connections to `port` to `remote_host:remote_port` reachable from the peer.
+///
+/// `port` 0 is RDP. `remote_port` 0 is dynamic forwarding, the destination of every connection
+/// is requested with SOCKS5, like `ssh -D`. `remote_host` with the `udp:` prefix forwards UDP.
 pub async fn listen(
     id: String,
     password: String,
@@ -53,37 +152,82 @@ pub async fn listen(
     lc: Arc<RwLock<LoginConfigHandler>>,
     remote_host: String,
     remote_port: i32,
+    opts: ForwardOptions,
 ) -> ResultType<()> {
-    let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+    if crate::server::mux_forward::is_host(&remote_host) {
+        bail!("Invalid remote host {}", remote_host);
+    }
+    if crate::server::udp_forward::parse_host(&remote_host).is_some() {
+        return udp::listen(
+            id,
+            password,
+            port,
+            interface,
+            ui_receiver,
+            key,
+            token,
+            lc,
+            remote_host,
+            remote_port,
+            opts,
+        )
+        .await;
+    }
+    let is_rdp = port == 0;
+    let is_socks5 = !is_rdp && remote_port == 0;
+    let listener = tcp::new_listener(opts.bind_address(port), true).await?;
     let addr = listener.local_addr()?;
     log::info!("listening on port {:?}", addr);
-    let is_rdp = port == 0;
     if is_rdp {
         run_rdp(addr.port());
     }
+    let rule = TunnelStats::new(if is_rdp {
+        format!("{} -> {} rdp", addr, id)
+    } else if is_socks5 {
+        format!("{} -> {} socks5", addr, id)
+    } else {
+        format!("{} -> {} {}:{}", addr, id, remote_host, remote_port)
+    });
     let mut ui_receiver = ui_receiver;
+    let count = Arc::new(AtomicUsize::new(0));
+    // The logins of the SOCKS5 connections take the data of the UI from it, one at a time.
+    let (login_tx, login_rx) = mpsc::unbounded_channel::<Data>();
+    let login_rx = Arc::new(TokioMutex::new(login_rx));
     loop {
         tokio::select! {
             Ok((forward, addr)) = listener.accept() => {
                 log::info!("new connection from {:?}", addr);
-                lc.write().unwrap().port_forward = (remote_host.clone(), remote_port);
-                let id = id.clone();
-                let password = password.clone();
+                let Some(guard) = ConnectionGuard::try_new(&count, opts.max_connections) else {
+                    log::warn!("refuse connection from {:?}, {} connections are open", addr, opts.max_connections);
+                    rule.add_error();
+                    continue;
+                };
+                let stats = rule.new_connection(addr.to_string());
+                if is_socks5 {
+                    // A slow client or login doesn't hold up the other connections.
+                    tokio::spawn(forward_socks5(id.clone(), password.clone(), login_tx.clone(), login_rx.clone(), interface.clone(), forward, addr, key.to_owned(), token.to_owned(), lc.clone(), guard, opts.clone(), stats));
+                    continue;
+                }
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
+                match open_tunnel(&id, &password, &mut ui_receiver, interface.clone(), Some(&mut forward), key, token, &lc, &remote_host, remote_port, is_rdp, &opts).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
+                        let idle_timeout = opts.idle_timeout;
                         tokio::spawn(async move {
-                            if let Err(err) = run_forward(forward, stream).await {
+                            let _guard = guard;
+                            if let Err(err) = run_forward(forward, stream, idle_timeout, &stats).await {
                                 interface.msgbox("error", "Error", &err.to_string(), "");
                             }
-                            log::info!("connection from {:?} closed", addr);
+                            log::info!("connection from {:?} closed, {}", addr, stats.snapshot());
                        });
                     }
                     Err(err) => {
+                        stats.add_error();
                         interface.on_establish_connection_error(err.to_string());
                     }
-                    _ => {}
+                    _ => {
+                        stats.add_error();
+                    }
                 }
             }
             d = ui_receiver.recv() => {
@@ -95,20 +239,248 @@ pub async fn listen(
                         println!("receive run_rdp from ui_receiver");
                         run_rdp(addr.port());
                     }
+                    Some(data @ (Data::Login(_) | Data::Message(_))) if is_socks5 => {
+                        login_tx.send(data).ok();
+                    }
                     _ => {}
                 }
             }
         }
     }
+    log::info!("port forward closed, {}", rule.snapshot());
     Ok(())
 }
 
+/// Reverse port forwarding, `remote_port` is listened on `bind` of the peer, and
+/// every connection accepted there is forwarded to `local_host:local_port`.
+pub async fn listen_reverse(
+    id: String,
+    password: String,
+    bind: String,
+    remote_port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    local_host: String,
+    local_port: i32,
+) -> ResultType<()> {
+    use crate::server::reverse_forward;
+    let mut ui_receiver = ui_receiver;
+    lc.write().unwrap().port_forward = (reverse_forward::listen_host(&bind), remote_port);
+    let Some(mut control) = connect_and_login(
+        &id,
+        &password,
+        &mut ui_receiver,
+        interface.clone(),
+        None,
+        key,
+        token,
+        false,
+    )
+    .await?
+    else {
+        bail!("Failed to log in to {}", id);
+    };
+    log::info!(
+        "{} is listening on {}:{}, forwarding to {}:{}",
+        id,
+        bind,
+        remote_port,
+        local_host,
+        local_port
+    );
+    let rule = TunnelStats::new(format!(
+        "{}:{}:{} -> {}:{}",
+        id, bind, remote_port, local_host, local_port
+    ));
+    let mut buffer = Vec::new();
+    // Any data keeps the control connection alive, the peer closes idle port forward connections.
+    let mut keep_alive = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        tokio::select! {
+            res = control.next() => {
+                let Some(Ok(bytes)) = res else {
+                    bail!("Reset by the peer");
+                };
+                buffer.extend(bytes);
+                while let Some(pos) = buffer.iter().position(|&c| c == b'\n') {
+                    let line = String::from_utf8_lossy(&buffer[..pos]).to_string();
+                    buffer.drain(..=pos);
+                    let Some((accept_token, addr)) = reverse_forward::parse_accept_line(&line) else {
+                        continue;
+                    };
+                    log::info!("new reverse connection from {}", addr);
+                    let stats = rule.new_connection(addr.to_owned());
+                    let forward = match timeout(
+                        READ_TIMEOUT,
+                        TcpStream::connect(format!("{}:{}", local_host, local_port)),
+                    )
+                    .await
+                    {
+                        Ok(Ok(forward)) => forward,
+                        res => {
+                            log::error!("Failed to connect {}:{}: {:?}", local_host, local_port, res);
+                            stats.add_error();
+                            continue;
+                        }
+                    };
+                    lc.write().unwrap().port_forward = (reverse_forward::accept_host(accept_token), 0);
+                    let forward = Framed::new(forward, BytesCodec::new());
+                    match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), None, key, token, false).await {
+                        Ok(Some(stream)) => {
+                            let interface = interface.clone();
+                            let addr = addr.to_owned();
+                            tokio::spawn(async move {
+                                if let Err(err) = run_forward(forward, Tunnel::Stream(stream), None, &stats).await {
+                                    interface.msgbox("error", "Error", &err.to_string(), "");
+                                }
+                                log::info!("reverse connection from {} closed, {}", addr, stats.snapshot());
+                            });
+                        }
+                        Err(err) => {
+                            log::error!("Failed to forward reverse connection from {}: {}", addr, err);
+                            stats.add_error();
+                        }
+                        _ => {
+                            stats.add_error();
+                        }
+                    }
+                }
+            }
+            _ = keep_alive.tick() => {
+                allow_err!(control.send_bytes(b"\n".to_vec().into()).await);
+            }
+            d = ui_receiver.recv() => {
+                if let Some(Data::Close) | None = d {
+                    break;
+                }
+            }
+        }
+    }
+    log::info!("reverse port forward closed, {}", rule.snapshot());
+    Ok(())
+}
+
+async fn forward_socks5(
+    id: String,
+    password: String,
+    // Keeps `login_rx` open, it would return `None` at once otherwise.
+    _login_tx: mpsc::UnboundedSender<Data>,
+    login_rx: Arc<TokioMutex<mpsc::UnboundedReceiver<Data>>>,
+    interface: impl Interface,
+    mut forward: TcpStream,
+    addr: SocketAddr,
+    key: String,
+    token: String,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    guard: ConnectionGuard,
+    opts: ForwardOptions,
+    stats: Arc<TunnelStats>,
+) {
+    let (host, port) = match timeout(READ_TIMEOUT, socks5::accept(&mut forward)).await {
+        Ok(Ok(dest)) => dest,
+        Ok(Err(err)) => {
+            log::error!("SOCKS5 request from {:?} failed: {}", addr, err);
+            stats.add_error();
+            return;
+        }
+        Err(_) => {
+            log::error!("SOCKS5 request from {:?} timed out", addr);
+            stats.add_error();
+            return;
+        }
+    };
+    log::info!("SOCKS5 connect to {}:{} from {:?}", host, port, addr);
+    let res = {
+        // The destination is in the login config shared by the connections.
+        let mut ui_receiver = login_rx.lock().await;
+        open_tunnel(
+            &id,
+            &password,
+            &mut ui_receiver,
+            interface.clone(),
+            None,
+            &key,
+            &token,
+            &lc,
+            &host,
+            port as _,
+            false,
+            &opts,
+        )
+        .await
+    };
+    let rep = match res {
+        Ok(Some(stream)) => {
+            if let Err(err) = socks5::reply(&mut forward, socks5::REP_SUCCEEDED).await {
+                log::error!("SOCKS5 reply to {:?} failed: {}", addr, err);
+                stats.add_error();
+                return;
+            }
+            tokio::spawn(async move {
+                let _guard = guard;
+                let forward = Framed::new(forward, BytesCodec::new());
+                if let Err(err) = run_forward(forward, stream, opts.idle_timeout, &stats).await {
+                    interface.msgbox("error", "Error", &err.to_string(), "");
+                }
+                log::info!("connection from {:?} closed, {}", addr, stats.snapshot());
+            });
+            return;
+        }
+        // The peer failed to connect to the destination.
+        Ok(None) => socks5::REP_HOST_UNREACHABLE,
+        Err(err) => {
+            log::error!("Failed to connect for {:?}: {}", addr, err);
+            socks5::REP_GENERAL_FAILURE
+        }
+    };
+    stats.add_error();
+    socks5::reply(&mut forward, rep).await.ok();
+}
+
+/// Open the tunnel of a forwarded connection to `host:port`, a stream of the multiplexed session
+/// of the rule if any, or a port forward connection of its own.
+async fn open_tunnel(
+    id: &str,
+    password: &str,
+    ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    interface: impl Interface,
+    forward: Option<&mut Framed<TcpStream, BytesCodec>>,
+    key: &str,
+    token: &str,
+    lc: &Arc<RwLock<LoginConfigHandler>>,
+    host: &str,
+    port: i32,
+    is_rdp: bool,
+    opts: &ForwardOptions,
+) -> ResultType<Option<Tunnel>> {
+    if let Some(session) = opts.session.as_ref() {
+        let stream = session.open(host, port).await?;
+        return Ok(Some(Tunnel::Mux(Framed::new(stream, BytesCodec::new()))));
+    }
+    lc.write().unwrap().port_forward = (host.to_owned(), port);
+    let stream = connect_and_login(
+        id,
+        password,
+        ui_receiver,
+        interface,
+        forward,
+        key,
+        token,
+        is_rdp,
+    )
+    .await?;
+    Ok(stream.map(Tunnel::Stream))
+}
+
 async fn connect_and_login(
     id: &str,
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -178,7 +550,7 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = async { forward.as_mut()?.next().await }, if forward.is_some() => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -194,26 +566,43 @@ async fn connect_and_login(
     Ok(Some(stream))
 }
 
-async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) -> ResultType<()> {
+async fn run_forward(
+    forward: Framed<TcpStream, BytesCodec>,
+    stream: Tunnel,
+    idle_timeout: Option<Duration>,
+    stats: &TunnelStats,
+) -> ResultType<()> {
     log::info!("new port forwarding connection started");
     let mut forward = forward;
     let mut stream = stream;
+    let mut last_active = Instant::now();
+    let mut timer = tokio::time::interval(Duration::from_secs(1));
     loop {
         tokio::select! {
             res = forward.next() => {
                 if let Some(Ok(bytes)) = res {
-                    allow_err!(stream.send_bytes(bytes.into()).await);
+                    last_active = Instant::now();
+                    stats.add_out(bytes.len());
+                    allow_err!(stats.check(stream.send_bytes(bytes.into()).await));
                 } else {
                     break;
                 }
             },
             res = stream.next() => {
                 if let Some(Ok(bytes)) = res {
-                    allow_err!(forward.send(bytes).await);
+                    last_active = Instant::now();
+                    stats.add_in(bytes.len());
+                    allow_err!(stats.check(forward.send(bytes).await));
                 } else {
                     break;
                 }
             },
+            _ = timer.tick(), if idle_timeout.is_some() => {
+                if idle_timeout.map_or(false, |t| last_active.elapsed() >= t) {
+                    log::info!("close idle port forwarding connection");
+                    break;
+                }
+            }
         }
     }
     Ok(())
@@ -0,0 +1,216 @@
+//! The controlling side of multiplexed port forwarding, see [`crate::server::mux_forward`].
+
+use super::connect_and_login;
+use crate::{
+    client::{Data, Interface, LoginConfigHandler},
+    server::{
+        mux_forward::{self, Frame, FrameDecoder, CLOSE, DATA, OPEN, OPENED},
+        reverse_forward::loopback_pair,
+    },
+};
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    futures::StreamExt,
+    log,
+    tokio::{
+        self,
+        net::TcpStream,
+        sync::{mpsc, oneshot},
+    },
+    ResultType, Stream,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+type Request = (Vec<u8>, oneshot::Sender<ResultType<TcpStream>>);
+
+/// One port forward connection to a peer, shared by all forwards to it. It is made when the
+/// first stream is opened, and made again when a stream is opened after it is lost.
+#[derive(Debug, Clone)]
+pub struct Session {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl Session {
+    pub fn start(
+        id: String,
+        password: String,
+        interface: impl Interface,
+        ui_receiver: mpsc::UnboundedReceiver<Data>,
+        key: String,
+        token: String,
+        lc: Arc<RwLock<LoginConfigHandler>>,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(err) =
+                run(&id, &password, interface, ui_receiver, &key, &token, lc, rx).await
+            {
+                log::error!("multiplexed port forward to {} error: {}", id, err);
+            }
+            log::info!("multiplexed port forward to {} closed", id);
+        });
+        Self { tx }
+    }
+
+    /// Open a stream to `host:port` reachable from the peer, `host` with the `udp:` prefix
+    /// carries the frames of UDP forwarding. The returned socket is relayed to the stream.
+    pub async fn open(&self, host: &str, port: i32) -> ResultType<TcpStream> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .tx
+            .send((mux_forward::encode_target(host, port), tx))
+            .is_err()
+        {
+            bail!("The multiplexed port forward is closed");
+        }
+        match rx.await {
+            Ok(res) => res,
+            Err(_) => bail!("The multiplexed port forward is closed"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Streams {
+    next_id: u32,
+    // Waiting for the peer to connect to the target.
+    pending: HashMap<u32, oneshot::Sender<ResultType<TcpStream>>>,
+    open: HashMap<u32, mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl Streams {
+    fn clear(&mut self) {
+        // Dropping the senders closes the relayed sockets.
+        self.pending.clear();
+        self.open.clear();
+    }
+}
+
+async fn run(
+    id: &str,
+    password: &str,
+    interface: impl Interface,
+    mut ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) -> ResultType<()> {
+    let mut stream: Option<Stream> = None;
+    let mut streams = Streams::default();
+    let mut decoder = FrameDecoder::default();
+    // The frames of the relayed sockets.
+    let (tx, mut rx) = mpsc::unbounded_channel::<Frame>();
+    loop {
+        tokio::select! {
+            Some((target, reply)) = requests.recv() => {
+                if stream.is_none() {
+                    lc.write().unwrap().port_forward = (mux_forward::HOST.to_owned(), 0);
+                    match connect_and_login(id, password, &mut ui_receiver, interface.clone(), None, key, token, false).await {
+                        Ok(Some(s)) => {
+                            log::info!("multiplexed port forward connection to {} is made", id);
+                            stream = Some(s);
+                            decoder = FrameDecoder::default();
+                        }
+                        Ok(None) => {
+                            reply.send(Err(anyhow!("Failed to log in to {}", id))).ok();
+                            continue;
+                        }
+                        Err(err) => {
+                            reply.send(Err(err)).ok();
+                            continue;
+                        }
+                    }
+                }
+                streams.next_id = streams.next_id.wrapping_add(1);
+                let sid = streams.next_id;
+                let frame = mux_forward::encode_frame(sid, OPEN, &target);
+                if let Some(s) = stream.as_mut() {
+                    if let Err(err) = s.send_bytes(frame.into()).await {
+                        log::error!("multiplexed port forward connection to {} error: {}", id, err);
+                        stream = None;
+                        streams.clear();
+                        reply.send(Err(err)).ok();
+                        continue;
+                    }
+                }
+                streams.pending.insert(sid, reply);
+            }
+            res = async { stream.as_mut()?.next().await }, if stream.is_some() => {
+                let Some(Ok(bytes)) = res else {
+                    log::info!("multiplexed port forward connection to {} is closed", id);
+                    stream = None;
+                    streams.clear();
+                    continue;
+                };
+                decoder.feed(&bytes);
+                while let Some((sid, kind, payload)) = decoder.next_frame() {
+                    match kind {
+                        OPENED => {
+                            let Some(reply) = streams.pending.remove(&sid) else {
+                                continue;
+                            };
+                            let (control, inner) = match loopback_pair().await {
+                                Ok(pair) => pair,
+                                Err(err) => {
+                                    // Closed as if by the socket, so that the peer closes it too.
+                                    streams.open.insert(sid, mpsc::unbounded_channel().0);
+                                    tx.send((sid, CLOSE, vec![])).ok();
+                                    reply.send(Err(err)).ok();
+                                    continue;
+                                }
+                            };
+                            let (data_tx, data_rx) = mpsc::unbounded_channel();
+                            streams.open.insert(sid, data_tx);
+                            tokio::spawn(mux_forward::pump(sid, inner, data_rx, tx.clone()));
+                            // If the opener is gone, the socket is closed and so is the stream.
+                            reply.send(Ok(control)).ok();
+                        }
+                        DATA => {
+                            if let Some(data_tx) = streams.open.get(&sid) {
+                                data_tx.send(payload).ok();
+                            }
+                        }
+                        CLOSE => {
+                            streams.open.remove(&sid);
+                            if let Some(reply) = streams.pending.remove(&sid) {
+                                let err = String::from_utf8_lossy(&payload).to_string();
+                                reply.send(Err(anyhow!(err))).ok();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Some((sid, kind, payload)) = rx.recv() => {
+                // Nothing is sent for the streams closed by the peer, or of a lost connection.
+                let is_open = if kind == CLOSE {
+                    streams.open.remove(&sid).is_some()
+                } else {
+                    streams.open.contains_key(&sid)
+                };
+                if !is_open {
+                    continue;
+                }
+                if let Some(s) = stream.as_mut() {
+                    let frame = mux_forward::encode_frame(sid, kind, &payload);
+                    if let Err(err) = s.send_bytes(frame.into()).await {
+                        log::error!("multiplexed port forward connection to {} error: {}", id, err);
+                        stream = None;
+                        streams.clear();
+                    }
+                }
+            }
+            d = ui_receiver.recv() => {
+                if let Some(Data::Close) | None = d {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
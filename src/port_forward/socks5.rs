@@ -0,0 +1,150 @@
+//! The server side of SOCKS5 (RFC 1928), only CONNECT without authentication is supported.
+
+use hbb_common::{
+    bail,
+    tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    ResultType,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+pub const REP_SUCCEEDED: u8 = 0;
+pub const REP_GENERAL_FAILURE: u8 = 1;
+pub const REP_HOST_UNREACHABLE: u8 = 4;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Negotiate the method and read the request, returns the destination host and port.
+///
+/// The hosts of the reverse and UDP forwards are refused, they would open a listener or a relay
+/// on the peer for any client of the SOCKS port.
+/// The request is replied with an error if it is not supported,
+/// otherwise the caller must reply with [`reply`] after connecting to the destination.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> ResultType<(String, u16)> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != VERSION {
+        bail!("Unsupported SOCKS version {}", buf[0]);
+    }
+    let mut methods = vec![0u8; buf[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        bail!("No acceptable SOCKS authentication method");
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        bail!("Unsupported SOCKS version {}", head[0]);
+    }
+    let host = match head[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        atyp => {
+            reply(stream, REP_ADDRESS_TYPE_NOT_SUPPORTED).await.ok();
+            bail!("Unsupported SOCKS address type {}", atyp);
+        }
+    };
+    let port = stream.read_u16().await?;
+    if head[1] != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await.ok();
+        bail!("Unsupported SOCKS command {}", head[1]);
+    }
+    if crate::server::reverse_forward::parse_host(&host).is_some()
+        || crate::server::udp_forward::parse_host(&host).is_some()
+        || crate::server::mux_forward::is_host(&host)
+    {
+        reply(stream, REP_GENERAL_FAILURE).await.ok();
+        bail!("Refused SOCKS destination {}", host);
+    }
+    Ok((host, port))
+}
+
+/// Reply to the request, the bound address is not meaningful for a forwarded connection and is always 0.0.0.0:0.
+pub async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, rep: u8) -> ResultType<()> {
+    stream
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio;
+
+    #[tokio::test]
+    async fn test_accept() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let task = tokio::spawn(async move { accept(&mut server).await.unwrap() });
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+        client
+            .write_all(&[
+                5, 1, 0, 3, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 80,
+            ])
+            .await
+            .unwrap();
+        assert_eq!(task.await.unwrap(), ("example".to_owned(), 80));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let task = tokio::spawn(async move { accept(&mut server).await.is_err() });
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        // BIND is not supported
+        client
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut rep = [0u8; 10];
+        client.read_exact(&mut rep).await.unwrap();
+        assert_eq!(rep[1], REP_COMMAND_NOT_SUPPORTED);
+        assert!(task.await.unwrap());
+
+        for host in [
+            "reverse-listen:0.0.0.0",
+            "reverse-accept:token",
+            "udp:127.0.0.1",
+            "mux:",
+        ] {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let task = tokio::spawn(async move { accept(&mut server).await.is_err() });
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            let mut request = vec![5, 1, 0, 3, host.len() as u8];
+            request.extend(host.as_bytes());
+            request.extend([0, 80]);
+            client.write_all(&request).await.unwrap();
+            client.read_exact(&mut rep).await.unwrap();
+            assert_eq!(rep[1], REP_GENERAL_FAILURE);
+            assert!(task.await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! The controlling side of UDP forwarding, see [`crate::server::udp_forward`].
+
+use super::{open_tunnel, ForwardOptions, Tunnel};
+use crate::{
+    client::{Data, Interface, LoginConfigHandler},
+    server::{
+        port_forward_stats::TunnelStats,
+        udp_forward::{self, FrameDecoder, FLOW_IDLE_TIMEOUT, MAX_DATAGRAM_SIZE},
+    },
+};
+use hbb_common::{
+    futures::StreamExt,
+    log,
+    tokio::{self, net::UdpSocket, sync::mpsc},
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+struct Flows {
+    next_id: u32,
+    by_addr: HashMap<SocketAddr, u32>,
+    // id -> (source address, last active)
+    by_id: HashMap<u32, (SocketAddr, Instant)>,
+}
+
+impl Flows {
+    fn get_or_insert(&mut self, addr: SocketAddr) -> u32 {
+        let id = match self.by_addr.get(&addr) {
+            Some(id) => *id,
+            None => {
+                self.next_id = self.next_id.wrapping_add(1);
+                self.by_addr.insert(addr, self.next_id);
+                self.next_id
+            }
+        };
+        self.by_id.insert(id, (addr, Instant::now()));
+        id
+    }
+
+    fn touch(&mut self, id: u32) -> Option<SocketAddr> {
+        let (addr, last_active) = self.by_id.get_mut(&id)?;
+        *last_active = Instant::now();
+        Some(*addr)
+    }
+
+    fn is_full(&self, addr: &SocketAddr, max: usize) -> bool {
+        max > 0 && !self.by_addr.contains_key(addr) && self.by_addr.len() >= max
+    }
+
+    fn expire(&mut self, timeout: Duration) {
+        self.by_id
+            .retain(|_, (_, last_active)| last_active.elapsed() < timeout);
+        let by_id = &self.by_id;
+        self.by_addr.retain(|_, id| by_id.contains_key(id));
+    }
+
+    fn clear(&mut self) {
+        self.by_addr.clear();
+        self.by_id.clear();
+    }
+}
+
+/// Forward datagrams to `port` to `remote_host:remote_port` reachable from the peer,
+/// `remote_host` is with the `udp:` prefix.
+///
+/// All flows share one connection, or one stream of the multiplexed session, which is made
+/// when the first datagram arrives.
+pub(super) async fn listen(
+    id: String,
+    password: String,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    remote_host: String,
+    remote_port: i32,
+    opts: ForwardOptions,
+) -> ResultType<()> {
+    let socket = UdpSocket::bind(opts.bind_address(port)).await?;
+    let target = format!(
+        "{}:{}",
+        udp_forward::parse_host(&remote_host).unwrap_or_default(),
+        remote_port
+    );
+    log::info!(
+        "listening on udp port {:?}, forwarding to {}",
+        socket.local_addr()?,
+        target
+    );
+    // The shared connection is the one connection of the rule.
+    let rule = TunnelStats::new(format!("udp {} -> {} {}", socket.local_addr()?, id, target));
+    let mut ui_receiver = ui_receiver;
+    let mut stream: Option<(Tunnel, Arc<TunnelStats>)> = None;
+    let mut flows = Flows::default();
+    let mut decoder = FrameDecoder::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    // The peer removes the flows idle for longer, their replies would go nowhere.
+    let idle_timeout = opts
+        .idle_timeout
+        .map_or(FLOW_IDLE_TIMEOUT, |x| x.min(FLOW_IDLE_TIMEOUT));
+    if opts.idle_timeout.map_or(false, |x| x > FLOW_IDLE_TIMEOUT) {
+        log::warn!(
+            "The idle timeout of UDP flows is at most {:?}",
+            FLOW_IDLE_TIMEOUT
+        );
+    }
+    let mut timer = tokio::time::interval(Duration::from_secs(10).min(idle_timeout));
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (n, addr) = match res {
+                    Ok(v) => v,
+                    Err(err) => {
+                        // e.g. ICMP port unreachable reported on Windows
+                        log::debug!("udp recv error: {}", err);
+                        continue;
+                    }
+                };
+                if flows.is_full(&addr, opts.max_connections) {
+                    log::debug!("drop datagram from {}, {} flows are open", addr, opts.max_connections);
+                    continue;
+                }
+                if stream.is_none() {
+                    let stats = rule.new_connection(target.clone());
+                    match open_tunnel(&id, &password, &mut ui_receiver, interface.clone(), None, key, token, &lc, &remote_host, remote_port, false, &opts).await {
+                        Ok(Some(s)) => {
+                            stream = Some((s, stats));
+                            decoder = FrameDecoder::default();
+                        }
+                        Ok(None) => {
+                            stats.add_error();
+                            continue;
+                        }
+                        Err(err) => {
+                            log::error!("Failed to connect for udp forwarding: {}", err);
+                            stats.add_error();
+                            continue;
+                        }
+                    }
+                }
+                let flow = flows.get_or_insert(addr);
+                if let Some((s, stats)) = stream.as_mut() {
+                    stats.add_out(n);
+                    if let Err(err) = s.send_bytes(udp_forward::encode_frame(flow, &buf[..n]).into()).await {
+                        log::error!("udp forwarding connection error: {}", err);
+                        stats.add_error();
+                        stream = None;
+                        flows.clear();
+                    }
+                }
+            }
+            res = async { stream.as_mut()?.0.next().await }, if stream.is_some() => {
+                let Some(Ok(bytes)) = res else {
+                    if let Some((_, stats)) = stream.take() {
+                        log::info!("udp forwarding connection closed, {}", stats.snapshot());
+                    }
+                    flows.clear();
+                    continue;
+                };
+                decoder.feed(&bytes);
+                while let Some((flow, data)) = decoder.next_frame() {
+                    if let Some(addr) = flows.touch(flow) {
+                        if let Some((_, stats)) = stream.as_ref() {
+                            stats.add_in(data.len());
+                        }
+                        socket.send_to(&data, addr).await.ok();
+                    }
+                }
+            }
+            _ = timer.tick() => {
+                flows.expire(idle_timeout);
+            }
+            d = ui_receiver.recv() => {
+                if let Some(Data::Close) | None = d {
+                    break;
+                }
+            }
+        }
+    }
+    log::info!("udp port forward closed, {}", rule.snapshot());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flows() {
+        let mut flows = Flows::default();
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let id_a = flows.get_or_insert(a);
+        let id_b = flows.get_or_insert(b);
+        assert_ne!(id_a, id_b);
+        assert_eq!(flows.get_or_insert(a), id_a);
+        assert_eq!(flows.touch(id_b), Some(b));
+        flows.by_id.get_mut(&id_a).unwrap().1 -= FLOW_IDLE_TIMEOUT;
+        flows.expire(FLOW_IDLE_TIMEOUT);
+        assert_eq!(flows.touch(id_a), None);
+        let c: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        assert!(flows.is_full(&c, 1));
+        assert!(!flows.is_full(&b, 1));
+        assert!(!flows.is_full(&c, 0));
+        assert_ne!(flows.get_or_insert(a), id_a);
+    }
+}
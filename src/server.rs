@@ -64,11 +64,23 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+pub mod access_schedule;
+pub mod approval_hook;
 mod connection;
 pub mod display_service;
+pub mod file_policy;
+pub mod login_lockout;
+pub mod mux_forward;
+pub mod peer_id_filter;
+pub mod permission_profile;
+pub mod port_forward_policy;
+pub mod port_forward_stats;
 #[cfg(windows)]
 pub mod portable_service;
+pub mod reverse_forward;
+pub mod session_limit;
 mod service;
+pub mod udp_forward;
 mod video_qos;
 pub mod video_service;
 
@@ -0,0 +1,266 @@
+//! Time windows in which the incoming sessions are accepted, in local time.
+//!
+//! The schedule is in the `access-schedule` option, in JSON:
+//!
+//! ```json
+//! {
+//!     "windows": ["Mon-Fri 08:00-18:00", "Sat 09:00-12:00"],
+//!     "passwords": { "permanent": ["*"] },
+//!     "peers": { "123456789": ["Mon-Sun 07:00-22:00"] },
+//!     "warning": 300
+//! }
+//! ```
+//!
+//! A window is `<days> <HH:MM>-<HH:MM>`, the days are `*`, a day, a range of days, or a list
+//! of them separated by commas, e.g. `Mon,Wed,Fri-Sun`. A window ending before it starts
+//! ends on the next day, e.g. `Fri 22:00-06:00`. A window of `*` alone is always open.
+//! The windows of the password, if any, are used instead of the default ones. The windows of the
+//! peer ID, if any, narrow them: a session is accepted when both are open. The peer ID is
+//! claimed by the peer and not verified, so it never widens the windows of the password.
+//! A session still open when its windows close is warned `warning` seconds before (300 by
+//! default), and then closed.
+//! An empty option, or no windows, accepts the sessions at any time. A window failing to parse
+//! denies them, like an invalid option.
+
+use super::permission_profile::PasswordKind;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike};
+use hbb_common::{bail, config::Config, log, ResultType};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+pub const OPTION_ACCESS_SCHEDULE: &str = "access-schedule";
+
+const DAY: u32 = 24 * 3600;
+const WEEK: u32 = 7 * DAY;
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const DEFAULT_WARNING: u32 = 300;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Settings {
+    windows: Vec<String>,
+    passwords: HashMap<String, Vec<String>>,
+    peers: HashMap<String, Vec<String>>,
+    warning: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Always,
+    /// Open, closing in the given seconds, with the seconds of the warning.
+    Until(u32, u32),
+    Denied,
+}
+
+// [start, end) in seconds of the week from Monday 00:00, end <= WEEK.
+type Interval = (u32, u32);
+
+fn parse_day(s: &str) -> ResultType<u32> {
+    let s = s.trim().to_lowercase();
+    match DAYS.iter().position(|x| s.starts_with(x)) {
+        Some(i) => Ok(i as _),
+        None => bail!("invalid day {}", s),
+    }
+}
+
+fn parse_time(s: &str) -> ResultType<u32> {
+    let Some((h, m)) = s.trim().split_once(':') else {
+        bail!("invalid time {}", s);
+    };
+    let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        bail!("invalid time {}", s);
+    }
+    Ok(h * 3600 + m * 60)
+}
+
+fn parse_window(s: &str) -> ResultType<Vec<Interval>> {
+    let s = s.trim();
+    if s == "*" {
+        return Ok(vec![(0, WEEK)]);
+    }
+    let Some((days, times)) = s.rsplit_once(' ') else {
+        bail!("invalid window {}", s);
+    };
+    let Some((start, end)) = times.split_once('-') else {
+        bail!("invalid window {}", s);
+    };
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    let mut enabled = [false; 7];
+    for part in days.split(',') {
+        let part = part.trim();
+        if part == "*" {
+            enabled = [true; 7];
+        } else if let Some((a, b)) = part.split_once('-') {
+            let (a, b) = (parse_day(a)?, parse_day(b)?);
+            let mut d = a;
+            loop {
+                enabled[d as usize] = true;
+                if d == b {
+                    break;
+                }
+                d = (d + 1) % 7;
+            }
+        } else {
+            enabled[parse_day(part)? as usize] = true;
+        }
+    }
+    let mut res = vec![];
+    for d in (0..7).filter(|d| enabled[*d as usize]) {
+        let start = d * DAY + start;
+        let end = if end > start % DAY || end == DAY {
+            d * DAY + end
+        } else {
+            (d + 1) * DAY + end
+        };
+        if end > WEEK {
+            res.push((start, WEEK));
+            res.push((0, end - WEEK));
+        } else {
+            res.push((start, end));
+        }
+    }
+    Ok(res)
+}
+
+fn parse_windows(windows: &[String]) -> ResultType<Vec<Interval>> {
+    let mut res = vec![];
+    for w in windows {
+        res.extend(parse_window(w)?);
+    }
+    Ok(res)
+}
+
+// `t` in seconds of the week.
+fn check_intervals(intervals: &[Interval], t: u32, warning: u32) -> Access {
+    if intervals.is_empty() {
+        return Access::Always;
+    }
+    let containing = |t: u32| {
+        intervals
+            .iter()
+            .filter(|(a, b)| *a <= t % WEEK && t % WEEK < *b)
+            .map(|(_, b)| *b + t / WEEK * WEEK)
+            .max()
+    };
+    let Some(mut end) = containing(t) else {
+        return Access::Denied;
+    };
+    // Follow the adjacent windows.
+    while let Some(next) = containing(end) {
+        if next - t >= WEEK {
+            return Access::Always;
+        }
+        end = next;
+    }
+    Access::Until(end - t, warning)
+}
+
+// Open when both are.
+fn intersect(a: Access, b: Access) -> Access {
+    match (a, b) {
+        (Access::Denied, _) | (_, Access::Denied) => Access::Denied,
+        (Access::Always, x) | (x, Access::Always) => x,
+        (Access::Until(a, warning), Access::Until(b, _)) => Access::Until(a.min(b), warning),
+    }
+}
+
+impl Settings {
+    fn check(&self, peer_id: &str, password: Option<PasswordKind>, now: NaiveDateTime) -> Access {
+        let windows = password
+            .and_then(|p| self.passwords.get(p.name()))
+            .unwrap_or(&self.windows);
+        let t = now.weekday().num_days_from_monday() * DAY + now.num_seconds_from_midnight();
+        let warning = self.warning.unwrap_or(DEFAULT_WARNING);
+        let check = |windows: &[String]| match parse_windows(windows) {
+            Ok(intervals) => check_intervals(&intervals, t, warning),
+            Err(err) => {
+                // Misconfigured, do not fall back to any time.
+                log::error!("Invalid {}: {}", OPTION_ACCESS_SCHEDULE, err);
+                Access::Denied
+            }
+        };
+        match self.peers.get(peer_id) {
+            Some(peer_windows) => intersect(check(windows), check(peer_windows)),
+            None => check(windows),
+        }
+    }
+}
+
+/// Whether a session of `peer_id` logged in with `password` is accepted now.
+pub fn check(peer_id: &str, password: Option<PasswordKind>) -> Access {
+    let option = Config::get_option(OPTION_ACCESS_SCHEDULE);
+    if option.trim().is_empty() {
+        return Access::Always;
+    }
+    match serde_json::from_str::<Settings>(&option) {
+        Ok(settings) => settings.check(peer_id, password, Local::now().naive_local()),
+        Err(err) => {
+            log::error!("Invalid {}: {}", OPTION_ACCESS_SCHEDULE, err);
+            Access::Denied
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_check() {
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "windows": ["Mon-Fri 08:00-18:00", "Fri 18:00-02:00"],
+                "passwords": { "permanent": ["*"] },
+                "peers": { "1": ["Sun-Mon 00:00-24:00"] },
+                "warning": 60
+            }"#,
+        )
+        .unwrap();
+        // 2024-01-01 is a Monday
+        let at = |d, h, m| {
+            NaiveDate::from_ymd_opt(2024, 1, d)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+        assert_eq!(settings.check("2", None, at(1, 7, 59)), Access::Denied);
+        assert_eq!(
+            settings.check("2", None, at(1, 17, 0)),
+            Access::Until(3600, 60)
+        );
+        assert_eq!(settings.check("2", None, at(6, 10, 0)), Access::Denied);
+        // Friday 17:00, until Saturday 02:00
+        assert_eq!(
+            settings.check("2", None, at(5, 17, 0)),
+            Access::Until(9 * 3600, 60)
+        );
+        assert_eq!(
+            settings.check("2", None, at(6, 1, 0)),
+            Access::Until(3600, 60)
+        );
+        assert_eq!(
+            settings.check("2", Some(PasswordKind::Permanent), at(6, 10, 0)),
+            Access::Always
+        );
+        // Sunday 23:00, until Tuesday 00:00 across the week
+        assert_eq!(
+            settings.check("1", Some(PasswordKind::Permanent), at(7, 23, 0)),
+            Access::Until(25 * 3600, 60)
+        );
+        assert_eq!(settings.check("1", None, at(2, 10, 0)), Access::Denied);
+        // The windows of the peer ID don't widen the default ones, Monday until 18:00.
+        assert_eq!(
+            settings.check("1", None, at(1, 10, 0)),
+            Access::Until(8 * 3600, 60)
+        );
+        assert_eq!(settings.check("1", None, at(1, 20, 0)), Access::Denied);
+        // A window failing to parse denies, rather than accepting at any time.
+        let settings: Settings =
+            serde_json::from_str(r#"{ "windows": ["Mon-Fri 8-18"] }"#).unwrap();
+        assert_eq!(settings.check("2", None, at(1, 10, 0)), Access::Denied);
+        assert!(parse_window("Mon 8:00").is_err());
+        assert!(parse_window("Xyz 08:00-09:00").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! External approval of the incoming connections, before the password or the connection
+//! manager is asked.
+//!
+//! The `approval-hook` option is a local command, or an `http://` or `https://` URL.
+//! The command is run by the shell, with the request in the environment variables
+//! `RUSTDESK_PEER_ID`, `RUSTDESK_PEER_NAME`, `RUSTDESK_PEER_IP` and `RUSTDESK_CONN_TYPE`,
+//! and in JSON on its stdin. It answers with `allow`, `deny` or `ask` on the first line of
+//! its stdout. The URL is posted the request in JSON, and answers with the same words in
+//! the body, or in the `decision` field of a JSON body.
+//!
+//! `allow` stands for the click in the connection manager: the peer can log in with the
+//! password even if only clicks are accepted, but the password, and 2FA if it is enabled,
+//! are still required. `deny` refuses the connection, and `ask` goes on as if there is no
+//! hook. A failure, a timeout (`approval-hook-timeout`, 10 seconds by default), or another
+//! answer is `ask`, or `deny` if `approval-hook-fallback` is `deny`.
+//!
+//! **The peer ID and name are what the peer claims, nothing verifies them.** Any peer can
+//! send the ID of a technician, so the hook must not treat them as an identity, this is why
+//! `allow` never skips the password. The IP is the one of the relay server if the connection
+//! is relayed.
+
+use hbb_common::{bail, config::Config, log, tokio, ResultType};
+use serde_derive::Serialize;
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+pub const OPTION_APPROVAL_HOOK: &str = "approval-hook";
+pub const OPTION_APPROVAL_HOOK_TIMEOUT: &str = "approval-hook-timeout";
+pub const OPTION_APPROVAL_HOOK_FALLBACK: &str = "approval-hook-fallback";
+
+const DEFAULT_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Allow,
+    Deny,
+    Ask,
+}
+
+impl Answer {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "allow" => Some(Self::Allow),
+            "deny" => Some(Self::Deny),
+            "ask" => Some(Self::Ask),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Request {
+    pub peer_id: String,
+    pub name: String,
+    pub ip: String,
+    /// remote, file-transfer, port-forward, view-camera or terminal
+    pub conn_type: String,
+}
+
+/// `None` if there is no hook.
+pub async fn ask(request: Request) -> Option<Answer> {
+    let hook = Config::get_option(OPTION_APPROVAL_HOOK);
+    let hook = hook.trim();
+    if hook.is_empty() {
+        return None;
+    }
+    let timeout = Duration::from_secs(
+        Config::get_option(OPTION_APPROVAL_HOOK_TIMEOUT)
+            .parse()
+            .unwrap_or(DEFAULT_TIMEOUT),
+    );
+    let res = if hook.starts_with("http://") || hook.starts_with("https://") {
+        post(hook, &request, timeout).await
+    } else {
+        let hook = hook.to_owned();
+        let request = request.clone();
+        match tokio::task::spawn_blocking(move || run(&hook, &request, timeout)).await {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+    };
+    let answer = match res {
+        Ok(answer) => answer,
+        Err(err) => {
+            log::error!("Approval hook failed: {}", err);
+            if Config::get_option(OPTION_APPROVAL_HOOK_FALLBACK) == "deny" {
+                Answer::Deny
+            } else {
+                Answer::Ask
+            }
+        }
+    };
+    log::info!(
+        "Approval hook answers {:?} to {} ({}) from {}",
+        answer,
+        request.peer_id,
+        request.conn_type,
+        request.ip
+    );
+    Some(answer)
+}
+
+fn parse_output(output: &str) -> ResultType<Answer> {
+    let first = output.lines().next().unwrap_or_default();
+    if let Some(answer) = Answer::parse(first) {
+        return Ok(answer);
+    }
+    if let Ok(v) = serde_json::from_str::<serde_json::Value>(output) {
+        if let Some(answer) = v["decision"].as_str().and_then(Answer::parse) {
+            return Ok(answer);
+        }
+    }
+    bail!("unknown answer {:?}", first);
+}
+
+async fn post(url: &str, request: &Request, timeout: Duration) -> ResultType<Answer> {
+    let resp = crate::hbbs_http::create_http_client_async()
+        .post(url)
+        .json(request)
+        .timeout(timeout)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!("{}", resp.status());
+    }
+    parse_output(&resp.text().await?)
+}
+
+fn run(command: &str, request: &Request, timeout: Duration) -> ResultType<Answer> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    let mut child = cmd
+        .env("RUSTDESK_PEER_ID", &request.peer_id)
+        .env("RUSTDESK_PEER_NAME", &request.name)
+        .env("RUSTDESK_PEER_IP", &request.ip)
+        .env("RUSTDESK_CONN_TYPE", &request.conn_type)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may not read it.
+        stdin
+            .write_all(serde_json::to_string(request)?.as_bytes())
+            .ok();
+    }
+    let mut stdout = child.stdout.take();
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(stdout) = stdout.as_mut() {
+            stdout.read_to_string(&mut output).ok();
+        }
+        output
+    });
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            bail!("timeout");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let output = reader.join().unwrap_or_default();
+    if !status.success() {
+        bail!("exit with {}", status);
+    }
+    parse_output(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(parse_output("allow\n").unwrap(), Answer::Allow);
+        assert_eq!(parse_output(" Deny \nmore").unwrap(), Answer::Deny);
+        assert_eq!(parse_output(r#"{"decision": "ask"}"#).unwrap(), Answer::Ask);
+        assert!(parse_output("yes").is_err());
+        assert!(parse_output("").is_err());
+    }
+}
@@ -14,10 +14,11 @@ use crate::platform::WallPaperRemover;
 #[cfg(windows)]
 use crate::portable_service::client as portable_client;
 use crate::{
+    audit_log, capability,
     client::{
         new_voice_call_request, new_voice_call_response, start_audio_thread, MediaData, MediaSender,
     },
-    display_service, ipc, privacy_mode, video_service, VERSION,
+    display_service, file_delta, file_digest, file_sync, ipc, privacy_mode, video_service, VERSION,
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
@@ -66,7 +67,6 @@ use std::collections::HashSet;
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: [Arc::<Mutex<HashMap<String, (i32, i32, i32)>>>; 2] = Default::default();
     static ref SESSIONS: Arc::<Mutex<HashMap<SessionKey, Session>>> = Default::default();
     static ref ALIVE_CONNS: Arc::<Mutex<Vec<i32>>> = Default::default();
     pub static ref AUTHED_CONNS: Arc::<Mutex<Vec<AuthedConn>>> = Default::default();
@@ -214,7 +214,10 @@ pub struct Connection {
     view_camera: bool,
     terminal: bool,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    // The reverse, UDP or multiplexed forward, started once the login is accepted.
+    pending_port_forward: Option<PortForward>,
     port_forward_address: String,
+    port_forward_stats: Option<Arc<port_forward_stats::TunnelStats>>,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
     require_2fa: Option<totp_rs::TOTP>,
@@ -225,6 +228,8 @@ pub struct Connection {
     restart: bool,
     recording: bool,
     block_input: bool,
+    password_kind: Option<permission_profile::PasswordKind>,
+    permission_profile: Option<(String, permission_profile::Profile)>,
     last_test_delay: Option<Instant>,
     network_delay: u32,
     lock_after_session_end: bool,
@@ -270,11 +275,27 @@ pub struct Connection {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     start_cm_ipc_para: Option<StartCmIpcPara>,
     auto_disconnect_timer: Option<(Instant, u64)>,
+    // The end of the access schedule window, the seconds to warn before, and whether warned.
+    access_schedule_end: Option<(Instant, u32, bool)>,
+    // The answer of the approval hook, asked once per connection.
+    approval: Option<approval_hook::Answer>,
+    // The position in the queue of the sessions over the limits.
+    queue_position: Option<usize>,
     authed_conn_id: Option<self::raii::AuthedConnID>,
     file_remove_log_control: FileRemoveLogControl,
     last_supported_encoding: Option<SupportedEncoding>,
     services_subed: bool,
     delayed_read_dir: Option<(String, bool)>,
+    file_policy: Option<file_policy::Policy>,
+    // (job id, file num) -> the bytes received, checked against the maximum file size
+    file_policy_received: HashMap<(i32, i32), u64>,
+    // The capabilities of the file transfer of the peer, see `crate::capability`.
+    peer_capabilities: std::collections::HashSet<String>,
+    // Job ID -> the path and the files of the read jobs sending the digests, for the audit.
+    digest_read_jobs: HashMap<i32, (String, Vec<(String, i64)>)>,
+    sent_digests: file_digest::Sent,
+    // Job ID -> the path, the files and the digests received of the write jobs, for the audit.
+    digest_write_jobs: HashMap<i32, (String, Vec<(String, i64)>, Vec<u8>)>,
     #[cfg(target_os = "macos")]
     retina: Retina,
     follow_remote_cursor: bool,
@@ -282,9 +303,6 @@ pub struct Connection {
     multi_ui_session: bool,
     tx_from_authed: mpsc::UnboundedSender<ipc::Data>,
     printer_data: Vec<(Instant, String, Vec<u8>)>,
-    // For post requests that need to be sent sequentially.
-    // eg. post_conn_audit
-    tx_post_seq: mpsc::UnboundedSender<(String, Value)>,
     terminal_service_id: String,
     terminal_persistent: bool,
     // The user token must be set when terminal is enabled.
@@ -367,11 +385,6 @@ impl Connection {
         let linux_headless_handle =
             LinuxHeadlessHandle::new(_rx_cm_stream_ready, _tx_desktop_ready);
 
-        let (tx_post_seq, rx_post_seq) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            Self::post_seq_loop(rx_post_seq).await;
-        });
-
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let tx_cloned = tx.clone();
         let mut conn = Self {
@@ -392,7 +405,9 @@ impl Connection {
             view_camera: false,
             terminal: false,
             port_forward_socket: None,
+            pending_port_forward: None,
             port_forward_address: "".to_owned(),
+            port_forward_stats: None,
             tx_to_cm,
             authorized: false,
             keyboard: Connection::permission("enable-keyboard"),
@@ -403,6 +418,8 @@ impl Connection {
             restart: Connection::permission("enable-remote-restart"),
             recording: Connection::permission("enable-record-session"),
             block_input: Connection::permission("enable-block-input"),
+            password_kind: None,
+            permission_profile: None,
             last_test_delay: None,
             network_delay: 0,
             lock_after_session_end: false,
@@ -447,16 +464,24 @@ impl Connection {
                 tx_cm_stream_ready,
             }),
             auto_disconnect_timer: None,
+            access_schedule_end: None,
+            approval: None,
+            queue_position: None,
             authed_conn_id: None,
             file_remove_log_control: FileRemoveLogControl::new(id),
             last_supported_encoding: None,
             services_subed: false,
             delayed_read_dir: None,
+            file_policy: None,
+            file_policy_received: HashMap::new(),
+            peer_capabilities: Default::default(),
+            digest_read_jobs: HashMap::new(),
+            sent_digests: Default::default(),
+            digest_write_jobs: HashMap::new(),
             #[cfg(target_os = "macos")]
             retina: Retina::default(),
             tx_from_authed,
             printer_data: Vec::new(),
-            tx_post_seq,
             terminal_service_id: "".to_owned(),
             terminal_persistent: false,
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -575,6 +600,9 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            // The profile of the session can't be overridden.
+                            let enabled = enabled
+                                && conn.permission_profile.as_ref().map_or(true, |(_, p)| p.allows(&name));
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -747,11 +775,12 @@ impl Connection {
                 _ = conn.file_timer.tick() => {
                     if !conn.read_jobs.is_empty() {
                         conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), fs::serialize_transfer_jobs(&conn.read_jobs))));
-                        match fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
-                            Ok(log) => {
+                        match file_digest::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream, &mut conn.sent_digests).await {
+                            Ok((log, digests)) => {
                                 if !log.is_empty() {
                                     conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
                                 }
+                                conn.on_file_digests_sent(digests);
                             }
                             Err(err) =>  {
                                 conn.on_close(&err.to_string(), false).await;
@@ -820,7 +849,11 @@ impl Connection {
                                 msg = Arc::new(new_msg);
                             }
                         }
+                        Some(message::Union::Clipboard(cb)) => {
+                            conn.record_clipboard_audit("out", std::slice::from_ref(cb));
+                        }
                         Some(message::Union::MultiClipboards(_multi_clipboards)) => {
+                            conn.record_clipboard_audit("out", &_multi_clipboards.clipboards);
                             #[cfg(not(target_os = "ios"))]
                             if let Some(msg_out) = crate::clipboard::get_msg_if_not_support_multi_clip(&conn.lr.version, &conn.lr.my_platform, _multi_clipboards) {
                                 if let Err(err) = conn.stream.send(&msg_out).await {
@@ -841,6 +874,9 @@ impl Connection {
                 },
                 Some(data) = rx_from_authed.recv() => {
                     match data {
+                        ipc::Data::RawMessage(bytes) => {
+                            allow_err!(conn.stream.send_raw(bytes).await);
+                        }
                         #[cfg(all(target_os = "windows", feature = "flutter"))]
                         ipc::Data::PrinterData(data) => {
                             if config::Config::get_bool_option(config::keys::OPTION_ENABLE_REMOTE_PRINTER) {
@@ -862,6 +898,14 @@ impl Connection {
                             break;
                         }
                     }
+                    if conn.queue_position.is_some() && !conn.authorized {
+                        conn.retry_queued_session().await;
+                    }
+                    if !conn.check_access_schedule_end().await {
+                        conn.send_close_reason_no_retry("The access window is closed").await;
+                        conn.on_close("access schedule", true).await;
+                        break;
+                    }
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
                     #[cfg(feature = "hwcodec")]
                     conn.update_supported_encoding();
@@ -928,9 +972,15 @@ impl Connection {
             raii::AuthedConnID::check_remove_session(conn.inner.id(), conn.session_key());
         }
 
-        conn.post_conn_audit(json!({
+        let mut audit = json!({
             "action": "close",
-        }));
+        });
+        if let Some(stats) = conn.port_forward_stats.take() {
+            let stats = stats.snapshot();
+            log::info!("Port forwarding closed, {}", stats);
+            audit["port_forward"] = json!(stats);
+        }
+        conn.post_conn_audit(audit);
         if let Some(s) = conn.server.upgrade() {
             let mut s = s.write().unwrap();
             s.remove_connection(&conn.inner);
@@ -1046,11 +1096,115 @@ impl Connection {
         log::debug!("Input thread exited");
     }
 
-    async fn post_seq_loop(mut rx: mpsc::UnboundedReceiver<(String, Value)>) {
-        while let Some((url, v)) = rx.recv().await {
-            allow_err!(Self::post_audit_async(url, v).await);
+    // Open the listener or the UDP socket of the forward requested at login, nothing is
+    // opened for a peer which is not logged in yet. Returns false if it fails.
+    async fn start_pending_port_forward(&mut self) -> bool {
+        let Some(pf) = self.pending_port_forward.take() else {
+            return true;
+        };
+        let res = match reverse_forward::parse_host(&pf.host) {
+            Some(reverse_forward::Request::Listen(bind)) => {
+                let mut addr = format!("{}:{}", bind, pf.port);
+                self.port_forward_address = format!("reverse {}", addr);
+                match port_forward_policy::check(bind, pf.port).await {
+                    port_forward_policy::Check::Unrestricted => {}
+                    port_forward_policy::Check::Allowed(allowed) => {
+                        addr = allowed[0].to_string();
+                    }
+                    port_forward_policy::Check::Denied => {
+                        let target = self.port_forward_address.clone();
+                        self.on_port_forward_denied(&target).await;
+                        return false;
+                    }
+                }
+                reverse_forward::start_listen(&addr)
+                    .await
+                    .map_err(|err| format!("Failed to listen on {}: {}", addr, err))
+            }
+            Some(reverse_forward::Request::Accept(token)) => {
+                match reverse_forward::take_pending(token) {
+                    Some((sock, desc)) => {
+                        self.port_forward_address = format!("reverse {}", desc);
+                        Ok(sock)
+                    }
+                    None => Err("The reverse forwarded connection is closed".to_owned()),
+                }
+            }
+            None if mux_forward::is_host(&pf.host) => {
+                self.port_forward_address = "multiplexed".to_owned();
+                let ip = self.ip.clone();
+                let (id, name) = (self.lr.my_id.clone(), self.lr.my_name.clone());
+                // The targets are checked against the allowlist when their streams are opened.
+                mux_forward::start(move |target| {
+                    log::warn!("Port forward to {} is denied by the allowlist", target);
+                    Connection::post_alarm_audit(
+                        AlarmAuditType::PortForwardDenied,
+                        json!({
+                            "ip": ip,
+                            "id": id,
+                            "name": name,
+                            "target": target,
+                        }),
+                    );
+                })
+                .await
+                .map_err(|err| format!("Failed to start the multiplexed forward: {}", err))
+            }
+            None => {
+                let host = udp_forward::parse_host(&pf.host).unwrap_or_default();
+                let host = if host.is_empty() { "localhost" } else { host };
+                let mut addr = format!("{}:{}", host, pf.port);
+                self.port_forward_address = format!("udp {}", addr);
+                match port_forward_policy::check(host, pf.port).await {
+                    port_forward_policy::Check::Unrestricted => {}
+                    port_forward_policy::Check::Allowed(allowed) => {
+                        addr = allowed[0].to_string();
+                    }
+                    port_forward_policy::Check::Denied => {
+                        let target = self.port_forward_address.clone();
+                        self.on_port_forward_denied(&target).await;
+                        return false;
+                    }
+                }
+                udp_forward::start(&addr)
+                    .await
+                    .map_err(|err| format!("Failed to access remote {}: {}", addr, err))
+            }
+        };
+        match res {
+            Ok(sock) => {
+                self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                true
+            }
+            Err(err) => {
+                self.send_login_error(err).await;
+                false
+            }
         }
-        log::debug!("post_seq_loop exited");
+    }
+
+    #[inline]
+    fn is_port_forward(&self) -> bool {
+        self.port_forward_socket.is_some() || self.pending_port_forward.is_some()
+    }
+
+    async fn on_port_forward_denied(&mut self, addr: &str) {
+        log::warn!("Port forward to {} is denied by the allowlist", addr);
+        self.send_login_error(format!(
+            "Forwarding to {} is not allowed by the remote side",
+            addr
+        ))
+        .await;
+        Self::post_alarm_audit(
+            AlarmAuditType::PortForwardDenied,
+            json!({
+                "ip": self.ip,
+                "id": self.lr.my_id.clone(),
+                "name": self.lr.my_name.clone(),
+                "target": addr,
+            }),
+        );
+        sleep(1.).await;
     }
 
     async fn try_port_forward_loop(
@@ -1061,6 +1215,15 @@ impl Connection {
         if let Some(mut forward) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
+            let stats = port_forward_stats::new_target_connection(
+                &self.port_forward_address,
+                format!(
+                    "{}@{} {}",
+                    self.lr.my_id, self.ip, self.port_forward_address
+                ),
+            );
+            self.port_forward_stats = Some(stats.clone());
+            // Closing by either side is not counted as an error.
             let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
             loop {
                 tokio::select! {
@@ -1079,7 +1242,9 @@ impl Connection {
                     res = forward.next() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            self.stream.send_bytes(res?.into()).await?;
+                            let bytes = stats.check(res)?;
+                            stats.add_out(bytes.len());
+                            stats.check(self.stream.send_bytes(bytes.into()).await)?;
                         } else {
                             bail!("Forward reset by the peer");
                         }
@@ -1087,13 +1252,18 @@ impl Connection {
                     res = self.stream.next() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            timeout(SEND_TIMEOUT_OTHER, forward.send(res?)).await??;
+                            let bytes = stats.check(res)?;
+                            stats.add_in(bytes.len());
+                            // Timed out, or failed to send.
+                            let res = stats.check(timeout(SEND_TIMEOUT_OTHER, forward.send(bytes)).await)?;
+                            stats.check(res)?;
                         } else {
                             bail!("Stream reset by the peer");
                         }
                     },
                     _ = self.timer.tick() => {
                         if last_recv_time.elapsed() >= H1 {
+                            stats.add_error();
                             bail!("Timeout");
                         }
                     }
@@ -1160,6 +1330,26 @@ impl Connection {
         true
     }
 
+    async fn check_peer_id(&mut self) -> bool {
+        let peer_id_filter::Check::Denied(pattern) = peer_id_filter::check(&self.lr.my_id) else {
+            return true;
+        };
+        log::warn!("Peer ID {} is denied", self.lr.my_id);
+        self.send_login_error("Your ID is blocked by the peer")
+            .await;
+        Self::post_alarm_audit(
+            AlarmAuditType::PeerIdDenied,
+            json!({
+                "ip": self.ip,
+                "id": self.lr.my_id.clone(),
+                "name": self.lr.my_name.clone(),
+                "pattern": pattern,
+            }),
+        );
+        sleep(1.).await;
+        false
+    }
+
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
         if !self.check_whitelist(&addr).await {
@@ -1198,16 +1388,43 @@ impl Connection {
     }
 
     fn post_conn_audit(&self, v: Value) {
-        if self.server_audit_conn.is_empty() {
+        let url = self.server_audit_conn.clone();
+        let local = audit_log::is_enabled(!url.is_empty());
+        if url.is_empty() && !local {
             return;
         }
-        let url = self.server_audit_conn.clone();
         let mut v = v;
         v["id"] = json!(Config::get_id());
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["conn_id"] = json!(self.inner.id);
         v["session_id"] = json!(self.lr.session_id);
-        allow_err!(self.tx_post_seq.send((url, v)));
+        if local {
+            audit_log::record("conn", &v);
+        }
+        if url.is_empty() {
+            return;
+        }
+        audit_log::outbox::post(url, v.to_string());
+    }
+
+    // Clipboard use is written to the local audit log only.
+    fn record_clipboard_audit(&self, direction: &str, clipboards: &[Clipboard]) {
+        if clipboards.is_empty() || !audit_log::is_enabled(!self.server_audit_conn.is_empty()) {
+            return;
+        }
+        audit_log::record(
+            "clipboard",
+            &json!({
+                "id": Config::get_id(),
+                "conn_id": self.inner.id,
+                "session_id": self.lr.session_id,
+                "peer_id": self.lr.my_id,
+                "ip": self.ip,
+                "direction": direction,
+                "formats": clipboards.iter().map(|c| c.format.value()).collect::<Vec<_>>(),
+                "size": clipboards.iter().map(|c| c.content.len()).sum::<usize>(),
+            }),
+        );
     }
 
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
@@ -1233,10 +1450,11 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
-        if self.server_audit_file.is_empty() {
+        let url = self.server_audit_file.clone();
+        let local = audit_log::is_enabled(!url.is_empty());
+        if url.is_empty() && !local {
             return;
         }
-        let url = self.server_audit_file.clone();
         let file_num = files.len();
         let mut files = files;
         files.sort_by(|a, b| b.1.cmp(&a.1));
@@ -1256,9 +1474,31 @@ impl Connection {
             "is_file":is_file,
             "info":json!(info).to_string(),
         });
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        if local {
+            audit_log::record("file", &v);
+        }
+        if url.is_empty() {
+            return;
+        }
+        audit_log::outbox::post(url, v.to_string());
+    }
+
+    // The digests of the largest files, the same as the files of the record.
+    fn post_file_digest_audit(
+        &self,
+        r#type: FileAuditType,
+        path: &str,
+        files: Vec<(String, i64)>,
+        digests: &[u8],
+    ) {
+        let mut sha256: Vec<_> = files.iter().zip(file_digest::to_hex(digests)).collect();
+        sha256.sort_by(|a, b| b.0 .1.cmp(&a.0 .1));
+        sha256.truncate(10);
+        let sha256: serde_json::Map<String, Value> = sha256
+            .into_iter()
+            .map(|((name, _), hex)| (name.clone(), json!(hex)))
+            .collect();
+        self.post_file_audit(r#type, path, files, json!({ "sha256": sha256 }));
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
@@ -1267,7 +1507,8 @@ impl Connection {
             Config::get_option("custom-rendezvous-server"),
             "alarm".to_owned(),
         );
-        if url.is_empty() {
+        let local = audit_log::is_enabled(!url.is_empty());
+        if url.is_empty() && !local {
             return;
         }
         let mut v = Value::default();
@@ -1275,14 +1516,13 @@ impl Connection {
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["typ"] = json!(typ as i8);
         v["info"] = serde_json::Value::String(info.to_string());
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
-    }
-
-    #[inline]
-    async fn post_audit_async(url: String, v: Value) -> ResultType<String> {
-        crate::post_request(url, v.to_string(), "").await
+        if local {
+            audit_log::record("alarm", &v);
+        }
+        if url.is_empty() {
+            return;
+        }
+        audit_log::outbox::post(url, v.to_string());
     }
 
     async fn send_logon_response(&mut self) {
@@ -1320,10 +1560,27 @@ impl Connection {
             self.send_login_error(crate::client::REQUIRE_2FA).await;
             return;
         }
-        self.authorized = true;
-        let (conn_type, auth_conn_type) = if self.file_transfer.is_some() {
+        if !self.apply_permission_profile().await {
+            return;
+        }
+        self.file_policy = file_policy::get(
+            self.permission_profile
+                .as_ref()
+                .map(|(name, _)| name.as_str()),
+        );
+        if !self.from_switch && !self.check_access_schedule(self.password_kind).await {
+            return;
+        }
+        if !self.check_session_limit(self.auth_conn_type().1).await {
+            return;
+        }
+        self.accept_logon().await;
+    }
+
+    fn auth_conn_type(&self) -> (i32, AuthConnType) {
+        if self.file_transfer.is_some() {
             (1, AuthConnType::FileTransfer)
-        } else if self.port_forward_socket.is_some() {
+        } else if self.is_port_forward() {
             (2, AuthConnType::PortForward)
         } else if self.view_camera {
             (3, AuthConnType::ViewCamera)
@@ -1331,7 +1588,16 @@ impl Connection {
             (4, AuthConnType::Terminal)
         } else {
             (0, AuthConnType::Remote)
-        };
+        }
+    }
+
+    // All the checks of the login are passed.
+    async fn accept_logon(&mut self) {
+        let (conn_type, auth_conn_type) = self.auth_conn_type();
+        if !self.start_pending_port_forward().await {
+            return;
+        }
+        self.authorized = true;
         self.authed_conn_id = Some(self::raii::AuthedConnID::new(
             self.inner.id(),
             auth_conn_type,
@@ -1344,9 +1610,11 @@ impl Connection {
             .unwrap()
             .get(&self.session_key())
             .map(|s| s.last_recv_time.clone());
-        self.post_conn_audit(
-            json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
-        );
+        let mut audit = json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type});
+        if let Some((name, _)) = self.permission_profile.as_ref() {
+            audit["profile"] = json!(name);
+        }
+        self.post_conn_audit(audit);
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -1428,6 +1696,14 @@ impl Connection {
         if !platform_additions.is_empty() {
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
         }
+        let mut additions =
+            serde_json::from_str::<serde_json::Map<String, Value>>(&pi.platform_additions)
+                .unwrap_or_default();
+        if let Some((name, _)) = self.permission_profile.as_ref() {
+            additions.insert("permission_profile".into(), json!(name));
+        }
+        additions.insert(capability::PLATFORM_ADDITION.into(), json!(capability::ALL));
+        pi.platform_additions = serde_json::to_string(&additions).unwrap_or("".into());
 
         if self.port_forward_socket.is_some() {
             let mut msg_out = Message::new();
@@ -1613,6 +1889,111 @@ impl Connection {
         }
     }
 
+    #[inline]
+    fn is_view_only(&self) -> bool {
+        self.password_kind == Some(permission_profile::PasswordKind::ViewOnly)
+    }
+
+    // Input, clipboard, file and terminal messages, and the requests changing the device,
+    // are dropped in the sessions of the view-only password, whatever the UI shows.
+    fn is_refused_in_view_only(msg: &Message) -> bool {
+        match &msg.union {
+            Some(message::Union::MouseEvent(_))
+            | Some(message::Union::PointerDeviceEvent(_))
+            | Some(message::Union::KeyEvent(_))
+            | Some(message::Union::Clipboard(_))
+            | Some(message::Union::MultiClipboards(_))
+            | Some(message::Union::Cliprdr(_))
+            | Some(message::Union::FileAction(_))
+            | Some(message::Union::FileResponse(_))
+            | Some(message::Union::TerminalAction(_)) => true,
+            Some(message::Union::Misc(misc)) => matches!(
+                misc.union,
+                Some(misc::Union::RestartRemoteDevice(_))
+                    | Some(misc::Union::ElevationRequest(_))
+                    | Some(misc::Union::SwitchSidesRequest(_))
+                    | Some(misc::Union::TogglePrivacyMode(_))
+                    | Some(misc::Union::ToggleVirtualDisplay(_))
+                    | Some(misc::Union::ChangeResolution(_))
+                    | Some(misc::Union::ChangeDisplayResolution(_))
+            ),
+            _ => false,
+        }
+    }
+
+    // Select the permission profile of the session, and take away what it does not allow.
+    // Returns false if the connection type is not allowed.
+    async fn apply_permission_profile(&mut self) -> bool {
+        if self.from_switch {
+            return true;
+        }
+        let Some((name, profile)) = permission_profile::select(&self.lr.my_id, self.password_kind)
+        else {
+            return true;
+        };
+        let allowed = if self.file_transfer.is_some() {
+            profile.file
+        } else if self.is_port_forward() {
+            profile.tunnel
+        } else if self.view_camera {
+            profile.camera
+        } else if self.terminal {
+            profile.terminal
+        } else {
+            profile.remote
+        };
+        log::info!(
+            "Permission profile of {}: {}{}",
+            self.lr.my_id,
+            name,
+            if allowed {
+                ""
+            } else {
+                ", connection type not allowed"
+            }
+        );
+        if !allowed {
+            self.send_login_error(format!(
+                "The connection type is not allowed by the permission profile \"{}\"",
+                name
+            ))
+            .await;
+            return false;
+        }
+        let mut disabled = vec![];
+        for (permission, value, allowed) in [
+            (Permission::Keyboard, &mut self.keyboard, profile.keyboard),
+            (
+                Permission::Clipboard,
+                &mut self.clipboard,
+                profile.clipboard,
+            ),
+            (Permission::Audio, &mut self.audio, profile.audio),
+            (Permission::File, &mut self.file, profile.file),
+            (Permission::Restart, &mut self.restart, profile.restart),
+            (
+                Permission::Recording,
+                &mut self.recording,
+                profile.recording,
+            ),
+            (
+                Permission::BlockInput,
+                &mut self.block_input,
+                profile.block_input,
+            ),
+        ] {
+            if *value && !allowed {
+                *value = false;
+                disabled.push(permission);
+            }
+        }
+        for permission in disabled {
+            self.send_permission(permission, false).await;
+        }
+        self.permission_profile = Some((name, profile));
+        true
+    }
+
     fn try_sub_camera_displays(&mut self) {
         if let Some(s) = self.server.upgrade() {
             let mut s = s.write().unwrap();
@@ -1625,7 +2006,7 @@ impl Connection {
     #[inline]
     fn is_remote(&self) -> bool {
         self.file_transfer.is_none()
-            && self.port_forward_socket.is_none()
+            && !self.is_port_forward()
             && !self.view_camera
             && !self.terminal
     }
@@ -1844,7 +2225,12 @@ impl Connection {
         self.tx_input.send(MessageInput::Key((msg, press))).ok();
     }
 
-    fn validate_one_password(&self, password: String) -> bool {
+    // Record which password matched, it decides the permission profile.
+    fn validate_one_password(
+        &mut self,
+        password: String,
+        kind: permission_profile::PasswordKind,
+    ) -> bool {
         if password.len() == 0 {
             return false;
         }
@@ -1854,13 +2240,20 @@ impl Connection {
         let mut hasher2 = Sha256::new();
         hasher2.update(&hasher.finalize()[..]);
         hasher2.update(&self.hash.challenge);
-        hasher2.finalize()[..] == self.lr.password[..]
+        if hasher2.finalize()[..] != self.lr.password[..] {
+            return false;
+        }
+        self.password_kind = Some(kind);
+        true
     }
 
     fn validate_password(&mut self) -> bool {
         if password::temporary_enabled() {
             let password = password::temporary_password();
-            if self.validate_one_password(password.clone()) {
+            if self.validate_one_password(
+                password.clone(),
+                permission_profile::PasswordKind::Temporary,
+            ) {
                 raii::AuthedConnID::update_or_insert_session(
                     self.session_key(),
                     Some(password),
@@ -1870,7 +2263,16 @@ impl Connection {
             }
         }
         if password::permanent_enabled() {
-            if self.validate_one_password(Config::get_permanent_password()) {
+            if self.validate_one_password(
+                Config::get_permanent_password(),
+                permission_profile::PasswordKind::Permanent,
+            ) {
+                return true;
+            }
+            if self.validate_one_password(
+                permission_profile::view_only_password(),
+                permission_profile::PasswordKind::ViewOnly,
+            ) {
                 return true;
             }
         }
@@ -1891,7 +2293,11 @@ impl Connection {
         if let Some(session) = session {
             if !self.lr.password.is_empty()
                 && (tfa && session.tfa
-                    || !tfa && self.validate_one_password(session.random_password.clone()))
+                    || !tfa
+                        && self.validate_one_password(
+                            session.random_password.clone(),
+                            permission_profile::PasswordKind::Temporary,
+                        ))
             {
                 log::info!("is recent session");
                 return true;
@@ -2010,6 +2416,14 @@ impl Connection {
             if self.authorized {
                 return true;
             }
+            if !self.check_peer_id().await {
+                return false;
+            }
+            // The session will need the approval in the connection manager.
+            if lr.password.is_empty() && !self.check_access_schedule(None).await {
+                sleep(1.).await;
+                return false;
+            }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
                     if !Connection::permission(keys::OPTION_ENABLE_FILE_TRANSFER) {
@@ -2049,14 +2463,25 @@ impl Connection {
                             o.terminal_persistent.enum_value() == Ok(BoolOption::Yes);
                     }
                     self.terminal_service_id = terminal.service_id;
+                    let os_login = !lr.os_login.username.is_empty();
+                    if os_login && !self.check_failure(login_lockout::Kind::OsLogin).await {
+                        sleep(1.).await;
+                        return false;
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if let Some(msg) =
                         self.fill_terminal_user_token(&lr.os_login.username, &lr.os_login.password)
                     {
+                        if os_login {
+                            self.update_failure(login_lockout::Kind::OsLogin, false);
+                        }
                         self.send_login_error(msg).await;
                         sleep(1.).await;
                         return false;
                     }
+                    if os_login {
+                        self.update_failure(login_lockout::Kind::OsLogin, true);
+                    }
 
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if let Some(is_user) =
@@ -2077,6 +2502,18 @@ impl Connection {
                         }
                     }
                 }
+                Some(login_request::Union::PortForward(pf))
+                    if reverse_forward::parse_host(&pf.host).is_some()
+                        || udp_forward::parse_host(&pf.host).is_some()
+                        || mux_forward::is_host(&pf.host) =>
+                {
+                    if !Connection::permission("enable-tunnel") {
+                        self.send_login_error("No permission of IP tunneling").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    self.pending_port_forward = Some(pf);
+                }
                 Some(login_request::Union::PortForward(mut pf)) => {
                     if !Connection::permission("enable-tunnel") {
                         self.send_login_error("No permission of IP tunneling").await;
@@ -2094,7 +2531,22 @@ impl Connection {
                     }
                     let mut addr = format!("{}:{}", pf.host, pf.port);
                     self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
+                    let allowed = match port_forward_policy::check(&pf.host, pf.port).await {
+                        port_forward_policy::Check::Unrestricted => vec![],
+                        port_forward_policy::Check::Allowed(allowed) => allowed,
+                        port_forward_policy::Check::Denied => {
+                            self.on_port_forward_denied(&addr).await;
+                            return false;
+                        }
+                    };
+                    let connect = async {
+                        if allowed.is_empty() {
+                            TcpStream::connect(&addr).await
+                        } else {
+                            TcpStream::connect(&allowed[..]).await
+                        }
+                    };
+                    match timeout(3000, connect).await {
                         Ok(Ok(sock)) => {
                             self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
                         }
@@ -2124,9 +2576,25 @@ impl Connection {
             #[cfg(not(target_os = "linux"))]
             let err_msg = "".to_owned();
             #[cfg(target_os = "linux")]
+            let os_login = lr
+                .os_login
+                .as_ref()
+                .map_or(false, |x| !x.username.is_empty());
+            #[cfg(target_os = "linux")]
+            if os_login && !self.check_failure(login_lockout::Kind::OsLogin).await {
+                return true;
+            }
+            #[cfg(target_os = "linux")]
             let err_msg = self
                 .linux_headless_handle
                 .try_start_desktop(lr.os_login.as_ref());
+            #[cfg(target_os = "linux")]
+            if os_login {
+                self.update_failure(
+                    login_lockout::Kind::OsLogin,
+                    err_msg != crate::client::LOGIN_MSG_DESKTOP_XSESSION_FAILED,
+                );
+            }
 
             // If err is LOGIN_MSG_DESKTOP_SESSION_NOT_READY, just keep this msg and go on checking password.
             if !err_msg.is_empty() && err_msg != crate::client::LOGIN_MSG_DESKTOP_SESSION_NOT_READY
@@ -2135,6 +2603,17 @@ impl Connection {
                 return true;
             }
 
+            // `allow` only stands for the click in the connection manager, the password is
+            // still checked.
+            let approval = self.ask_approval_hook().await;
+            if approval == Some(approval_hook::Answer::Deny) {
+                self.send_login_error("Connection denied by the approval policy")
+                    .await;
+                sleep(1.).await;
+                return false;
+            }
+            let approved = approval == Some(approval_hook::Answer::Allow);
+
             // https://github.com/rustdesk/rustdesk-server-pro/discussions/646
             // `is_logon` is used to check login with `OPTION_ALLOW_LOGON_SCREEN_PASSWORD` == "Y".
             // `is_logon_ui()` is used on Windows, because there's no good way to detect `is_locked()`.
@@ -2163,10 +2642,13 @@ impl Connection {
                 self.send_login_error(crate::client::LOGIN_MSG_OFFLINE)
                     .await;
                 return false;
-            } else if (password::approve_mode() == ApproveMode::Click
-                && !(crate::get_builtin_option(keys::OPTION_ALLOW_LOGON_SCREEN_PASSWORD) == "Y"
-                    && is_logon()))
-                || password::approve_mode() == ApproveMode::Both && !password::has_valid_password()
+            } else if !approved
+                && ((password::approve_mode() == ApproveMode::Click
+                    && !(crate::get_builtin_option(keys::OPTION_ALLOW_LOGON_SCREEN_PASSWORD)
+                        == "Y"
+                        && is_logon()))
+                    || password::approve_mode() == ApproveMode::Both
+                        && !password::has_valid_password())
             {
                 self.try_start_cm(lr.my_id, lr.my_name, false);
                 if hbb_common::get_version_number(&lr.version)
@@ -2195,12 +2677,11 @@ impl Connection {
                     .await;
                 }
             } else {
-                let (failure, res) = self.check_failure(0).await;
-                if !res {
+                if !self.check_failure(login_lockout::Kind::Password).await {
                     return true;
                 }
                 if !self.validate_password() {
-                    self.update_failure(failure, false, 0);
+                    self.update_failure(login_lockout::Kind::Password, false);
                     if err_msg.is_empty() {
                         self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_WRONG)
                             .await;
@@ -2212,7 +2693,7 @@ impl Connection {
                         .await;
                     }
                 } else {
-                    self.update_failure(failure, true, 0);
+                    self.update_failure(login_lockout::Kind::Password, true);
                     if err_msg.is_empty() {
                         #[cfg(target_os = "linux")]
                         self.linux_headless_handle.wait_desktop_cm_ready().await;
@@ -2224,14 +2705,13 @@ impl Connection {
                 }
             }
         } else if let Some(message::Union::Auth2fa(tfa)) = msg.union {
-            let (failure, res) = self.check_failure(1).await;
-            if !res {
+            if !self.check_failure(login_lockout::Kind::TwoFactor).await {
                 return true;
             }
             if let Some(totp) = self.require_2fa.as_ref() {
                 if let Ok(res) = totp.check_current(&tfa.code) {
                     if res {
-                        self.update_failure(failure, true, 1);
+                        self.update_failure(login_lockout::Kind::TwoFactor, true);
                         self.require_2fa.take();
                         raii::AuthedConnID::set_session_2fa(self.session_key());
                         self.send_logon_response().await;
@@ -2250,7 +2730,7 @@ impl Connection {
                             });
                         }
                     } else {
-                        self.update_failure(failure, false, 1);
+                        self.update_failure(login_lockout::Kind::TwoFactor, false);
                         self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
                             .await;
                     }
@@ -2301,6 +2781,9 @@ impl Connection {
             if self.port_forward_socket.is_some() {
                 return true;
             }
+            if self.is_view_only() && Self::is_refused_in_view_only(&msg) {
+                return true;
+            }
             match msg.union {
                 #[allow(unused_mut)]
                 Some(message::Union::MouseEvent(mut me)) => {
@@ -2501,6 +2984,7 @@ impl Connection {
                 }
                 Some(message::Union::Clipboard(cb)) => {
                     if self.clipboard {
+                        self.record_clipboard_audit("in", std::slice::from_ref(&cb));
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Host);
                         // ios as the controlled side is actually not supported for now.
@@ -2528,6 +3012,9 @@ impl Connection {
                     }
                 }
                 Some(message::Union::MultiClipboards(_mcb)) => {
+                    if self.clipboard {
+                        self.record_clipboard_audit("in", &_mcb.clipboards);
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_mcb.clipboards, ClipboardSide::Host);
@@ -2651,6 +3138,29 @@ impl Connection {
                                 return true;
                             }
                         }
+                        if let Some((id, file_num, err)) = self.check_file_policy(&fa) {
+                            log::warn!("File action refused: {}", err);
+                            match &fa.union {
+                                Some(file_action::Union::Send(s)) => self.post_file_audit(
+                                    FileAuditType::RemoteSend,
+                                    &s.path,
+                                    vec![],
+                                    json!({"refused": err}),
+                                ),
+                                Some(file_action::Union::Receive(r)) => self.post_file_audit(
+                                    FileAuditType::RemoteReceive,
+                                    &r.path,
+                                    Self::get_files_for_audit(
+                                        fs::JobType::Generic,
+                                        r.files.clone(),
+                                    ),
+                                    json!({"refused": err}),
+                                ),
+                                _ => {}
+                            }
+                            self.send(fs::new_error(id, err, file_num)).await;
+                            return true;
+                        }
                         match fa.union {
                             Some(file_action::Union::ReadEmptyDirs(rd)) => {
                                 self.read_empty_dirs(&rd.path, rd.include_hidden);
@@ -2712,9 +3222,43 @@ impl Connection {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
                                     Ok(mut job) => {
+                                        let files = job.files().to_owned();
+                                        if let Some(Err(err)) = self
+                                            .file_policy
+                                            .as_ref()
+                                            .filter(|_| r#type == JobType::Generic)
+                                            .map(|p| p.check_transfer(false, &path, &files))
+                                        {
+                                            let err = err.to_string();
+                                            log::warn!("File action refused: {}", err);
+                                            self.post_file_audit(
+                                                FileAuditType::RemoteSend,
+                                                &path,
+                                                Self::get_files_for_audit(r#type, files),
+                                                json!({"refused": err}),
+                                            );
+                                            self.send(fs::new_error(id, err, 0)).await;
+                                            return true;
+                                        }
+                                        if r#type == JobType::Generic
+                                            && self
+                                                .peer_capabilities
+                                                .contains(capability::FILE_DIGEST)
+                                        {
+                                            self.digest_read_jobs.insert(
+                                                id,
+                                                (
+                                                    path.clone(),
+                                                    Self::get_files_for_audit(
+                                                        r#type,
+                                                        files.clone(),
+                                                    ),
+                                                ),
+                                            );
+                                            self.sent_digests.add(id);
+                                        }
                                         self.send(fs::new_dir(id, path, job.files().to_vec()))
                                             .await;
-                                        let files = job.files().to_owned();
                                         job.is_remote = true;
                                         job.conn_id = self.inner.id();
                                         let job_type = job.r#type;
@@ -2742,6 +3286,21 @@ impl Connection {
                                 let od = can_enable_overwrite_detection(get_version_number(
                                     &self.lr.version,
                                 ));
+                                let digests =
+                                    self.peer_capabilities.contains(capability::FILE_DIGEST);
+                                if digests {
+                                    self.digest_write_jobs.insert(
+                                        r.id,
+                                        (
+                                            r.path.clone(),
+                                            Self::get_files_for_audit(
+                                                fs::JobType::Generic,
+                                                r.files.clone(),
+                                            ),
+                                            vec![],
+                                        ),
+                                    );
+                                }
                                 self.send_fs(ipc::FS::NewWrite {
                                     path: r.path.clone(),
                                     id: r.id,
@@ -2755,6 +3314,7 @@ impl Connection {
                                     overwrite_detection: od,
                                     total_size: r.total_size,
                                     conn_id: self.inner.id(),
+                                    digests,
                                 });
                                 self.post_file_audit(
                                     FileAuditType::RemoteReceive,
@@ -2797,6 +3357,10 @@ impl Connection {
                                 )));
                             }
                             Some(file_action::Union::Cancel(c)) => {
+                                self.file_policy_received.retain(|k, _| k.0 != c.id);
+                                self.digest_read_jobs.remove(&c.id);
+                                self.sent_digests.remove(c.id);
+                                self.digest_write_jobs.remove(&c.id);
                                 self.send_fs(ipc::FS::CancelWrite { id: c.id });
                                 if let Some(job) = fs::remove_job(c.id, &mut self.read_jobs) {
                                     self.send_to_cm(ipc::Data::FileTransferLog((
@@ -2807,6 +3371,7 @@ impl Connection {
                             }
                             Some(file_action::Union::SendConfirm(r)) => {
                                 if let Some(job) = fs::get_job(r.id, &mut self.read_jobs) {
+                                    self.sent_digests.on_confirm(&r);
                                     job.confirm(&r).await;
                                 } else {
                                     if let Ok(sc) = r.write_to_bytes() {
@@ -2836,6 +3401,41 @@ impl Connection {
                 }
                 Some(message::Union::FileResponse(fr)) => match fr.union {
                     Some(file_response::Union::Block(block)) => {
+                        if capability::is_block(&block) {
+                            self.peer_capabilities = capability::from_block(&block);
+                            return true;
+                        }
+                        if file_digest::is_digest_block(&block) {
+                            if let Some((_, _, digests)) = self.digest_write_jobs.get_mut(&block.id)
+                            {
+                                file_digest::set(digests, block.file_num, &block.data);
+                            }
+                            self.send_fs(ipc::FS::FileDigest {
+                                id: block.id,
+                                file_num: block.file_num,
+                                digest: block.data.to_vec(),
+                            });
+                            return true;
+                        }
+                        if file_delta::is_delta_block(&block) {
+                            if !self.check_file_policy_received(&block).await {
+                                return true;
+                            }
+                            if block.blk_id == file_delta::END_BLK_ID {
+                                // Done, or transferred in full from the start.
+                                self.file_policy_received
+                                    .remove(&(block.id, block.file_num));
+                            }
+                            self.handle_file_delta_block(block);
+                            return true;
+                        }
+                        if file_sync::is_hash_block(&block) {
+                            self.handle_file_sync_hash(block).await;
+                            return true;
+                        }
+                        if !self.check_file_policy_received(&block).await {
+                            return true;
+                        }
                         self.send_fs(ipc::FS::WriteBlock {
                             id: block.id,
                             file_num: block.file_num,
@@ -2844,6 +3444,15 @@ impl Connection {
                         });
                     }
                     Some(file_response::Union::Done(d)) => {
+                        self.file_policy_received.remove(&(d.id, d.file_num));
+                        if let Some((path, files, digests)) = self.digest_write_jobs.remove(&d.id) {
+                            self.post_file_digest_audit(
+                                FileAuditType::RemoteReceive,
+                                &path,
+                                files,
+                                &digests,
+                            );
+                        }
                         self.send_fs(ipc::FS::WriteDone {
                             id: d.id,
                             file_num: d.file_num,
@@ -2858,6 +3467,15 @@ impl Connection {
                         is_resume: d.is_resume,
                     }),
                     Some(file_response::Union::Error(e)) => {
+                        if fs::get_job(e.id, &mut self.read_jobs).is_some() {
+                            log::warn!(
+                                "The peer fails to receive file {} of job {}: {}",
+                                e.file_num,
+                                e.id,
+                                e.error
+                            );
+                        }
+                        self.digest_write_jobs.remove(&e.id);
                         self.send_fs(ipc::FS::WriteError {
                             id: e.id,
                             file_num: e.file_num,
@@ -3170,61 +3788,49 @@ impl Connection {
         }
     }
 
-    fn update_failure(&self, (mut failure, time): ((i32, i32, i32), i32), remove: bool, i: usize) {
-        if remove {
-            if failure.0 != 0 {
-                LOGIN_FAILURES[i].lock().unwrap().remove(&self.ip);
-            }
-            return;
-        }
-        if failure.0 == time {
-            failure.1 += 1;
-            failure.2 += 1;
-        } else {
-            failure.0 = time;
-            failure.1 = 1;
-            failure.2 += 1;
-        }
-        LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .insert(self.ip.clone(), failure);
-    }
-
-    async fn check_failure(&mut self, i: usize) -> (((i32, i32, i32), i32), bool) {
-        let failure = LOGIN_FAILURES[i]
-            .lock()
-            .unwrap()
-            .get(&self.ip)
-            .map(|x| x.clone())
-            .unwrap_or((0, 0, 0));
-        let time = (get_time() / 60_000) as i32;
-        let res = if failure.2 > 30 {
-            self.send_login_error("Too many wrong attempts").await;
+    fn update_failure(&self, kind: login_lockout::Kind, success: bool) {
+        if let Some(ban) = login_lockout::update(kind, &self.ip, success) {
             Self::post_alarm_audit(
-                AlarmAuditType::ExceedThirtyAttempts,
+                AlarmAuditType::LoginBanned,
                 json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
+                    "ip": self.ip,
+                    "id": self.lr.my_id.clone(),
+                    "name": self.lr.my_name.clone(),
+                    "kind": ban.kind,
+                    "failures": ban.failures,
+                    "until": ban.until,
                 }),
             );
-            false
-        } else if time == failure.0 && failure.1 > 6 {
-            self.send_login_error("Please try 1 minute later").await;
-            Self::post_alarm_audit(
+        }
+    }
+
+    async fn check_failure(&mut self, kind: login_lockout::Kind) -> bool {
+        let (msg, typ) = match login_lockout::check(kind, &self.ip) {
+            login_lockout::Check::Allowed => return true,
+            login_lockout::Check::Banned => (
+                "Too many wrong attempts".to_owned(),
+                AlarmAuditType::ExceedThirtyAttempts,
+            ),
+            login_lockout::Check::MinuteLimit => (
+                "Please try 1 minute later".to_owned(),
                 AlarmAuditType::SixAttemptsWithinOneMinute,
-                json!({
-                            "ip": self.ip,
-                            "id": self.lr.my_id.clone(),
-                            "name": self.lr.my_name.clone(),
-                }),
-            );
-            false
-        } else {
-            true
+            ),
+            login_lockout::Check::Backoff(secs) => (
+                format!("Please try {} seconds later", secs),
+                AlarmAuditType::LoginBackoff,
+            ),
         };
-        ((failure, time), res)
+        self.send_login_error(msg).await;
+        Self::post_alarm_audit(
+            typ,
+            json!({
+                "ip": self.ip,
+                "id": self.lr.my_id.clone(),
+                "name": self.lr.my_name.clone(),
+                "kind": kind.name(),
+            }),
+        );
+        false
     }
 
     fn refresh_video_display(&self, display: Option<usize>) {
@@ -3896,7 +4502,156 @@ impl Connection {
         raii::AuthedConnID::check_remove_session(self.inner.id(), self.session_key());
     }
 
+    // The job ID, the file number and the error of the file action refused by the file
+    // transfer policy. The files of `Send` are checked when its job is created.
+    fn check_file_policy(&self, fa: &FileAction) -> Option<(i32, i32, String)> {
+        let policy = self.file_policy.as_ref()?;
+        let (id, file_num, res) = match fa.union.as_ref()? {
+            file_action::Union::AllFiles(f) => (f.id, -1, policy.check_path(&f.path)),
+            file_action::Union::Send(s) if JobType::from_proto(s.file_type) == JobType::Generic => {
+                (s.id, 0, policy.check_transfer(false, &s.path, &[]))
+            }
+            file_action::Union::Receive(r) => {
+                (r.id, 0, policy.check_transfer(true, &r.path, &r.files))
+            }
+            file_action::Union::RemoveDir(d) => (d.id, 0, policy.check_change(&d.path)),
+            file_action::Union::RemoveFile(f) => (f.id, f.file_num, policy.check_change(&f.path)),
+            file_action::Union::Create(c) => (c.id, 0, policy.check_change(&c.path)),
+            file_action::Union::Rename(r) => {
+                let new_path = std::path::Path::new(&r.path).with_file_name(&r.new_name);
+                let new_path = new_path.to_string_lossy();
+                (
+                    r.id,
+                    0,
+                    policy
+                        .check_change(&r.path)
+                        .and_then(|_| policy.check_change(&new_path))
+                        .and_then(|_| policy.check_file(&new_path, 0)),
+                )
+            }
+            _ => return None,
+        };
+        res.err().map(|err| (id, file_num, err.to_string()))
+    }
+
+    // The bytes of a file over the maximum size of the file transfer policy are refused,
+    // whatever the size the peer claims. The bytes of a delta are the literal ones.
+    async fn check_file_policy_received(&mut self, block: &FileTransferBlock) -> bool {
+        let max = match self.file_policy.as_ref() {
+            Some(policy) if policy.max_file_size() > 0 => policy.max_file_size(),
+            _ => return true,
+        };
+        let len = if file_delta::is_delta_block(block) {
+            file_delta::literal_len(block)
+        } else if block.compressed {
+            hbb_common::compress::decompress(&block.data).len() as u64
+        } else {
+            block.data.len() as u64
+        };
+        let (id, file_num) = (block.id, block.file_num);
+        let received = self.file_policy_received.entry((id, file_num)).or_default();
+        *received += len;
+        if *received <= max {
+            return true;
+        }
+        let err = format!(
+            "The file is larger than {} bytes allowed by the file transfer policy",
+            max
+        );
+        log::warn!("File action refused: {}", err);
+        self.file_policy_received.retain(|k, _| k.0 != id);
+        self.send_fs(ipc::FS::CancelWrite { id });
+        self.post_file_audit(
+            FileAuditType::RemoteReceive,
+            "",
+            vec![],
+            json!({"refused": err}),
+        );
+        self.send(fs::new_error(id, err, file_num)).await;
+        false
+    }
+
+    // Audit the digests sent of the read jobs done, and forget the jobs failed.
+    fn on_file_digests_sent(&mut self, digests: Vec<(i32, Vec<u8>)>) {
+        for (id, digests) in digests {
+            if let Some((path, files)) = self.digest_read_jobs.remove(&id) {
+                self.post_file_digest_audit(FileAuditType::RemoteSend, &path, files, &digests);
+            }
+        }
+        let read_jobs = &self.read_jobs;
+        self.digest_read_jobs
+            .retain(|id, _| read_jobs.iter().any(|job| job.id() == *id));
+    }
+
+    // The signature of a copy of the file downloaded, or the delta of the file uploaded
+    // applied by the cm, see `crate::file_delta`.
+    fn handle_file_delta_block(&mut self, block: FileTransferBlock) {
+        if block.blk_id == file_delta::SIGNATURE_BLK_ID {
+            if let Some(job) = fs::get_job(block.id, &mut self.read_jobs) {
+                let tx = self.tx_from_authed.clone();
+                file_delta::send_delta(job, block.file_num, block.data.to_vec(), move |msg| {
+                    if let Ok(bytes) = msg.write_to_bytes() {
+                        tx.send(ipc::Data::RawMessage(bytes)).ok();
+                    }
+                });
+            }
+        } else {
+            self.send_fs(ipc::FS::Delta {
+                id: block.id,
+                file_num: block.file_num,
+                blk_id: block.blk_id,
+                data: block.data.to_vec(),
+            });
+        }
+    }
+
+    // The digests of the files of this side compared by a sync of the peer,
+    // see `crate::file_sync`.
+    async fn handle_file_sync_hash(&mut self, block: FileTransferBlock) {
+        let id = block.id;
+        let request: file_sync::HashRequest = match serde_json::from_slice(&block.data) {
+            Ok(request) => request,
+            Err(err) => {
+                log::warn!("Invalid sync hash request: {}", err);
+                return;
+            }
+        };
+        if !self.file_transfer_enabled()
+            || crate::get_builtin_option(keys::OPTION_ONE_WAY_FILE_TRANSFER) == "Y"
+        {
+            self.send(fs::new_error(id, "one-way-file-transfer-tip", -1))
+                .await;
+            return;
+        }
+        if let Some(policy) = self.file_policy.as_ref() {
+            let entries: Vec<FileEntry> = request
+                .files
+                .iter()
+                .map(|name| FileEntry {
+                    name: name.clone(),
+                    ..Default::default()
+                })
+                .collect();
+            if let Err(err) = policy.check_transfer(false, &request.path, &entries) {
+                log::warn!("File action refused: {}", err);
+                self.send(fs::new_error(id, err, -1)).await;
+                return;
+            }
+        }
+        let tx = self.tx_from_authed.clone();
+        tokio::spawn(async move {
+            let digests = file_sync::compute_digests(&request.path, &request.files).await;
+            if let Ok(bytes) = file_sync::new_hash_block(id, digests).write_to_bytes() {
+                tx.send(ipc::Data::RawMessage(bytes)).ok();
+            }
+        });
+    }
+
     fn read_empty_dirs(&mut self, dir: &str, include_hidden: bool) {
+        if let Some(Err(err)) = self.file_policy.as_ref().map(|p| p.check_path(dir)) {
+            log::warn!("File action refused: {}", err);
+            return;
+        }
         let dir = dir.to_string();
         self.send_fs(ipc::FS::ReadEmptyDirs {
             dir,
@@ -3905,7 +4660,22 @@ impl Connection {
     }
 
     fn read_dir(&mut self, dir: &str, include_hidden: bool) {
-        let dir = dir.to_string();
+        let mut dir = dir.to_string();
+        if let Some(policy) = self.file_policy.as_ref() {
+            // The home directory is listed for an empty one.
+            let path = if dir.is_empty() {
+                Config::get_home().to_string_lossy().to_string()
+            } else {
+                dir.clone()
+            };
+            if let Err(err) = policy.check_path(&path) {
+                log::warn!("File action refused: {}", err);
+                match policy.first_root() {
+                    Some(root) => dir = root.to_owned(),
+                    None => return,
+                }
+            }
+        }
         self.send_fs(ipc::FS::ReadDir {
             dir,
             include_hidden,
@@ -3977,6 +4747,142 @@ impl Connection {
         self.pressed_modifiers.clear();
     }
 
+    // Returns false if the session is refused as busy, or waits in the queue.
+    async fn check_session_limit(&mut self, conn_type: AuthConnType) -> bool {
+        let sessions: Vec<_> = AUTHED_CONNS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| (c.conn_type, c.peer_id.clone()))
+            .collect();
+        match session_limit::check(&sessions, self.inner.id(), conn_type, &self.lr.my_id) {
+            session_limit::Check::Accepted => {
+                if self.queue_position.take().is_some() {
+                    log::info!("Queued session of {} is accepted", self.lr.my_id);
+                }
+                true
+            }
+            session_limit::Check::Busy => {
+                log::info!("Session of {} is refused, too many sessions", self.lr.my_id);
+                self.queue_position = None;
+                self.send_login_error(crate::client::LOGIN_MSG_BUSY).await;
+                false
+            }
+            session_limit::Check::Queued(pos) => {
+                if self.queue_position != Some(pos) {
+                    log::info!("Session of {} is queued at {}", self.lr.my_id, pos);
+                    self.queue_position = Some(pos);
+                    self.send_login_error(format!("{}{}", crate::client::LOGIN_MSG_QUEUED, pos))
+                        .await;
+                }
+                false
+            }
+        }
+    }
+
+    // The other checks of the login are passed before the session is queued.
+    async fn retry_queued_session(&mut self) {
+        if !self.check_session_limit(self.auth_conn_type().1).await {
+            return;
+        }
+        self.accept_logon().await;
+        if self.authorized {
+            self.try_start_cm(self.lr.my_id.clone(), self.lr.my_name.clone(), true);
+        }
+    }
+
+    async fn ask_approval_hook(&mut self) -> Option<approval_hook::Answer> {
+        if self.approval.is_none() {
+            let conn_type = if self.file_transfer.is_some() {
+                "file-transfer"
+            } else if self.is_port_forward() {
+                "port-forward"
+            } else if self.view_camera {
+                "view-camera"
+            } else if self.terminal {
+                "terminal"
+            } else {
+                "remote"
+            };
+            self.approval = approval_hook::ask(approval_hook::Request {
+                peer_id: self.lr.my_id.clone(),
+                name: self.lr.my_name.clone(),
+                ip: self.ip.clone(),
+                conn_type: conn_type.to_owned(),
+            })
+            .await;
+            if self.approval == Some(approval_hook::Answer::Deny) {
+                Self::post_alarm_audit(
+                    AlarmAuditType::ApprovalDenied,
+                    json!({
+                        "ip": self.ip,
+                        "id": self.lr.my_id.clone(),
+                        "name": self.lr.my_name.clone(),
+                        "type": conn_type,
+                    }),
+                );
+            }
+        }
+        self.approval
+    }
+
+    async fn check_access_schedule(
+        &mut self,
+        password: Option<permission_profile::PasswordKind>,
+    ) -> bool {
+        match access_schedule::check(&self.lr.my_id, password) {
+            access_schedule::Access::Always => {
+                self.access_schedule_end = None;
+                true
+            }
+            access_schedule::Access::Until(secs, warning) => {
+                self.access_schedule_end = Some((
+                    Instant::now() + Duration::from_secs(secs as _),
+                    warning,
+                    false,
+                ));
+                true
+            }
+            access_schedule::Access::Denied => {
+                log::info!("{} is outside of the access schedule", self.lr.my_id);
+                self.send_login_error("Access is not allowed at this time by the access schedule")
+                    .await;
+                false
+            }
+        }
+    }
+
+    // Warn before the access window closes, returns false when it is closed.
+    async fn check_access_schedule_end(&mut self) -> bool {
+        if !self.authorized {
+            return true;
+        }
+        let Some((end, warning, warned)) = self.access_schedule_end.as_mut() else {
+            return true;
+        };
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            log::info!("The access window of {} is closed", self.lr.my_id);
+            return false;
+        }
+        if !*warned && left <= Duration::from_secs(*warning as _) {
+            *warned = true;
+            let mut msg_out = Message::new();
+            msg_out.set_message_box(MessageBox {
+                msgtype: "custom-nook-nocancel-hasclose".to_owned(),
+                title: "Access schedule".to_owned(),
+                text: format!(
+                    "The access window closes in {} minutes, the session will be disconnected.",
+                    (left.as_secs() + 59) / 60
+                ),
+                link: "".to_owned(),
+                ..Default::default()
+            });
+            self.send(msg_out).await;
+        }
+        true
+    }
+
     fn get_auto_disconenct_timer() -> Option<(Instant, u64)> {
         if Config::get_option("allow-auto-disconnect") == "Y" {
             let mut minute: u64 = Config::get_option("auto-disconnect-timeout")
@@ -4363,6 +5269,11 @@ pub enum AlarmAuditType {
     IpWhitelist = 0,
     ExceedThirtyAttempts = 1,
     SixAttemptsWithinOneMinute = 2,
+    PortForwardDenied = 3,
+    PeerIdDenied = 4,
+    LoginBanned = 5,
+    LoginBackoff = 6,
+    ApprovalDenied = 7,
 }
 
 pub enum FileAuditType {
@@ -4696,6 +5607,7 @@ pub struct AuthedConn {
     pub session_key: SessionKey,
     pub sender: mpsc::UnboundedSender<Data>,
     pub printer: bool,
+    pub peer_id: String,
 }
 
 mod raii {
@@ -4716,6 +5628,7 @@ mod raii {
         fn drop(&mut self) {
             let mut active_conns_lock = ALIVE_CONNS.lock().unwrap();
             active_conns_lock.retain(|&c| c != self.0);
+            crate::server::session_limit::dequeue(self.0);
         }
     }
 
@@ -4738,6 +5651,7 @@ mod raii {
                 session_key,
                 sender,
                 printer,
+                peer_id: lr.my_id.clone(),
             });
             Self::check_wake_lock();
             use std::sync::Once;
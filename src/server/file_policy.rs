@@ -0,0 +1,260 @@
+//! Rules of the file transfers of the incoming sessions, by permission profile.
+//!
+//! The rules are in the `file-transfer-policy` option, in JSON, by the name of the profile
+//! (see [`super::permission_profile`]), `*` for the other sessions, including the ones
+//! without a profile:
+//!
+//! ```json
+//! {
+//!     "support": {
+//!         "roots": ["C:\\Shared", "D:\\Support"],
+//!         "blocked_extensions": ["exe", "msi", "bat"],
+//!         "max_file_size": 104857600,
+//!         "mode": "upload-only"
+//!     },
+//!     "*": { "blocked_extensions": ["exe"] }
+//! }
+//! ```
+//!
+//! - `roots`: the directories the peer is jailed in, nothing outside of them can be listed,
+//!   read or written. Empty for all.
+//! - `blocked_extensions`: the files which can't be sent or received, case insensitive.
+//! - `max_file_size`: in bytes, 0 is unlimited.
+//! - `mode`: `download-only`, the peer can only copy the files of this side, or
+//!   `upload-only`, the peer can only copy its files to this side. Empty for both.
+//!
+//! An empty option has no rules. An invalid one refuses all the file operations.
+
+use hbb_common::{bail, config::Config, log, message_proto::FileEntry, ResultType};
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+pub const OPTION_FILE_TRANSFER_POLICY: &str = "file-transfer-policy";
+pub const DOWNLOAD_ONLY: &str = "download-only";
+pub const UPLOAD_ONLY: &str = "upload-only";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    roots: Vec<String>,
+    blocked_extensions: Vec<String>,
+    max_file_size: u64,
+    mode: String,
+    #[serde(skip)]
+    invalid: bool,
+}
+
+impl Policy {
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// The directory to list instead of the one out of the roots.
+    pub fn first_root(&self) -> Option<&str> {
+        self.roots.first().map(|x| x.as_str())
+    }
+
+    /// The peer copies the files of this side.
+    pub fn check_download(&self) -> ResultType<()> {
+        self.check_valid()?;
+        if self.mode == UPLOAD_ONLY {
+            bail!("Only uploading files is allowed by the file transfer policy");
+        }
+        Ok(())
+    }
+
+    /// The peer copies its files to this side, or changes the files of this side.
+    pub fn check_upload(&self) -> ResultType<()> {
+        self.check_valid()?;
+        if self.mode == DOWNLOAD_ONLY {
+            bail!("Only downloading files is allowed by the file transfer policy");
+        }
+        Ok(())
+    }
+
+    pub fn check_path(&self, path: &str) -> ResultType<()> {
+        self.check_valid()?;
+        if self.roots.is_empty() {
+            return Ok(());
+        }
+        if let Some(path) = resolve(Path::new(path)) {
+            if self
+                .roots
+                .iter()
+                .filter_map(|x| resolve(Path::new(x)))
+                .any(|root| is_within(&path, &root))
+            {
+                return Ok(());
+            }
+        }
+        bail!(
+            "{} is out of the directories allowed by the file transfer policy",
+            path
+        );
+    }
+
+    pub fn check_file(&self, name: &str, size: u64) -> ResultType<()> {
+        self.check_valid()?;
+        if let Some(ext) = Path::new(name).extension() {
+            let ext = ext.to_string_lossy().to_lowercase();
+            if self
+                .blocked_extensions
+                .iter()
+                .any(|x| x.trim_start_matches('.').to_lowercase() == ext)
+            {
+                bail!("{} is blocked by the file transfer policy", name);
+            }
+        }
+        if self.max_file_size > 0 && size > self.max_file_size {
+            bail!(
+                "{} is larger than {} bytes allowed by the file transfer policy",
+                name,
+                self.max_file_size
+            );
+        }
+        Ok(())
+    }
+
+    /// The peer creates, removes or renames `path`.
+    pub fn check_change(&self, path: &str) -> ResultType<()> {
+        self.check_upload()?;
+        self.check_path(path)
+    }
+
+    /// The peer copies `files` in `path`, from this side if not `upload`.
+    pub fn check_transfer(&self, upload: bool, path: &str, files: &[FileEntry]) -> ResultType<()> {
+        if upload {
+            self.check_upload()?;
+        } else {
+            self.check_download()?;
+        }
+        self.check_path(path)?;
+        for f in files {
+            // The name is empty if `path` is the file.
+            let path = Path::new(path).join(&f.name);
+            let path = path.to_string_lossy();
+            let path = path.trim_end_matches(std::path::MAIN_SEPARATOR);
+            self.check_path(path)?;
+            self.check_file(path, f.size)?;
+        }
+        Ok(())
+    }
+
+    fn check_valid(&self) -> ResultType<()> {
+        if self.invalid {
+            bail!("The file transfer policy is invalid");
+        }
+        Ok(())
+    }
+}
+
+// The absolute path with the symbolic links of its existing part resolved,
+// `None` if it is relative or goes up.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || path.components().any(|x| x == Component::ParentDir) {
+        return None;
+    }
+    let mut existing = path;
+    let mut rest = vec![];
+    loop {
+        if let Ok(p) = existing.canonicalize() {
+            return Some(rest.iter().rev().fold(p, |p, x| p.join(x)));
+        }
+        rest.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+}
+
+fn is_within(path: &Path, root: &Path) -> bool {
+    #[cfg(windows)]
+    {
+        let lower = |x: &Path| PathBuf::from(x.to_string_lossy().to_lowercase());
+        lower(path).starts_with(lower(root))
+    }
+    #[cfg(not(windows))]
+    path.starts_with(root)
+}
+
+/// The policy of the sessions with `profile`, `None` if there is no rule for them.
+pub fn get(profile: Option<&str>) -> Option<Policy> {
+    let option = Config::get_option(OPTION_FILE_TRANSFER_POLICY);
+    if option.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str::<HashMap<String, Policy>>(&option) {
+        Ok(mut policies) => profile
+            .and_then(|x| policies.remove(x))
+            .or_else(|| policies.remove("*")),
+        Err(err) => {
+            log::error!("Invalid {}: {}", OPTION_FILE_TRANSFER_POLICY, err);
+            Some(Policy {
+                invalid: true,
+                ..Default::default()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let root = std::env::temp_dir().join("rustdesk_file_policy_test");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let policies: HashMap<String, Policy> = serde_json::from_value(serde_json::json!({
+            "support": {
+                "roots": [root.to_string_lossy()],
+                "blocked_extensions": [".EXE", "bat"],
+                "max_file_size": 100,
+                "mode": "upload-only"
+            }
+        }))
+        .unwrap();
+        let policy = &policies["support"];
+        let path = |x: &str| root.join(x).to_string_lossy().to_string();
+        assert!(policy.check_path(&path("sub")).is_ok());
+        assert!(policy.check_path(&path("sub/new/a.txt")).is_ok());
+        assert!(policy.check_path(&path("../x")).is_err());
+        assert!(policy
+            .check_path(&root.with_file_name("x").to_string_lossy())
+            .is_err());
+        assert!(policy.check_path("relative").is_err());
+        assert!(policy.check_file("a.txt", 100).is_ok());
+        assert!(policy.check_file("a.txt", 101).is_err());
+        assert!(policy.check_file("dir/A.exe", 1).is_err());
+        assert!(policy.check_file("exe", 1).is_ok());
+        assert!(policy.check_upload().is_ok());
+        let entry = |name: &str, size| FileEntry {
+            name: name.to_owned(),
+            size,
+            ..Default::default()
+        };
+        assert!(policy
+            .check_transfer(
+                true,
+                &path("sub"),
+                &[entry("a.txt", 1), entry("b/c.txt", 2)]
+            )
+            .is_ok());
+        assert!(policy
+            .check_transfer(true, &path("sub"), &[entry("a.txt", 1), entry("b.bat", 2)])
+            .is_err());
+        assert!(policy
+            .check_transfer(true, &path("sub"), &[entry("../../a.txt", 1)])
+            .is_err());
+        assert!(policy.check_transfer(false, &path("sub"), &[]).is_err());
+        assert!(policy.check_download().is_err());
+        let invalid = Policy {
+            invalid: true,
+            ..Default::default()
+        };
+        assert!(invalid.check_path("/").is_err());
+        assert!(Policy::default().check_path("/").is_ok());
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
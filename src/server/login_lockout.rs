@@ -0,0 +1,322 @@
+//! Lockout of the addresses making wrong login attempts.
+//!
+//! Wrong passwords, 2FA codes and OS logins are counted per address, each on its own,
+//! and a success resets the count of its kind. The options:
+//!
+//! - `login-failure-minute-limit`, 6 by default: more failures in a minute are refused
+//!   until the next minute.
+//! - `login-failure-ban-limit`, 30 by default: the address is banned after more failures,
+//!   0 never bans.
+//! - `login-failure-backoff`, in seconds, 0 by default: after the second failure, the next
+//!   attempt is refused for this long, doubled after each more failure.
+//! - `login-failure-backoff-max`, in seconds, 3600 by default.
+//! - `login-ban-duration`, in seconds, 0 by default, which bans until the address is unbanned.
+//!
+//! A ban refuses all the kinds of attempts of the address. Bans are saved in
+//! `login_bans.json` of the config directory, so they are kept across restarts.
+
+use hbb_common::{config::Config, get_time, log};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, sync::Mutex};
+
+pub const OPTION_MINUTE_LIMIT: &str = "login-failure-minute-limit";
+pub const OPTION_BAN_LIMIT: &str = "login-failure-ban-limit";
+pub const OPTION_BACKOFF: &str = "login-failure-backoff";
+pub const OPTION_BACKOFF_MAX: &str = "login-failure-backoff-max";
+pub const OPTION_BAN_DURATION: &str = "login-ban-duration";
+
+const FILE_NAME: &str = "login_bans.json";
+
+lazy_static::lazy_static! {
+    static ref FAILURES: Mutex<HashMap<(Kind, String), Failures>> = Default::default();
+    // Loaded on first use.
+    static ref BANS: Mutex<Option<Vec<Ban>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Password,
+    TwoFactor,
+    OsLogin,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::TwoFactor => "2fa",
+            Self::OsLogin => "os-login",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Failures {
+    // minutes since epoch
+    minute: i64,
+    in_minute: u32,
+    total: u32,
+    // ms since epoch
+    last: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: String,
+    /// The kind of the attempts, see [`Kind::name`].
+    pub kind: String,
+    pub failures: u32,
+    /// ms since epoch
+    pub time: i64,
+    /// ms since epoch, 0 until unbanned.
+    pub until: i64,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} wrong {} attempts, banned at {}",
+            self.ip,
+            self.failures,
+            self.kind,
+            format_time(self.time)
+        )?;
+        if self.until > 0 {
+            write!(f, " until {}", format_time(self.until))?;
+        }
+        Ok(())
+    }
+}
+
+fn format_time(ms: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms)
+        .map(|x| {
+            x.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Allowed,
+    Banned,
+    MinuteLimit,
+    /// The seconds to wait.
+    Backoff(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Policy {
+    minute_limit: u32,
+    ban_limit: u32,
+    backoff: u64,
+    backoff_max: u64,
+    ban_duration: u64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            minute_limit: 6,
+            ban_limit: 30,
+            backoff: 0,
+            backoff_max: 3600,
+            ban_duration: 0,
+        }
+    }
+}
+
+impl Policy {
+    fn load() -> Self {
+        let d = Self::default();
+        fn get<T: std::str::FromStr>(name: &str, default: T) -> T {
+            Config::get_option(name).trim().parse().unwrap_or(default)
+        }
+        Self {
+            minute_limit: get(OPTION_MINUTE_LIMIT, d.minute_limit),
+            ban_limit: get(OPTION_BAN_LIMIT, d.ban_limit),
+            backoff: get(OPTION_BACKOFF, d.backoff),
+            backoff_max: get(OPTION_BACKOFF_MAX, d.backoff_max),
+            ban_duration: get(OPTION_BAN_DURATION, d.ban_duration),
+        }
+    }
+
+    // `now` in ms since epoch
+    fn check(&self, failures: &Failures, now: i64) -> Check {
+        if failures.minute == now / 60_000 && failures.in_minute > self.minute_limit {
+            return Check::MinuteLimit;
+        }
+        if self.backoff > 0 && failures.total >= 2 {
+            let wait = self
+                .backoff
+                .saturating_mul(1 << (failures.total - 2).min(32))
+                .min(self.backoff_max);
+            let elapsed = (now - failures.last).max(0) as u64 / 1000;
+            if elapsed < wait {
+                return Check::Backoff(wait - elapsed);
+            }
+        }
+        Check::Allowed
+    }
+
+    fn is_ban(&self, failures: &Failures) -> bool {
+        self.ban_limit > 0 && failures.total > self.ban_limit
+    }
+}
+
+impl Failures {
+    fn add(&mut self, now: i64) {
+        let minute = now / 60_000;
+        if self.minute == minute {
+            self.in_minute += 1;
+        } else {
+            self.minute = minute;
+            self.in_minute = 1;
+        }
+        self.total += 1;
+        self.last = now;
+    }
+}
+
+fn with_bans<T>(f: impl FnOnce(&mut Vec<Ban>) -> T) -> T {
+    let mut lock = BANS.lock().unwrap();
+    let bans = lock.get_or_insert_with(|| {
+        fs::read_to_string(Config::path(FILE_NAME))
+            .ok()
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default()
+    });
+    let now = get_time();
+    bans.retain(|x| x.until == 0 || x.until > now);
+    f(bans)
+}
+
+fn save(bans: &[Ban]) {
+    let path = Config::path(FILE_NAME);
+    let tmp = path.with_extension("json.tmp");
+    let res = serde_json::to_string_pretty(bans)
+        .map_err(std::io::Error::from)
+        .and_then(|x| fs::write(&tmp, x))
+        .and_then(|_| fs::rename(&tmp, &path));
+    if let Err(err) = res {
+        log::error!("Failed to save {}: {}", path.display(), err);
+    }
+}
+
+/// Whether an attempt of `kind` from `ip` may be made.
+pub fn check(kind: Kind, ip: &str) -> Check {
+    if with_bans(|bans| bans.iter().any(|x| x.ip == ip)) {
+        return Check::Banned;
+    }
+    let failures = FAILURES
+        .lock()
+        .unwrap()
+        .get(&(kind, ip.to_owned()))
+        .cloned()
+        .unwrap_or_default();
+    Policy::load().check(&failures, get_time())
+}
+
+/// Count the result of an attempt, returns the ban if the address is banned by it.
+pub fn update(kind: Kind, ip: &str, success: bool) -> Option<Ban> {
+    let key = (kind, ip.to_owned());
+    let mut lock = FAILURES.lock().unwrap();
+    if success {
+        lock.remove(&key);
+        return None;
+    }
+    let now = get_time();
+    let failures = lock.entry(key).or_default();
+    failures.add(now);
+    let policy = Policy::load();
+    if !policy.is_ban(failures) {
+        return None;
+    }
+    let ban = Ban {
+        ip: ip.to_owned(),
+        kind: kind.name().to_owned(),
+        failures: failures.total,
+        time: now,
+        until: if policy.ban_duration > 0 {
+            now + policy.ban_duration as i64 * 1000
+        } else {
+            0
+        },
+    };
+    lock.retain(|k, _| k.1 != ip);
+    drop(lock);
+    log::warn!("Ban {}", ban);
+    with_bans(|bans| {
+        bans.retain(|x| x.ip != ip);
+        bans.push(ban.clone());
+        save(bans);
+    });
+    Some(ban)
+}
+
+pub fn list() -> Vec<Ban> {
+    with_bans(|bans| bans.clone())
+}
+
+/// Remove the ban of `ip`, or all bans if it is empty. Returns the number removed.
+pub fn unban(ip: &str) -> usize {
+    let n = with_bans(|bans| {
+        let n = bans.len();
+        bans.retain(|x| !ip.is_empty() && x.ip != ip);
+        let n = n - bans.len();
+        if n > 0 {
+            save(bans);
+        }
+        n
+    });
+    let mut lock = FAILURES.lock().unwrap();
+    lock.retain(|k, _| !ip.is_empty() && k.1 != ip);
+    if n > 0 {
+        log::info!("Unban {}", if ip.is_empty() { "all" } else { ip });
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy {
+            minute_limit: 3,
+            ban_limit: 5,
+            backoff: 10,
+            backoff_max: 25,
+            ban_duration: 0,
+        };
+        let mut f = Failures::default();
+        let mut now = 1_000_000_000;
+        f.add(now);
+        assert_eq!(policy.check(&f, now), Check::Allowed);
+        f.add(now);
+        assert_eq!(policy.check(&f, now + 4_000), Check::Backoff(6));
+        assert_eq!(policy.check(&f, now + 10_000), Check::Allowed);
+        now += 30_000;
+        f.add(now);
+        assert_eq!(policy.check(&f, now), Check::Backoff(20));
+        f.add(now);
+        // capped
+        assert_eq!(policy.check(&f, now), Check::Backoff(25));
+        assert!(!policy.is_ban(&f));
+        f.add(now);
+        f.add(now);
+        assert!(policy.is_ban(&f));
+
+        let policy = Policy::default();
+        let mut f = Failures::default();
+        for _ in 0..7 {
+            f.add(now);
+        }
+        assert_eq!(policy.check(&f, now), Check::MinuteLimit);
+        assert_eq!(policy.check(&f, now + 60_000), Check::Allowed);
+    }
+}
@@ -0,0 +1,377 @@
+//! Multiplexed port forwarding, the forwards of a rule file share one port forward connection.
+//!
+//! A `PortForward` login request with `host = "mux:"` starts it. After login, both sides send
+//! frames, `stream id (u32 BE) + kind (u8) + length (u16 BE) + payload`. The controlling side
+//! opens a stream with [`OPEN`], whose payload is the port (u16 BE) and the host of the target,
+//! with the `udp:` prefix for UDP, which is then carried as the frames of [`super::udp_forward`].
+//! The controlled side checks the target against the allowlist, the same as the target of a
+//! connection of its own, and answers with [`OPENED`], or [`CLOSE`] with the error. Either side
+//! closes a stream with [`CLOSE`].
+
+use super::{port_forward_policy, reverse_forward, udp_forward};
+use hbb_common::{
+    bail, log, timeout,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::mpsc,
+    },
+    ResultType,
+};
+use std::{collections::HashMap, sync::Arc};
+
+pub const HOST: &str = "mux:";
+pub const OPEN: u8 = 0;
+pub const OPENED: u8 = 1;
+pub const DATA: u8 = 2;
+pub const CLOSE: u8 = 3;
+const MAX_STREAMS: usize = 256;
+const HEADER_LEN: usize = 7;
+const READ_SIZE: usize = 16 * 1024;
+
+/// `(stream id, kind, payload)`
+pub type Frame = (u32, u8, Vec<u8>);
+
+type OnDenied = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// If it is the host of a multiplexed `PortForward` login request.
+pub fn is_host(host: &str) -> bool {
+    host.starts_with(HOST)
+}
+
+pub fn encode_frame(stream: u32, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len().min(u16::MAX as usize);
+    let mut frame = Vec::with_capacity(HEADER_LEN + len);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&(len as u16).to_be_bytes());
+    frame.extend_from_slice(&payload[..len]);
+    frame
+}
+
+pub fn encode_target(host: &str, port: i32) -> Vec<u8> {
+    let mut target = (port as u16).to_be_bytes().to_vec();
+    target.extend_from_slice(host.as_bytes());
+    target
+}
+
+fn decode_target(target: &[u8]) -> Option<(String, i32)> {
+    if target.len() < 2 {
+        return None;
+    }
+    let port = u16::from_be_bytes([target[0], target[1]]) as i32;
+    let host = std::str::from_utf8(&target[2..]).ok()?;
+    Some((host.to_owned(), port))
+}
+
+/// Split the byte stream back into frames.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+        let stream = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]);
+        let kind = self.buf[4];
+        let len = u16::from_be_bytes([self.buf[5], self.buf[6]]) as usize;
+        if self.buf.len() < HEADER_LEN + len {
+            return None;
+        }
+        let payload = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        Some((stream, kind, payload))
+    }
+}
+
+/// Relay `sock` as the stream `id`, its data is sent to `tx` as frames, and the data received
+/// from `rx` is written to it. [`CLOSE`] is sent once either is closed.
+pub async fn pump(
+    id: u32,
+    sock: TcpStream,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: mpsc::UnboundedSender<Frame>,
+) {
+    let (mut reader, mut writer) = sock.into_split();
+    let mut buf = vec![0u8; READ_SIZE];
+    loop {
+        tokio::select! {
+            res = reader.read(&mut buf) => {
+                match res {
+                    Ok(n) if n > 0 => {
+                        if tx.send((id, DATA, buf[..n].to_vec())).is_err() {
+                            return;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            data = rx.recv() => {
+                let Some(data) = data else {
+                    break;
+                };
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    tx.send((id, CLOSE, vec![])).ok();
+}
+
+/// Serve the streams of a multiplexed forward, returns the socket to be used as the port forward
+/// socket of the connection. `on_denied` is called with the targets denied by the allowlist.
+pub async fn start(on_denied: impl Fn(&str) + Send + Sync + 'static) -> ResultType<TcpStream> {
+    let (control, inner) = reverse_forward::loopback_pair().await?;
+    let on_denied: OnDenied = Arc::new(on_denied);
+    tokio::spawn(async move {
+        log::info!("Multiplexed forward started");
+        if let Err(err) = serve(inner, on_denied).await {
+            log::error!("Multiplexed forward error: {}", err);
+        }
+        log::info!("Multiplexed forward closed");
+    });
+    Ok(control)
+}
+
+async fn serve(control: TcpStream, on_denied: OnDenied) -> ResultType<()> {
+    let (mut reader, mut writer) = control.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Frame>();
+    let mut streams: HashMap<u32, mpsc::UnboundedSender<Vec<u8>>> = HashMap::new();
+    let mut decoder = FrameDecoder::default();
+    let mut buf = vec![0u8; READ_SIZE];
+    loop {
+        tokio::select! {
+            res = reader.read(&mut buf) => {
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                decoder.feed(&buf[..n]);
+                while let Some((id, kind, payload)) = decoder.next_frame() {
+                    match kind {
+                        OPEN => {
+                            if streams.contains_key(&id) {
+                                log::warn!("Multiplexed stream {} is opened twice", id);
+                                continue;
+                            }
+                            if streams.len() >= MAX_STREAMS {
+                                let frame = encode_frame(id, CLOSE, b"Too many streams");
+                                writer.write_all(&frame).await?;
+                                continue;
+                            }
+                            let (data_tx, data_rx) = mpsc::unbounded_channel();
+                            // The data sent before it is connected waits in the channel.
+                            streams.insert(id, data_tx);
+                            tokio::spawn(open(id, payload, data_rx, tx.clone(), on_denied.clone()));
+                        }
+                        DATA => {
+                            if let Some(stream) = streams.get(&id) {
+                                stream.send(payload).ok();
+                            }
+                        }
+                        CLOSE => {
+                            streams.remove(&id);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Some((id, kind, payload)) = rx.recv() => {
+                // Nothing is sent for the streams closed by the peer.
+                let is_open = if kind == CLOSE {
+                    streams.remove(&id).is_some()
+                } else {
+                    streams.contains_key(&id)
+                };
+                if is_open {
+                    writer.write_all(&encode_frame(id, kind, &payload)).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn open(
+    id: u32,
+    target: Vec<u8>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: mpsc::UnboundedSender<Frame>,
+    on_denied: OnDenied,
+) {
+    match connect(&target, &on_denied).await {
+        Ok(sock) => {
+            tx.send((id, OPENED, vec![])).ok();
+            pump(id, sock, rx, tx).await;
+        }
+        Err(err) => {
+            log::warn!("Failed to open multiplexed stream {}: {}", id, err);
+            tx.send((id, CLOSE, err.to_string().into_bytes())).ok();
+        }
+    }
+}
+
+async fn connect(target: &[u8], on_denied: &OnDenied) -> ResultType<TcpStream> {
+    let Some((host, port)) = decode_target(target) else {
+        bail!("Invalid target");
+    };
+    if reverse_forward::parse_host(&host).is_some() || is_host(&host) {
+        bail!("Unsupported target {}", host);
+    }
+    let udp = udp_forward::parse_host(&host);
+    let host = udp.unwrap_or(&host);
+    let host = if host.is_empty() { "localhost" } else { host };
+    let addr = format!("{}:{}", host, port);
+    let allowed = match port_forward_policy::check(host, port).await {
+        port_forward_policy::Check::Unrestricted => vec![],
+        port_forward_policy::Check::Allowed(allowed) => allowed,
+        port_forward_policy::Check::Denied => {
+            on_denied(&addr);
+            bail!("Forwarding to {} is not allowed by the remote side", addr);
+        }
+    };
+    if udp.is_some() {
+        let addr = allowed.first().map_or(addr, |x| x.to_string());
+        return udp_forward::start(&addr).await;
+    }
+    let connect = async {
+        if allowed.is_empty() {
+            TcpStream::connect(&addr).await
+        } else {
+            TcpStream::connect(&allowed[..]).await
+        }
+    };
+    match timeout(3000, connect).await {
+        Ok(Ok(sock)) => Ok(sock),
+        _ => bail!(
+            "Failed to access remote {}, please make sure if it is open",
+            addr
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::net::TcpListener;
+    use std::time::Duration;
+
+    async fn next_frame(control: &mut TcpStream, decoder: &mut FrameDecoder) -> Frame {
+        let mut buf = [0u8; 1024];
+        loop {
+            if let Some(frame) = decoder.next_frame() {
+                return frame;
+            }
+            let n = tokio::time::timeout(Duration::from_secs(5), control.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            decoder.feed(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut decoder = FrameDecoder::default();
+        let mut stream = encode_frame(1, OPEN, &encode_target("udp:10.0.0.1", 53));
+        stream.extend(encode_frame(1, CLOSE, b""));
+        decoder.feed(&stream[..5]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.feed(&stream[5..]);
+        let (id, kind, target) = decoder.next_frame().unwrap();
+        assert_eq!((id, kind), (1, OPEN));
+        assert_eq!(
+            decode_target(&target),
+            Some(("udp:10.0.0.1".to_owned(), 53))
+        );
+        assert_eq!(decoder.next_frame(), Some((1, CLOSE, vec![])));
+        assert_eq!(decoder.next_frame(), None);
+        assert!(is_host("mux:") && !is_host("udp:mux:"));
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        // An echo server.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as i32;
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = sock.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.ok();
+                });
+            }
+        });
+
+        let mut control = start(|_| {}).await.unwrap();
+        let mut decoder = FrameDecoder::default();
+        for id in [1, 2] {
+            let target = encode_target("127.0.0.1", port);
+            control
+                .write_all(&encode_frame(id, OPEN, &target))
+                .await
+                .unwrap();
+        }
+        control
+            .write_all(&encode_frame(
+                3,
+                OPEN,
+                &encode_target("reverse-accept:x", 1),
+            ))
+            .await
+            .unwrap();
+        let mut opened = vec![];
+        let mut closed = vec![];
+        while opened.len() + closed.len() < 3 {
+            match next_frame(&mut control, &mut decoder).await {
+                (id, OPENED, _) => opened.push(id),
+                (id, CLOSE, _) => closed.push(id),
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+        opened.sort();
+        assert_eq!((opened, closed), (vec![1, 2], vec![3]));
+
+        control
+            .write_all(&encode_frame(2, DATA, b"b"))
+            .await
+            .unwrap();
+        control
+            .write_all(&encode_frame(1, DATA, b"a"))
+            .await
+            .unwrap();
+        let mut echoed = vec![
+            next_frame(&mut control, &mut decoder).await,
+            next_frame(&mut control, &mut decoder).await,
+        ];
+        echoed.sort();
+        assert_eq!(
+            echoed,
+            vec![(1, DATA, b"a".to_vec()), (2, DATA, b"b".to_vec())]
+        );
+
+        // Closing a stream leaves the others open.
+        control
+            .write_all(&encode_frame(1, CLOSE, b""))
+            .await
+            .unwrap();
+        control
+            .write_all(&encode_frame(2, DATA, b"c"))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_frame(&mut control, &mut decoder).await,
+            (2, DATA, b"c".to_vec())
+        );
+    }
+}
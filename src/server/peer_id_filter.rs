@@ -0,0 +1,84 @@
+//! Which peer IDs may connect, by the ID the peer logs in with rather than its IP,
+//! which is the relay's when the connection is relayed.
+//!
+//! The patterns are in the `peer-id-allowlist` and `peer-id-denylist` options, separated by
+//! commas or new lines, e.g. `123456789, 88*, team-?-*`. `*` matches any characters, and `?`
+//! matches one, case insensitive. A peer matching the deny list is refused. Otherwise, if the
+//! allow list is not empty, only the peers matching it are accepted.
+
+use crate::glob;
+use hbb_common::config::Config;
+
+pub const OPTION_PEER_ID_ALLOWLIST: &str = "peer-id-allowlist";
+pub const OPTION_PEER_ID_DENYLIST: &str = "peer-id-denylist";
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Allowed,
+    /// With the pattern of the deny list, empty if not in the allow list.
+    Denied(String),
+}
+
+fn patterns(option: &str) -> Vec<String> {
+    option
+        .split(|c| c == ',' || c == '\n')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn check_with(allowlist: &str, denylist: &str, id: &str) -> Check {
+    let id = id.trim().to_lowercase();
+    let is_match = |pattern: &String| glob::matches(pattern, &id, None);
+    if let Some(pattern) = patterns(denylist).iter().find(|x| is_match(x)) {
+        return Check::Denied(pattern.clone());
+    }
+    let allowlist = patterns(allowlist);
+    if allowlist.is_empty() || allowlist.iter().any(is_match) {
+        Check::Allowed
+    } else {
+        Check::Denied("".to_owned())
+    }
+}
+
+pub fn check(id: &str) -> Check {
+    check_with(
+        &Config::get_option(OPTION_PEER_ID_ALLOWLIST),
+        &Config::get_option(OPTION_PEER_ID_DENYLIST),
+        id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check_with("", "", "123"), Check::Allowed);
+        let allow = "123456789, 88*\nteam-?-*";
+        assert_eq!(check_with(allow, "", "123456789"), Check::Allowed);
+        assert_eq!(
+            check_with(allow, "", "12345678"),
+            Check::Denied("".to_owned())
+        );
+        assert_eq!(check_with(allow, "", "88"), Check::Allowed);
+        assert_eq!(check_with(allow, "", "881234"), Check::Allowed);
+        assert_eq!(check_with(allow, "", "Team-A-pc"), Check::Allowed);
+        assert_eq!(
+            check_with(allow, "", "team-ab-pc"),
+            Check::Denied("".to_owned())
+        );
+        assert_eq!(check_with(allow, "", ""), Check::Denied("".to_owned()));
+        assert_eq!(
+            check_with(allow, "8812*", "881234"),
+            Check::Denied("8812*".to_owned())
+        );
+        assert_eq!(check_with("", "*", "1"), Check::Denied("*".to_owned()));
+        let id = "a".repeat(5000);
+        assert_eq!(
+            check_with("", "*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b", &id),
+            Check::Allowed
+        );
+    }
+}
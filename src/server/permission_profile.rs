@@ -0,0 +1,320 @@
+//! Permission profiles of the incoming sessions.
+//!
+//! The profiles are in the `permission-profiles` option, in JSON:
+//!
+//! ```json
+//! {
+//!     "profiles": { "helpdesk": { "remote": true, "keyboard": true, "clipboard": true } },
+//!     "default": "support",
+//!     "passwords": { "permanent": "admin", "temporary": "support" },
+//!     "peers": { "123456789": "file-only" },
+//!     "groups": { "auditors": { "peers": ["987654321"], "profile": "view-only" } }
+//! }
+//! ```
+//!
+//! `view-only`, `support`, `file-only` and `admin` are built in, the toggles missing in a
+//! custom profile are off. The profile of a session is the one of the password, or the default
+//! if the password has none, narrowed by the ones of the peer ID and of the groups having it:
+//! only the toggles on in all of them are on. The peer ID is claimed by the peer and not
+//! verified, so it can only take permissions away, never add some to the password's.
+//! The groups are the lists of peer IDs in this option, not the address book groups, which
+//! belong to the account of the controlling side and are unknown here.
+//! A profile only takes away the permissions enabled by the global options, it never adds one.
+//! An empty option applies no profile.
+//!
+//! A session logged in with the view-only password always has the `view-only` profile.
+//! The name of the profile is reported to the peer in `permission_profile` of the platform
+//! additions of the peer info, and the permissions it takes away with `PermissionInfo`.
+
+use hbb_common::{
+    config::{load_path, store_path, Config, ENCRYPT_MAX_LEN},
+    log,
+    password_security::{decrypt_str_or_original, encrypt_str_or_original},
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+pub const OPTION_PERMISSION_PROFILES: &str = "permission-profiles";
+pub const VIEW_ONLY: &str = "view-only";
+pub const SUPPORT: &str = "support";
+pub const FILE_ONLY: &str = "file-only";
+pub const ADMIN: &str = "admin";
+
+/// The password a session is logged in with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordKind {
+    Temporary,
+    Permanent,
+    ViewOnly,
+}
+
+impl PasswordKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Temporary => "temporary",
+            Self::Permanent => "permanent",
+            Self::ViewOnly => "view-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// Connection types.
+    pub remote: bool,
+    pub camera: bool,
+    pub terminal: bool,
+    pub tunnel: bool,
+    /// Permissions in the session, `file` is file transfer too.
+    pub keyboard: bool,
+    pub clipboard: bool,
+    pub audio: bool,
+    pub file: bool,
+    pub restart: bool,
+    pub recording: bool,
+    pub block_input: bool,
+}
+
+impl Profile {
+    pub fn builtin(name: &str) -> Option<Self> {
+        let all = Self {
+            remote: true,
+            camera: true,
+            terminal: true,
+            tunnel: true,
+            keyboard: true,
+            clipboard: true,
+            audio: true,
+            file: true,
+            restart: true,
+            recording: true,
+            block_input: true,
+        };
+        match name {
+            VIEW_ONLY => Some(Self {
+                remote: true,
+                camera: true,
+                audio: true,
+                ..Default::default()
+            }),
+            SUPPORT => Some(Self {
+                terminal: false,
+                tunnel: false,
+                ..all
+            }),
+            FILE_ONLY => Some(Self {
+                file: true,
+                ..Default::default()
+            }),
+            ADMIN => Some(all),
+            _ => None,
+        }
+    }
+
+    /// The toggles on in both.
+    pub fn intersect(&self, other: &Self) -> Self {
+        Self {
+            remote: self.remote && other.remote,
+            camera: self.camera && other.camera,
+            terminal: self.terminal && other.terminal,
+            tunnel: self.tunnel && other.tunnel,
+            keyboard: self.keyboard && other.keyboard,
+            clipboard: self.clipboard && other.clipboard,
+            audio: self.audio && other.audio,
+            file: self.file && other.file,
+            restart: self.restart && other.restart,
+            recording: self.recording && other.recording,
+            block_input: self.block_input && other.block_input,
+        }
+    }
+
+    /// Whether the permission of `Data::SwitchPermission` is allowed.
+    pub fn allows(&self, permission: &str) -> bool {
+        match permission {
+            "keyboard" => self.keyboard,
+            "clipboard" => self.clipboard,
+            "audio" => self.audio,
+            "file" => self.file,
+            "restart" => self.restart,
+            "recording" => self.recording,
+            "block_input" => self.block_input,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Group {
+    peers: Vec<String>,
+    profile: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Settings {
+    profiles: HashMap<String, Profile>,
+    default: String,
+    passwords: HashMap<String, String>,
+    peers: HashMap<String, String>,
+    groups: HashMap<String, Group>,
+}
+
+impl Settings {
+    fn select(&self, peer_id: &str, password: Option<PasswordKind>) -> (String, Profile) {
+        let base = password
+            .and_then(|p| self.passwords.get(p.name()))
+            .map(|x| x.as_str())
+            .unwrap_or(if self.default.is_empty() {
+                ADMIN
+            } else {
+                &self.default
+            });
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(b.0));
+        let narrowing = self.peers.get(peer_id).into_iter().chain(
+            groups
+                .into_iter()
+                .filter(|(_, g)| g.peers.iter().any(|x| x == peer_id))
+                .map(|(_, g)| &g.profile),
+        );
+        let (mut names, mut profile) = {
+            let (name, profile) = self.get(base);
+            (vec![name], profile)
+        };
+        for name in narrowing {
+            let (name, other) = self.get(name);
+            profile = profile.intersect(&other);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        (names.join("+"), profile)
+    }
+
+    fn get<'a>(&self, name: &'a str) -> (&'a str, Profile) {
+        match self
+            .profiles
+            .get(name)
+            .copied()
+            .or_else(|| Profile::builtin(name))
+        {
+            Some(profile) => (name, profile),
+            None => {
+                log::error!("Unknown permission profile {}, use {}", name, VIEW_ONLY);
+                (VIEW_ONLY, Profile::builtin(VIEW_ONLY).unwrap_or_default())
+            }
+        }
+    }
+}
+
+// Encrypted like the permanent password, and like it kept out of the options, which are sent
+// to the processes of the user over IPC.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ViewOnlyPassword {
+    password: String,
+}
+
+fn view_only_password_path() -> PathBuf {
+    Config::path("view_only_password.toml")
+}
+
+/// The second permanent password, which only grants view-only sessions.
+pub fn view_only_password() -> String {
+    let stored = load_path::<ViewOnlyPassword>(view_only_password_path());
+    decrypt_str_or_original(&stored.password, "00").0
+}
+
+/// An empty password disables it.
+pub fn set_view_only_password(password: &str) {
+    let password = if password.is_empty() {
+        "".to_owned()
+    } else {
+        encrypt_str_or_original(password, "00", ENCRYPT_MAX_LEN)
+    };
+    if let Err(err) = store_path(view_only_password_path(), ViewOnlyPassword { password }) {
+        log::error!("Failed to store the view-only password: {}", err);
+    }
+}
+
+/// The profile name and the profile of a session, `None` if no profile is configured.
+pub fn select(peer_id: &str, password: Option<PasswordKind>) -> Option<(String, Profile)> {
+    if password == Some(PasswordKind::ViewOnly) {
+        return Some((VIEW_ONLY.to_owned(), Profile::builtin(VIEW_ONLY)?));
+    }
+    let option = Config::get_option(OPTION_PERMISSION_PROFILES);
+    if option.trim().is_empty() {
+        return None;
+    }
+    let settings = match serde_json::from_str::<Settings>(&option) {
+        Ok(settings) => settings,
+        Err(err) => {
+            // Misconfigured, do not fall back to the full permissions.
+            log::error!("Invalid {}: {}", OPTION_PERMISSION_PROFILES, err);
+            return Some((VIEW_ONLY.to_owned(), Profile::builtin(VIEW_ONLY)?));
+        }
+    };
+    Some(settings.select(peer_id, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "profiles": { "helpdesk": { "remote": true, "keyboard": true } },
+                "default": "support",
+                "passwords": { "permanent": "admin", "temporary": "helpdesk" },
+                "peers": { "1": "file-only", "2": "nothing" },
+                "groups": { "auditors": { "peers": ["1", "3"], "profile": "view-only" } }
+            }"#,
+        )
+        .unwrap();
+        let select = |id, password| settings.select(id, password).0;
+        assert_eq!(
+            select("1", Some(PasswordKind::Permanent)),
+            "admin+file-only+view-only"
+        );
+        assert_eq!(select("2", None), "support+view-only");
+        assert_eq!(
+            select("3", Some(PasswordKind::Permanent)),
+            "admin+view-only"
+        );
+        assert_eq!(select("4", Some(PasswordKind::Permanent)), ADMIN);
+        assert_eq!(select("4", None), SUPPORT);
+        let (name, profile) = settings.select("4", Some(PasswordKind::Temporary));
+        assert_eq!(name, "helpdesk");
+        assert!(profile.keyboard && !profile.clipboard && !profile.file);
+        assert_eq!(
+            settings.select("1", Some(PasswordKind::Permanent)).1,
+            Profile::default()
+        );
+        assert_eq!(
+            settings.select("3", Some(PasswordKind::Permanent)).1,
+            Profile::builtin(VIEW_ONLY).unwrap()
+        );
+
+        // A peer claiming the ID of a peer mapped to a wider profile gets no more than its
+        // password's.
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "passwords": { "permanent": "admin", "temporary": "view-only" },
+                "peers": { "1": "admin" },
+                "groups": { "admins": { "peers": ["1"], "profile": "admin" } }
+            }"#,
+        )
+        .unwrap();
+        let (name, profile) = settings.select("1", Some(PasswordKind::Temporary));
+        assert_eq!(name, "view-only+admin");
+        assert_eq!(profile, Profile::builtin(VIEW_ONLY).unwrap());
+        assert!(!profile.allows("keyboard") && !profile.file);
+        assert_eq!(settings.select("1", Some(PasswordKind::Permanent)).0, ADMIN);
+        assert!(!Profile::builtin(VIEW_ONLY).unwrap().allows("keyboard"));
+        assert!(!Profile::builtin(SUPPORT).unwrap().terminal);
+    }
+}
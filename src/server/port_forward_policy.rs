@@ -0,0 +1,209 @@
+//! Which destinations the peer may forward ports to.
+//!
+//! The rules are in the `port-forward-allowlist` option, separated by commas or new lines,
+//! e.g. `10.0.0.0/8:22, db.lan:5432-5433, [fd00::/8]:80, *.example.com, *:443`.
+//! A rule is `<target>[:<port>[-<port>]]`, the target is `*`, an IP address, a CIDR,
+//! a host name, or `*.<domain>` for its sub domains. IPv6 targets with a port must be in brackets.
+//! All ports are allowed if it is not given.
+//!
+//! An empty option allows everything. Otherwise only the destinations matching one of the rules
+//! are allowed, the invalid rules are ignored.
+
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{config::Config, log, tokio::net::lookup_host};
+use std::net::SocketAddr;
+
+pub const OPTION_PORT_FORWARD_ALLOWLIST: &str = "port-forward-allowlist";
+
+#[derive(Debug)]
+enum Target {
+    Any,
+    Cidr(IpCidr),
+    // lowercase, `*.` for sub domains
+    Host(String),
+}
+
+#[derive(Debug)]
+struct Rule {
+    target: Target,
+    ports: (u16, u16),
+}
+
+impl Rule {
+    fn parse(s: &str) -> Option<Self> {
+        let (target, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (target, rest) = rest.split_once(']')?;
+            match rest {
+                "" => (target, None),
+                _ => (target, Some(rest.strip_prefix(':')?)),
+            }
+        } else {
+            match s.rsplit_once(':') {
+                // A bare IPv6 address or CIDR
+                Some((target, _)) if target.contains(':') => (s, None),
+                Some((target, ports)) => (target, Some(ports)),
+                None => (s, None),
+            }
+        };
+        let ports = match ports {
+            None => (0, u16::MAX),
+            Some(ports) => match ports.split_once('-') {
+                Some((a, b)) => (a.trim().parse().ok()?, b.trim().parse().ok()?),
+                None => {
+                    let port = ports.trim().parse().ok()?;
+                    (port, port)
+                }
+            },
+        };
+        if ports.0 > ports.1 {
+            return None;
+        }
+        let target = target.trim();
+        let target = if target == "*" {
+            Target::Any
+        } else if let Ok(cidr) = IpCidr::from_str(target) {
+            Target::Cidr(cidr)
+        } else if !target.is_empty()
+            && !target.contains('/')
+            && !target.contains(':')
+            && target.trim_start_matches("*.").find('*').is_none()
+        {
+            Target::Host(target.to_lowercase())
+        } else {
+            return None;
+        };
+        Some(Self { target, ports })
+    }
+
+    fn match_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn match_host(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        match &self.target {
+            Target::Any => true,
+            Target::Host(h) => match h.strip_prefix("*.") {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => *h == host,
+            },
+            Target::Cidr(_) => false,
+        }
+    }
+
+    fn match_addr(&self, addr: &SocketAddr) -> bool {
+        match &self.target {
+            Target::Any => true,
+            Target::Cidr(cidr) => cidr.contains(addr.ip()),
+            Target::Host(_) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Self {
+        let rules = s
+            .split(|c| c == ',' || c == '\n')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .filter_map(|x| {
+                let rule = Rule::parse(x);
+                if rule.is_none() {
+                    log::warn!("Invalid port forward allowlist rule: {}", x);
+                }
+                rule
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// `None` if port forwarding is not restricted.
+    pub fn load() -> Option<Self> {
+        let s = Config::get_option(OPTION_PORT_FORWARD_ALLOWLIST);
+        if s.trim().is_empty() {
+            return None;
+        }
+        Some(Self::parse(&s))
+    }
+
+    /// Keep the resolved addresses of `host:port` which are allowed.
+    ///
+    /// All of them are allowed if the host name is allowed, otherwise they are checked one by one,
+    /// so that the allowed addresses are connected instead of resolving the host again.
+    pub fn filter(&self, host: &str, port: u16, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let rules: Vec<&Rule> = self.rules.iter().filter(|r| r.match_port(port)).collect();
+        if rules.iter().any(|r| r.match_host(host)) {
+            return addrs;
+        }
+        addrs
+            .into_iter()
+            .filter(|addr| rules.iter().any(|r| r.match_addr(addr)))
+            .collect()
+    }
+}
+
+pub enum Check {
+    Unrestricted,
+    Allowed(Vec<SocketAddr>),
+    Denied,
+}
+
+/// Check `host:port` against the allowlist.
+pub async fn check(host: &str, port: i32) -> Check {
+    let Some(policy) = Policy::load() else {
+        return Check::Unrestricted;
+    };
+    let Ok(port) = u16::try_from(port) else {
+        return Check::Denied;
+    };
+    let addrs = match lookup_host((host, port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(err) => {
+            log::error!("Failed to resolve {}: {}", host, err);
+            vec![]
+        }
+    };
+    let allowed = policy.filter(host, port, addrs);
+    if allowed.is_empty() {
+        Check::Denied
+    } else {
+        Check::Allowed(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(policy: &str, host: &str, port: u16, ip: &str) -> bool {
+        let addr = SocketAddr::new(ip.parse().unwrap(), port);
+        !Policy::parse(policy)
+            .filter(host, port, vec![addr])
+            .is_empty()
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy =
+            "10.0.0.0/8:22, db.lan:5432-5433\n[fd00::/8]:80, *.example.com, 192.168.1.1, bad:x";
+        assert!(allowed(policy, "10.1.2.3", 22, "10.1.2.3"));
+        assert!(!allowed(policy, "10.1.2.3", 23, "10.1.2.3"));
+        assert!(allowed(policy, "DB.lan", 5433, "172.16.0.1"));
+        assert!(!allowed(policy, "db.lan", 5434, "172.16.0.1"));
+        assert!(allowed(policy, "fd00::1", 80, "fd00::1"));
+        assert!(allowed(policy, "a.example.com", 1, "1.1.1.1"));
+        assert!(!allowed(policy, "example.com", 1, "1.1.1.1"));
+        assert!(allowed(policy, "192.168.1.1", 3389, "192.168.1.1"));
+        // The resolved address is checked against the CIDR rules.
+        assert!(allowed(policy, "jump.lan", 22, "10.0.0.1"));
+        assert!(!allowed(policy, "bad", 1, "8.8.8.8"));
+        assert!(allowed("*:443", "any.host", 443, "8.8.8.8"));
+        assert!(!allowed("*:443", "any.host", 80, "8.8.8.8"));
+        assert!(!allowed("invalid:rule:x", "any.host", 80, "8.8.8.8"));
+    }
+}
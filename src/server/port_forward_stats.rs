@@ -0,0 +1,232 @@
+//! Traffic accounting of port forwarding, on both sides.
+//!
+//! A forward rule, or a forward target on the controlled side, has one [`TunnelStats`],
+//! and each connection through it has its own, whose counts are added to the rule too.
+//! `bytes_in` is received from the other side of the tunnel, and `bytes_out` is sent to it.
+//!
+//! The stats of a target are dropped once it has no connection for [`TARGET_IDLE_TIMEOUT`],
+//! or earlier if there are more than [`MAX_TARGETS`] targets.
+
+use hbb_common::get_time;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Instant,
+};
+
+/// In ms.
+pub const TARGET_IDLE_TIMEOUT: i64 = 3_600_000;
+pub const MAX_TARGETS: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref LIVE: Mutex<Vec<Weak<TunnelStats>>> = Default::default();
+    // target -> stats, of the controlled side
+    static ref TARGETS: Mutex<HashMap<String, Arc<TunnelStats>>> = Default::default();
+}
+
+#[derive(Debug)]
+pub struct TunnelStats {
+    name: String,
+    parent: Option<Arc<TunnelStats>>,
+    started: Instant,
+    // ms since epoch
+    start_time: i64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connections: AtomicU64,
+    active: AtomicU64,
+    errors: AtomicU64,
+    // ms since epoch, when a connection was opened or closed
+    last_active: AtomicI64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// The name of the rule of a connection, empty for a rule.
+    pub rule: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub connections: u64,
+    pub active: u64,
+    pub errors: u64,
+    /// ms since epoch
+    pub start_time: i64,
+    /// In seconds.
+    pub duration: u64,
+}
+
+impl TunnelStats {
+    /// Stats of a rule, which is listed by [`live`] until it is dropped.
+    pub fn new(name: String) -> Arc<Self> {
+        Self::new_with_parent(name, None)
+    }
+
+    fn new_with_parent(name: String, parent: Option<Arc<TunnelStats>>) -> Arc<Self> {
+        let stats = Arc::new(Self {
+            name,
+            parent,
+            started: Instant::now(),
+            start_time: get_time(),
+            bytes_in: Default::default(),
+            bytes_out: Default::default(),
+            connections: Default::default(),
+            active: Default::default(),
+            errors: Default::default(),
+            last_active: AtomicI64::new(get_time()),
+        });
+        let mut live = LIVE.lock().unwrap();
+        live.retain(|x| x.strong_count() > 0);
+        live.push(Arc::downgrade(&stats));
+        stats
+    }
+
+    /// Stats of a new connection of this rule, it is active until dropped.
+    pub fn new_connection(self: &Arc<Self>, name: String) -> Arc<Self> {
+        for stats in self.chain() {
+            stats.connections.fetch_add(1, Ordering::Relaxed);
+            stats.active.fetch_add(1, Ordering::Relaxed);
+            stats.last_active.store(get_time(), Ordering::Relaxed);
+        }
+        Self::new_with_parent(name, Some(self.clone()))
+    }
+
+    fn chain(&self) -> impl Iterator<Item = &TunnelStats> {
+        std::iter::successors(Some(self), |x| x.parent.as_deref())
+    }
+
+    pub fn add_in(&self, n: usize) {
+        for stats in self.chain() {
+            stats.bytes_in.fetch_add(n as _, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_out(&self, n: usize) {
+        for stats in self.chain() {
+            stats.bytes_out.fetch_add(n as _, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_error(&self) {
+        for stats in self.chain() {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count an error if `res` is one.
+    pub fn check<T, E>(&self, res: Result<T, E>) -> Result<T, E> {
+        if res.is_err() {
+            self.add_error();
+        }
+        res
+    }
+
+    // No connection for `timeout` ms.
+    fn is_idle(&self, timeout: i64) -> bool {
+        self.active.load(Ordering::Relaxed) == 0
+            && get_time() - self.last_active.load(Ordering::Relaxed) >= timeout
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            name: self.name.clone(),
+            rule: self
+                .parent
+                .as_ref()
+                .map(|x| x.name.clone())
+                .unwrap_or_default(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            start_time: self.start_time,
+            duration: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+impl Drop for TunnelStats {
+    fn drop(&mut self) {
+        if let Some(parent) = self.parent.as_ref() {
+            for stats in parent.chain() {
+                stats.active.fetch_sub(1, Ordering::Relaxed);
+                stats.last_active.store(get_time(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: in {} bytes, out {} bytes",
+            self.name, self.bytes_in, self.bytes_out
+        )?;
+        if self.rule.is_empty() {
+            write!(
+                f,
+                ", {} connections ({} active)",
+                self.connections, self.active
+            )?;
+        }
+        write!(f, ", {} errors, {}s", self.errors, self.duration)
+    }
+}
+
+/// The stats of the rules and connections alive in this process.
+pub fn live() -> Vec<Snapshot> {
+    LIVE.lock()
+        .unwrap()
+        .iter()
+        .filter_map(|x| x.upgrade())
+        .map(|x| x.snapshot())
+        .collect()
+}
+
+/// Stats of a connection to `target` of the controlled side, its counts are added to the
+/// stats of `target`, which are kept until it is idle.
+pub fn new_target_connection(target: &str, name: String) -> Arc<TunnelStats> {
+    let mut targets = TARGETS.lock().unwrap();
+    targets.retain(|_, x| !x.is_idle(TARGET_IDLE_TIMEOUT));
+    if targets.len() >= MAX_TARGETS && !targets.contains_key(target) {
+        targets.retain(|_, x| !x.is_idle(0));
+    }
+    let stats = targets
+        .entry(target.to_owned())
+        .or_insert_with(|| TunnelStats::new(target.to_owned()))
+        .clone();
+    stats.new_connection(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let rule = TunnelStats::new("test rule".to_owned());
+        let a = rule.new_connection("a".to_owned());
+        let b = rule.new_connection("b".to_owned());
+        a.add_in(10);
+        a.add_out(20);
+        b.add_in(1);
+        b.add_error();
+        drop(b);
+        let s = rule.snapshot();
+        assert_eq!((s.bytes_in, s.bytes_out), (11, 20));
+        assert_eq!((s.connections, s.active, s.errors), (2, 1, 1));
+        let s = a.snapshot();
+        assert_eq!((s.bytes_in, s.bytes_out, s.errors), (10, 20, 0));
+        assert_eq!(s.rule, "test rule");
+        let names: Vec<_> = live().into_iter().map(|x| x.name).collect();
+        assert!(names.contains(&"a".to_owned()));
+        assert!(!names.contains(&"b".to_owned()));
+    }
+}
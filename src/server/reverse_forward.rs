@@ -0,0 +1,172 @@
+//! Reverse port forwarding, the controlled side listens and every accepted connection is
+//! tunnelled back to the controlling side over a port forward connection of its own.
+//!
+//! It reuses the `PortForward` login request:
+//! - `host = "reverse-listen:<bind address>"`, `port = <port>` opens the listener once the
+//!   login is accepted, if the bind address is allowed by `port-forward-allowlist`. Then
+//!   every accepted connection is announced on this connection as a line `accept <token> <peer address>`.
+//!   The controlling side may send anything on it to keep it alive, it is ignored.
+//!   The listener is closed with this connection.
+//! - `host = "reverse-accept:<token>"` logs in another connection, which is forwarded to the
+//!   accepted connection of `token` instead of connecting to a host.
+
+use hbb_common::{
+    bail, log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const LISTEN_PREFIX: &str = "reverse-listen:";
+const ACCEPT_PREFIX: &str = "reverse-accept:";
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+// Accepted connections not claimed in time are closed, by a timer of each.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    // token -> (accepted connection, description, accepted at)
+    static ref PENDING: Mutex<HashMap<String, (TcpStream, String, Instant)>> = Default::default();
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Request<'a> {
+    Listen(&'a str),
+    Accept(&'a str),
+}
+
+pub fn listen_host(bind: &str) -> String {
+    format!("{}{}", LISTEN_PREFIX, bind)
+}
+
+pub fn accept_host(token: &str) -> String {
+    format!("{}{}", ACCEPT_PREFIX, token)
+}
+
+/// Parse the host of a `PortForward` login request, `None` for a normal port forward.
+pub fn parse_host(host: &str) -> Option<Request> {
+    if let Some(bind) = host.strip_prefix(LISTEN_PREFIX) {
+        Some(Request::Listen(if bind.is_empty() {
+            DEFAULT_BIND_ADDRESS
+        } else {
+            bind
+        }))
+    } else {
+        host.strip_prefix(ACCEPT_PREFIX).map(Request::Accept)
+    }
+}
+
+/// Parse an `accept <token> <peer address>` line of the control connection.
+pub fn parse_accept_line(line: &str) -> Option<(&str, &str)> {
+    let mut it = line.trim_end().splitn(3, ' ');
+    match (it.next(), it.next(), it.next()) {
+        (Some("accept"), Some(token), addr) if !token.is_empty() => {
+            Some((token, addr.unwrap_or_default()))
+        }
+        _ => None,
+    }
+}
+
+/// Listen on `addr`, returns the socket to be used as the port forward socket of the control connection.
+pub async fn start_listen(addr: &str) -> ResultType<TcpStream> {
+    let listener = TcpListener::bind(addr).await?;
+    let (control, inner) = loopback_pair().await?;
+    let addr = addr.to_owned();
+    tokio::spawn(async move {
+        log::info!("Reverse forward listening on {}", addr);
+        if let Err(err) = serve(listener, inner, &addr).await {
+            log::error!("Reverse forward on {} error: {}", addr, err);
+        }
+        log::info!("Reverse forward on {} closed", addr);
+    });
+    Ok(control)
+}
+
+/// Take the accepted connection of `token`, with its description.
+pub fn take_pending(token: &str) -> Option<(TcpStream, String)> {
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, (_, _, t)| t.elapsed() < PENDING_TIMEOUT);
+    pending.remove(token).map(|(s, desc, _)| (s, desc))
+}
+
+/// A connected pair of loopback sockets, one is used as the port forward socket of a connection
+/// and the other is served by a task of its own.
+pub async fn loopback_pair() -> ResultType<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let a = TcpStream::connect(listener.local_addr()?).await?;
+    let (b, peer) = listener.accept().await?;
+    // Make sure nobody else connected first.
+    if peer != a.local_addr()? {
+        bail!("Unexpected loopback connection from {}", peer);
+    }
+    Ok((a, b))
+}
+
+async fn serve(listener: TcpListener, control: TcpStream, addr: &str) -> ResultType<()> {
+    let (mut reader, mut writer) = control.into_split();
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, peer) = res?;
+                let token = uuid::Uuid::new_v4().to_string();
+                log::info!("Reverse forward on {} accepted {}", addr, peer);
+                PENDING.lock().unwrap().insert(
+                    token.clone(),
+                    (stream, format!("{} <- {}", addr, peer), Instant::now()),
+                );
+                let pending_token = token.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(PENDING_TIMEOUT).await;
+                    if PENDING.lock().unwrap().remove(&pending_token).is_some() {
+                        log::info!("Reverse forwarded {} is not claimed in time, closed", peer);
+                    }
+                });
+                writer
+                    .write_all(format!("accept {} {}\n", token, peer).as_bytes())
+                    .await?;
+            }
+            res = reader.read(&mut buf) => {
+                // The control connection is closed.
+                if res? == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_host("localhost"), None);
+        assert_eq!(
+            parse_host(&listen_host("")),
+            Some(Request::Listen(DEFAULT_BIND_ADDRESS))
+        );
+        assert_eq!(
+            parse_host(&listen_host("0.0.0.0")),
+            Some(Request::Listen("0.0.0.0"))
+        );
+        assert_eq!(
+            parse_host(&accept_host("abc")),
+            Some(Request::Accept("abc"))
+        );
+        assert_eq!(
+            parse_accept_line("accept abc 1.2.3.4:5\n"),
+            Some(("abc", "1.2.3.4:5"))
+        );
+        assert_eq!(parse_accept_line("ping\n"), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! Limits of the concurrent sessions, and the queue of the sessions waiting for them.
+//!
+//! The options, 0 or empty is unlimited:
+//!
+//! - `max-sessions`: all sessions.
+//! - `max-sessions-per-peer`: the sessions of a peer ID.
+//! - `max-sessions-remote`, `max-sessions-file-transfer`, `max-sessions-port-forward`,
+//!   `max-sessions-view-camera` and `max-sessions-terminal`: the sessions of a type.
+//!
+//! A session over the limits is refused as busy, or, if `session-queue` is `Y`, waits in the
+//! queue and is accepted when it can be, before the sessions queued after it.
+
+use super::AuthConnType;
+use hbb_common::config::Config;
+use std::{collections::VecDeque, sync::Mutex};
+
+pub const OPTION_MAX_SESSIONS: &str = "max-sessions";
+pub const OPTION_MAX_SESSIONS_PER_PEER: &str = "max-sessions-per-peer";
+pub const OPTION_SESSION_QUEUE: &str = "session-queue";
+
+lazy_static::lazy_static! {
+    static ref QUEUE: Mutex<VecDeque<(i32, AuthConnType, String)>> = Default::default();
+}
+
+fn type_option(conn_type: AuthConnType) -> &'static str {
+    match conn_type {
+        AuthConnType::Remote => "max-sessions-remote",
+        AuthConnType::FileTransfer => "max-sessions-file-transfer",
+        AuthConnType::PortForward => "max-sessions-port-forward",
+        AuthConnType::ViewCamera => "max-sessions-view-camera",
+        AuthConnType::Terminal => "max-sessions-terminal",
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    total: usize,
+    per_peer: usize,
+    // (type, limit)
+    per_type: Vec<(AuthConnType, usize)>,
+}
+
+impl Limits {
+    fn load() -> Self {
+        let get = |name: &str| Config::get_option(name).trim().parse().unwrap_or(0);
+        Self {
+            total: get(OPTION_MAX_SESSIONS),
+            per_peer: get(OPTION_MAX_SESSIONS_PER_PEER),
+            per_type: [
+                AuthConnType::Remote,
+                AuthConnType::FileTransfer,
+                AuthConnType::PortForward,
+                AuthConnType::ViewCamera,
+                AuthConnType::Terminal,
+            ]
+            .into_iter()
+            .map(|t| (t, get(type_option(t))))
+            .filter(|(_, n)| *n > 0)
+            .collect(),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.total == 0 && self.per_peer == 0 && self.per_type.is_empty()
+    }
+
+    // `sessions` are the types and the peer IDs of the open sessions.
+    fn is_full(
+        &self,
+        sessions: &[(AuthConnType, String)],
+        conn_type: AuthConnType,
+        peer_id: &str,
+    ) -> bool {
+        let over = |limit: usize, n: usize| limit > 0 && n >= limit;
+        over(self.total, sessions.len())
+            || over(
+                self.per_peer,
+                sessions.iter().filter(|(_, id)| id == peer_id).count(),
+            )
+            || self.per_type.iter().any(|(t, limit)| {
+                *t == conn_type && over(*limit, sessions.iter().filter(|(x, _)| x == t).count())
+            })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Accepted,
+    Busy,
+    /// The position in the queue, from 1.
+    Queued(usize),
+}
+
+/// Whether the session `conn_id` can be accepted now, given the open `sessions`.
+/// It is queued if it can't, and the queue is enabled.
+pub fn check(
+    sessions: &[(AuthConnType, String)],
+    conn_id: i32,
+    conn_type: AuthConnType,
+    peer_id: &str,
+) -> Check {
+    let limits = Limits::load();
+    let mut queue = QUEUE.lock().unwrap();
+    if limits.is_unlimited() {
+        queue.retain(|x| x.0 != conn_id);
+        return Check::Accepted;
+    }
+    let pos = queue.iter().position(|x| x.0 == conn_id);
+    // The sessions queued before can go first.
+    let ahead = queue
+        .iter()
+        .take(pos.unwrap_or(queue.len()))
+        .any(|(_, t, id)| !limits.is_full(sessions, *t, id));
+    if !ahead && !limits.is_full(sessions, conn_type, peer_id) {
+        queue.retain(|x| x.0 != conn_id);
+        return Check::Accepted;
+    }
+    if Config::get_option(OPTION_SESSION_QUEUE) != "Y" {
+        queue.retain(|x| x.0 != conn_id);
+        return Check::Busy;
+    }
+    match pos {
+        Some(pos) => Check::Queued(pos + 1),
+        None => {
+            queue.push_back((conn_id, conn_type, peer_id.to_owned()));
+            Check::Queued(queue.len())
+        }
+    }
+}
+
+/// Remove the session `conn_id` from the queue, when it is closed.
+pub fn dequeue(conn_id: i32) {
+    QUEUE.lock().unwrap().retain(|x| x.0 != conn_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_full() {
+        let limits = Limits {
+            total: 3,
+            per_peer: 2,
+            per_type: vec![(AuthConnType::Remote, 1)],
+        };
+        let sessions = vec![(AuthConnType::Remote, "1".to_owned())];
+        assert!(limits.is_full(&sessions, AuthConnType::Remote, "2"));
+        assert!(!limits.is_full(&sessions, AuthConnType::FileTransfer, "1"));
+        let sessions = vec![
+            (AuthConnType::FileTransfer, "1".to_owned()),
+            (AuthConnType::Terminal, "1".to_owned()),
+        ];
+        assert!(limits.is_full(&sessions, AuthConnType::Remote, "1"));
+        assert!(!limits.is_full(&sessions, AuthConnType::Remote, "2"));
+        let sessions = vec![
+            (AuthConnType::FileTransfer, "1".to_owned()),
+            (AuthConnType::Terminal, "2".to_owned()),
+            (AuthConnType::Terminal, "3".to_owned()),
+        ];
+        assert!(limits.is_full(&sessions, AuthConnType::FileTransfer, "4"));
+        assert!(Limits::default().is_unlimited());
+    }
+}
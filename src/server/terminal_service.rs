@@ -24,6 +24,25 @@ const SERVICE_IDLE_TIMEOUT: Duration = Duration::from_secs(3600); // 1 hour idle
 const CHANNEL_BUFFER_SIZE: usize = 100; // Number of messages to buffer in channel
 const COMPRESS_THRESHOLD: usize = 512; // Compress terminal data larger than this
 
+// Exec requests run one command without a PTY, they reuse the terminal messages:
+// - `OpenTerminal` with `rows == 0 && cols == 0` opens an exec session.
+// - The first `TerminalData` is the command line, a json array of strings, the command is run
+//   directly without a shell. The following `TerminalData` are written to stdin,
+//   an empty one closes stdin.
+// - stdout is sent as `TerminalData` with the terminal id, stderr with `exec_stderr_id(terminal_id)`.
+// - `TerminalClosed.exit_code` is the exit code of the command, 128 + signal if it is killed by a signal.
+
+/// Whether `open` requests an exec session instead of an interactive terminal.
+pub fn is_exec_open(open: &OpenTerminal) -> bool {
+    open.rows == 0 && open.cols == 0
+}
+
+/// The terminal id that stderr of exec session `terminal_id` is sent with.
+/// Exec terminal ids must be non-negative, so that it never collides with them.
+pub fn exec_stderr_id(terminal_id: i32) -> i32 {
+    !terminal_id
+}
+
 lazy_static::lazy_static! {
     // Global registry of persistent terminal services indexed by service_id
     static ref TERMINAL_SERVICES: Arc<Mutex<HashMap<String, Arc<Mutex<PersistentTerminalService>>>>> =
@@ -153,6 +172,10 @@ fn remove_service(service_id: &str) {
             let mut session = session.lock().unwrap();
             session.stop();
         }
+        let execs = std::mem::take(&mut service.lock().unwrap().execs);
+        for (_, exec) in execs.iter() {
+            exec.lock().unwrap().stop();
+        }
     }
 }
 
@@ -343,6 +366,12 @@ fn run(sp: TerminalService, service_id: String) -> ResultType<()> {
         let should_remove = !service.lock().unwrap().is_persistent;
         if should_remove {
             remove_service(&service_id);
+        } else {
+            // Nobody can receive the output of the exec sessions any more.
+            let execs = std::mem::take(&mut service.lock().unwrap().execs);
+            for (_, exec) in execs.iter() {
+                exec.lock().unwrap().stop();
+            }
         }
     }
 
@@ -548,10 +577,152 @@ impl Drop for TerminalSession {
     }
 }
 
+/// A command started by an exec request, stdin/stdout/stderr are pipes.
+struct ExecSession {
+    child: Option<std::process::Child>,
+    // Channel for sending input to the stdin writer thread, dropped to close stdin
+    input_tx: Option<SyncSender<Vec<u8>>>,
+    // (is_stderr, data) from the stdout/stderr reader threads
+    output_rx: Option<Receiver<(bool, Vec<u8>)>>,
+    reader_threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ExecSession {
+    fn new() -> Self {
+        Self {
+            child: None,
+            input_tx: None,
+            output_rx: None,
+            reader_threads: Vec::new(),
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.child.is_some()
+    }
+
+    fn start(&mut self, terminal_id: i32, command_line: &[u8]) -> Result<u32> {
+        let args: Vec<String> =
+            serde_json::from_slice(command_line).context("Invalid exec command line")?;
+        let Some((program, args)) = args.split_first() else {
+            return Err(anyhow!("Empty exec command line"));
+        };
+        log::info!("Exec {}: {} {:?}", terminal_id, program, args);
+        let mut child = std::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", program))?;
+
+        let (input_tx, input_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
+        let (output_tx, output_rx) = mpsc::sync_channel::<(bool, Vec<u8>)>(CHANNEL_BUFFER_SIZE);
+        if let Some(mut stdin) = child.stdin.take() {
+            thread::spawn(move || {
+                while let Ok(data) = input_rx.recv() {
+                    if let Err(e) = stdin.write_all(&data).and_then(|_| stdin.flush()) {
+                        log::debug!("Exec {} stdin write error: {}", terminal_id, e);
+                        break;
+                    }
+                }
+                // stdin is closed when it is dropped here.
+            });
+        }
+        let stdout = child
+            .stdout
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>);
+        let stderr = child
+            .stderr
+            .take()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>);
+        for (is_stderr, reader) in [(false, stdout), (true, stderr)] {
+            let Some(mut reader) = reader else {
+                continue;
+            };
+            let output_tx = output_tx.clone();
+            self.reader_threads.push(thread::spawn(move || {
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            // Unlike the terminal, block instead of dropping data when the channel is full,
+                            // the output of a command must be complete.
+                            if output_tx.send((is_stderr, buf[..n].to_vec())).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            log::error!("Exec {} read error: {}", terminal_id, e);
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+
+        let pid = child.id();
+        self.child = Some(child);
+        self.input_tx = Some(input_tx);
+        self.output_rx = Some(output_rx);
+        Ok(pid)
+    }
+
+    fn write_stdin(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            self.input_tx = None;
+        } else if let Some(input_tx) = &self.input_tx {
+            input_tx.send(data.to_vec()).ok();
+        }
+    }
+
+    /// The exit code if the command has exited and stdout and stderr are closed.
+    /// The output read after this returns `Some` is complete.
+    fn try_exit_code(&mut self) -> Option<i32> {
+        if self.reader_threads.iter().any(|t| !t.is_finished()) {
+            return None;
+        }
+        let status = self.child.as_mut()?.try_wait().ok()??;
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Some(128 + signal);
+            }
+        }
+        Some(status.code().unwrap_or(-1))
+    }
+
+    fn stop(&mut self) {
+        self.input_tx = None;
+        if let Some(mut child) = self.child.take() {
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+        // The threads are not joined, the pipes may be inherited by a process that is still running.
+        // They exit on EOF, or when the channels are dropped.
+        self.output_rx = None;
+        self.reader_threads.clear();
+    }
+}
+
+impl Drop for ExecSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
 /// Persistent terminal service that can survive connection drops
 pub struct PersistentTerminalService {
     service_id: String,
     sessions: HashMap<i32, Arc<Mutex<TerminalSession>>>,
+    // Exec sessions are not kept after the connection drops.
+    execs: HashMap<i32, Arc<Mutex<ExecSession>>>,
     pub created_at: Instant,
     last_activity: Instant,
     pub is_persistent: bool,
@@ -564,6 +735,7 @@ impl PersistentTerminalService {
         Self {
             service_id,
             sessions: HashMap::new(),
+            execs: HashMap::new(),
             created_at: Instant::now(),
             last_activity: Instant::now(),
             is_persistent,
@@ -622,6 +794,22 @@ impl PersistentTerminalService {
     }
 }
 
+fn set_terminal_data(terminal_data: &mut TerminalData, data: Vec<u8>) {
+    // Compress data if it exceeds threshold
+    if data.len() > COMPRESS_THRESHOLD {
+        let compressed = compress::compress(&data);
+        if compressed.len() < data.len() {
+            terminal_data.data = bytes::Bytes::from(compressed);
+            terminal_data.compressed = true;
+        } else {
+            // Compression didn't help, send uncompressed
+            terminal_data.data = bytes::Bytes::from(data);
+        }
+    } else {
+        terminal_data.data = bytes::Bytes::from(data);
+    }
+}
+
 pub struct TerminalServiceProxy {
     service_id: String,
     is_persistent: bool,
@@ -676,6 +864,9 @@ impl TerminalServiceProxy {
         };
         service.lock().unwrap().update_activity();
         match &action.union {
+            Some(terminal_action::Union::Open(open)) if is_exec_open(open) => {
+                self.handle_exec_open(&mut service.lock().unwrap(), open)
+            }
             Some(terminal_action::Union::Open(open)) => {
                 self.handle_open(&mut service.lock().unwrap(), open)
             }
@@ -689,6 +880,15 @@ impl TerminalServiceProxy {
                 self.handle_resize(session, resize)
             }
             Some(terminal_action::Union::Data(data)) => {
+                let exec = service
+                    .lock()
+                    .unwrap()
+                    .execs
+                    .get(&data.terminal_id)
+                    .cloned();
+                if let Some(exec) = exec {
+                    return self.handle_exec_data(exec, data);
+                }
                 let session = service
                     .lock()
                     .unwrap()
@@ -698,6 +898,10 @@ impl TerminalServiceProxy {
                 self.handle_data(session, data)
             }
             Some(terminal_action::Union::Close(close)) => {
+                let exec = service.lock().unwrap().execs.remove(&close.terminal_id);
+                if let Some(exec) = exec {
+                    return self.handle_exec_close(exec, close);
+                }
                 self.handle_close(&mut service.lock().unwrap(), close)
             }
             _ => Ok(None),
@@ -989,6 +1193,132 @@ impl TerminalServiceProxy {
         }
     }
 
+    fn handle_exec_open(
+        &self,
+        service: &mut PersistentTerminalService,
+        open: &OpenTerminal,
+    ) -> Result<Option<TerminalResponse>> {
+        if open.terminal_id < 0 {
+            return Err(anyhow!("Invalid exec terminal id {}", open.terminal_id));
+        }
+        if service.sessions.contains_key(&open.terminal_id)
+            || service.execs.contains_key(&open.terminal_id)
+        {
+            return Err(anyhow!("Terminal {} already exists", open.terminal_id));
+        }
+        // `std::process::Command` can't run the command as another user.
+        #[cfg(target_os = "windows")]
+        if self.user_token.is_some() {
+            return Err(anyhow!(
+                "Exec is not supported for the logged-on user session yet"
+            ));
+        }
+        service
+            .execs
+            .insert(open.terminal_id, Arc::new(Mutex::new(ExecSession::new())));
+
+        let mut response = TerminalResponse::new();
+        let mut opened = TerminalOpened::new();
+        opened.terminal_id = open.terminal_id;
+        opened.success = true;
+        opened.message = "Exec session opened".to_string();
+        opened.service_id = service.service_id.clone();
+        response.set_opened(opened);
+        Ok(Some(response))
+    }
+
+    fn handle_exec_data(
+        &self,
+        exec: Arc<Mutex<ExecSession>>,
+        data: &TerminalData,
+    ) -> Result<Option<TerminalResponse>> {
+        let mut exec = exec.lock().unwrap();
+        if exec.is_started() {
+            exec.write_stdin(&data.data);
+            return Ok(None);
+        }
+        let command_line = if data.compressed {
+            compress::decompress(&data.data)
+        } else {
+            data.data.to_vec()
+        };
+        if let Err(err) = exec.start(data.terminal_id, &command_line) {
+            // Close the session, so that the peer can tell the command is not run.
+            if let Some(service) = get_service(&self.service_id) {
+                service.lock().unwrap().execs.remove(&data.terminal_id);
+            }
+            return Err(err);
+        }
+        Ok(None)
+    }
+
+    fn handle_exec_close(
+        &self,
+        exec: Arc<Mutex<ExecSession>>,
+        close: &CloseTerminal,
+    ) -> Result<Option<TerminalResponse>> {
+        exec.lock().unwrap().stop();
+        let mut response = TerminalResponse::new();
+        let mut closed = TerminalClosed::new();
+        closed.terminal_id = close.terminal_id;
+        closed.exit_code = -1; // -1 indicates forced termination
+        response.set_closed(closed);
+        Ok(Some(response))
+    }
+
+    fn read_exec_outputs(
+        &self,
+        service: &Arc<Mutex<PersistentTerminalService>>,
+        responses: &mut Vec<TerminalResponse>,
+    ) {
+        let execs: Vec<(i32, Arc<Mutex<ExecSession>>)> = service
+            .lock()
+            .unwrap()
+            .execs
+            .iter()
+            .map(|(id, exec)| (*id, exec.clone()))
+            .collect();
+        let mut exited = Vec::new();
+        for (terminal_id, exec_arc) in execs {
+            let Ok(mut exec) = exec_arc.try_lock() else {
+                continue;
+            };
+            // Check before reading, so that no output is left after the closed message.
+            let exit_code = exec.try_exit_code();
+            if let Some(output_rx) = &exec.output_rx {
+                while let Ok((is_stderr, data)) = output_rx.try_recv() {
+                    let mut terminal_data = TerminalData::new();
+                    terminal_data.terminal_id = if is_stderr {
+                        exec_stderr_id(terminal_id)
+                    } else {
+                        terminal_id
+                    };
+                    set_terminal_data(&mut terminal_data, data);
+                    let mut response = TerminalResponse::new();
+                    response.set_data(terminal_data);
+                    responses.push(response);
+                }
+            }
+            if let Some(exit_code) = exit_code {
+                log::info!("Exec {} exited with {}", terminal_id, exit_code);
+                exec.stop();
+                exited.push(terminal_id);
+                let mut response = TerminalResponse::new();
+                let mut closed = TerminalClosed::new();
+                closed.terminal_id = terminal_id;
+                closed.exit_code = exit_code;
+                response.set_closed(closed);
+                responses.push(response);
+            }
+        }
+        if !exited.is_empty() {
+            let mut service = service.lock().unwrap();
+            for terminal_id in exited {
+                service.execs.remove(&terminal_id);
+            }
+        }
+    }
+
     pub fn read_outputs(&self) -> Vec<TerminalResponse> {
         let service = match get_service(&self.service_id) {
             Some(s) => s,
@@ -1009,6 +1339,7 @@ impl TerminalServiceProxy {
 
         let mut responses = Vec::new();
         let mut closed_terminals = Vec::new();
+        self.read_exec_outputs(&service, &mut responses);
 
         // Process each session with its own lock
         for (terminal_id, session_arc) in sessions {
@@ -1056,20 +1387,7 @@ impl TerminalServiceProxy {
                     let mut response = TerminalResponse::new();
                     let mut terminal_data = TerminalData::new();
                     terminal_data.terminal_id = terminal_id;
-
-                    // Compress data if it exceeds threshold
-                    if data.len() > COMPRESS_THRESHOLD {
-                        let compressed = compress::compress(&data);
-                        if compressed.len() < data.len() {
-                            terminal_data.data = bytes::Bytes::from(compressed);
-                            terminal_data.compressed = true;
-                        } else {
-                            // Compression didn't help, send uncompressed
-                            terminal_data.data = bytes::Bytes::from(data);
-                        }
-                    } else {
-                        terminal_data.data = bytes::Bytes::from(data);
-                    }
+                    set_terminal_data(&mut terminal_data, data);
 
                     response.set_data(terminal_data);
                     responses.push(response);
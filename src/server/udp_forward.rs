@@ -0,0 +1,247 @@
+//! UDP port forwarding over a port forward connection.
+//!
+//! A `PortForward` login request with `host = "udp:<host>"` forwards UDP to `<host>:<port>`.
+//! After login, datagrams are sent both ways as frames, `flow id (u32 BE) + length (u16 BE) + payload`.
+//! The controlling side gives every source address its own flow, and the controlled side sends the
+//! datagrams of every flow from a socket of its own, so that the replies can be told apart.
+//! Flows idle for [`FLOW_IDLE_TIMEOUT`] are removed on both sides.
+
+use hbb_common::{
+    bail, log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{lookup_host, TcpStream, UdpSocket},
+        sync::mpsc,
+        task::JoinHandle,
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const PREFIX: &str = "udp:";
+pub const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FLOWS: usize = 256;
+const HEADER_LEN: usize = 6;
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// The host of a UDP forward rule or `PortForward` login request, e.g. `udp:8.8.8.8`,
+/// `None` if it is TCP.
+pub fn parse_host(host: &str) -> Option<&str> {
+    host.strip_prefix(PREFIX)
+}
+
+pub fn encode_frame(flow: u32, data: &[u8]) -> Vec<u8> {
+    let len = data.len().min(MAX_DATAGRAM_SIZE);
+    let mut frame = Vec::with_capacity(HEADER_LEN + len);
+    frame.extend_from_slice(&flow.to_be_bytes());
+    frame.extend_from_slice(&(len as u16).to_be_bytes());
+    frame.extend_from_slice(&data[..len]);
+    frame
+}
+
+/// Split the byte stream back into frames.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<(u32, Vec<u8>)> {
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+        let flow = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]);
+        let len = u16::from_be_bytes([self.buf[4], self.buf[5]]) as usize;
+        if self.buf.len() < HEADER_LEN + len {
+            return None;
+        }
+        let data = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        Some((flow, data))
+    }
+}
+
+/// Forward UDP to `addr`, returns the socket to be used as the port forward socket of the connection.
+pub async fn start(addr: &str) -> ResultType<TcpStream> {
+    let Some(target) = lookup_host(addr).await?.next() else {
+        bail!("Failed to resolve {}", addr);
+    };
+    let (control, inner) = super::reverse_forward::loopback_pair().await?;
+    tokio::spawn(async move {
+        log::info!("UDP forward to {} started", target);
+        if let Err(err) = serve(inner, target).await {
+            log::error!("UDP forward to {} error: {}", target, err);
+        }
+        log::info!("UDP forward to {} closed", target);
+    });
+    Ok(control)
+}
+
+struct Flow {
+    socket: Arc<UdpSocket>,
+    last_active: Instant,
+    recv_task: JoinHandle<()>,
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+async fn new_flow(
+    flow: u32,
+    target: SocketAddr,
+    tx: mpsc::Sender<(u32, Vec<u8>)>,
+) -> ResultType<Flow> {
+    let bind = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = Arc::new(UdpSocket::bind(bind).await?);
+    socket.connect(target).await?;
+    let recv_socket = socket.clone();
+    let recv_task = tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while let Ok(n) = recv_socket.recv(&mut buf).await {
+            if tx.send((flow, buf[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Flow {
+        socket,
+        last_active: Instant::now(),
+        recv_task,
+    })
+}
+
+async fn serve(control: TcpStream, target: SocketAddr) -> ResultType<()> {
+    let (mut reader, mut writer) = control.into_split();
+    let (tx, mut rx) = mpsc::channel::<(u32, Vec<u8>)>(64);
+    let mut flows: HashMap<u32, Flow> = HashMap::new();
+    let mut decoder = FrameDecoder::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut timer = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            res = reader.read(&mut buf) => {
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                decoder.feed(&buf[..n]);
+                while let Some((id, data)) = decoder.next_frame() {
+                    if !flows.contains_key(&id) {
+                        if flows.len() >= MAX_FLOWS {
+                            log::warn!("Too many UDP flows to {}, drop datagram of flow {}", target, id);
+                            continue;
+                        }
+                        match new_flow(id, target, tx.clone()).await {
+                            Ok(flow) => {
+                                flows.insert(id, flow);
+                            }
+                            Err(err) => {
+                                log::error!("Failed to create UDP flow to {}: {}", target, err);
+                                continue;
+                            }
+                        }
+                    }
+                    if let Some(flow) = flows.get_mut(&id) {
+                        flow.last_active = Instant::now();
+                        if let Err(err) = flow.socket.send(&data).await {
+                            log::debug!("Failed to send UDP datagram to {}: {}", target, err);
+                        }
+                    }
+                }
+            }
+            Some((id, data)) = rx.recv() => {
+                if let Some(flow) = flows.get_mut(&id) {
+                    flow.last_active = Instant::now();
+                }
+                writer.write_all(&encode_frame(id, &data)).await?;
+            }
+            _ = timer.tick() => {
+                flows.retain(|_, flow| flow.last_active.elapsed() < FLOW_IDLE_TIMEOUT);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let mut decoder = FrameDecoder::default();
+        let mut stream = encode_frame(1, b"hello");
+        stream.extend(encode_frame(2, b""));
+        stream.extend(encode_frame(3, b"world"));
+        decoder.feed(&stream[..4]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.feed(&stream[4..]);
+        assert_eq!(decoder.next_frame(), Some((1, b"hello".to_vec())));
+        assert_eq!(decoder.next_frame(), Some((2, vec![])));
+        assert_eq!(decoder.next_frame(), Some((3, b"world".to_vec())));
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(parse_host("udp:8.8.8.8"), Some("8.8.8.8"));
+        assert_eq!(parse_host("8.8.8.8"), None);
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        // An echo server, which replies with the flow it sees the datagram from.
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let mut reply = buf[..n].to_vec();
+                reply.extend_from_slice(format!("@{}", from.port()).as_bytes());
+                echo.send_to(&reply, from).await.ok();
+            }
+        });
+
+        let mut control = start(&echo_addr.to_string()).await.unwrap();
+        control.write_all(&encode_frame(7, b"a")).await.unwrap();
+        control.write_all(&encode_frame(8, b"b")).await.unwrap();
+        control.write_all(&encode_frame(7, b"c")).await.unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        let mut replies = HashMap::<u32, Vec<String>>::new();
+        let mut buf = [0u8; 1024];
+        while replies.values().map(|v| v.len()).sum::<usize>() < 3 {
+            let n = tokio::time::timeout(Duration::from_secs(5), control.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            decoder.feed(&buf[..n]);
+            while let Some((flow, data)) = decoder.next_frame() {
+                replies
+                    .entry(flow)
+                    .or_default()
+                    .push(String::from_utf8(data).unwrap());
+            }
+        }
+        let (a, c) = (&replies[&7][0], &replies[&7][1]);
+        let b = &replies[&8][0];
+        assert!(a.starts_with("a@") && c.starts_with("c@") && b.starts_with("b@"));
+        // The same flow is sent from the same socket, different flows from different ones.
+        assert_eq!(a[1..], c[1..]);
+        assert_ne!(a[1..], b[1..]);
+    }
+}
@@ -351,6 +351,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
 
         // for tmp use, without real conn id
         let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+        let mut received_digests = crate::file_digest::Received::default();
+        let mut received_deltas = crate::file_delta::Receiver::default();
+        let mut received_scans = crate::file_scan::Pending::default();
 
         #[cfg(target_os = "windows")]
         let is_authorized = self.cm.is_authorized(self.conn_id);
@@ -443,10 +446,10 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     if let ipc::FS::WriteBlock { id, file_num, data: _, compressed } = fs {
                                         if let Ok(bytes) = self.stream.next_raw().await {
                                             fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed};
-                                            handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                            handle_fs(fs, &mut write_jobs, &mut received_digests, &mut received_deltas, &mut received_scans, &self.tx, Some(&tx_log)).await;
                                         }
                                     } else {
-                                        handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                        handle_fs(fs, &mut write_jobs, &mut received_digests, &mut received_deltas, &mut received_scans, &self.tx, Some(&tx_log)).await;
                                     }
                                     let log = fs::serialize_transfer_jobs(&write_jobs);
                                     self.cm.ui_handler.file_transfer_log("transfer", &log);
@@ -674,6 +677,9 @@ pub async fn start_listen<T: InvokeUiCM>(
 ) {
     let mut current_id = 0;
     let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+    let mut received_digests = crate::file_digest::Received::default();
+    let mut received_deltas = crate::file_delta::Receiver::default();
+    let mut received_scans = crate::file_scan::Pending::default();
     loop {
         match rx.recv().await {
             Some(Data::Login {
@@ -720,7 +726,16 @@ pub async fn start_listen<T: InvokeUiCM>(
                 cm.new_message(current_id, text);
             }
             Some(Data::FS(fs)) => {
-                handle_fs(fs, &mut write_jobs, &tx, None).await;
+                handle_fs(
+                    fs,
+                    &mut write_jobs,
+                    &mut received_digests,
+                    &mut received_deltas,
+                    &mut received_scans,
+                    &tx,
+                    None,
+                )
+                .await;
             }
             Some(Data::Close) => {
                 break;
@@ -747,6 +762,9 @@ pub async fn start_listen<T: InvokeUiCM>(
 async fn handle_fs(
     fs: ipc::FS,
     write_jobs: &mut Vec<fs::TransferJob>,
+    received_digests: &mut crate::file_digest::Received,
+    received_deltas: &mut crate::file_delta::Receiver,
+    received_scans: &mut crate::file_scan::Pending,
     tx: &UnboundedSender<Data>,
     tx_log: Option<&UnboundedSender<String>>,
 ) {
@@ -788,7 +806,11 @@ async fn handle_fs(
             overwrite_detection,
             total_size,
             conn_id,
+            digests,
         } => {
+            if digests && crate::file_digest::is_enabled() {
+                received_digests.expect(id);
+            }
             // cm has no show_hidden context
             // dummy remote, show_hidden, is_remote
             let mut job = fs::TransferJob::new_write(
@@ -814,6 +836,9 @@ async fn handle_fs(
             write_jobs.push(job);
         }
         ipc::FS::CancelWrite { id } => {
+            received_digests.remove(id);
+            received_deltas.remove(id);
+            received_scans.remove(id);
             if let Some(job) = fs::remove_job(id, write_jobs) {
                 job.remove_download_file();
                 tx_log.map(|tx: &UnboundedSender<String>| {
@@ -822,13 +847,51 @@ async fn handle_fs(
             }
         }
         ipc::FS::WriteDone { id, file_num } => {
+            received_deltas.remove(id);
+            let received = received_digests.take(id);
+            received_digests.remove(id);
+            let scans = received_scans.take(id);
             if let Some(job) = fs::remove_job(id, write_jobs) {
-                job.modify_time();
-                send_raw(fs::new_done(id, file_num), tx);
-                tx_log.map(|tx| tx.send(serialize_transfer_job(&job, true, false, "")));
+                let tx = tx.clone();
+                let tx_log = tx_log.cloned();
+                // Not to hold the other jobs while scanning and verifying.
+                tokio::spawn(async move {
+                    // The errors of the previous files are sent before the done too.
+                    scans.await;
+                    if let Err(err) = crate::file_scan::scan_received(&job, job.file_num()).await {
+                        let err = err.to_string();
+                        tx_log.map(|tx| tx.send(serialize_transfer_job(&job, false, false, &err)));
+                        send_raw(fs::new_error(id, err, job.file_num()), &tx);
+                        return;
+                    }
+                    job.modify_time();
+                    // Before the done, for a sync of the peer to report them.
+                    if let Some(received) = received {
+                        for (num, _, err) in crate::file_digest::verify(&received).await {
+                            send_raw(fs::new_error(id, err, num), &tx);
+                        }
+                    }
+                    send_raw(fs::new_done(id, file_num), &tx);
+                    tx_log.map(|tx| tx.send(serialize_transfer_job(&job, true, false, "")));
+                });
             }
         }
+        ipc::FS::FileDigest {
+            id,
+            file_num,
+            digest,
+        } => {
+            received_digests.on_digest(&FileTransferBlock {
+                id,
+                file_num,
+                data: digest.into(),
+                ..Default::default()
+            });
+        }
         ipc::FS::WriteError { id, file_num, err } => {
+            received_digests.remove(id);
+            received_deltas.remove(id);
+            received_scans.remove(id);
             if let Some(job) = fs::remove_job(id, write_jobs) {
                 tx_log.map(|tx| tx.send(serialize_transfer_job(&job, false, false, &err)));
                 send_raw(fs::new_error(job.id(), err, file_num), tx);
@@ -841,6 +904,28 @@ async fn handle_fs(
             compressed,
         } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
+                // The previous file is complete when the blocks of the next one come.
+                if file_num != job.file_num() {
+                    let scan = crate::file_scan::scan_received(job, job.file_num());
+                    let prev_file_num = job.file_num();
+                    // The job as of the end of the file, its error set if the scan fails.
+                    let job_log = tx_log
+                        .map(|tx| (tx.clone(), serialize_transfer_job(job, false, false, "")));
+                    let tx = tx.clone();
+                    received_scans.spawn(id, async move {
+                        if let Err(err) = scan.await {
+                            let err = err.to_string();
+                            if let Some((tx_log, job_log)) = job_log {
+                                let mut value: serde_json::Value =
+                                    serde_json::from_str(&job_log).unwrap_or_default();
+                                value["error"] = err.clone().into();
+                                tx_log.send(value.to_string()).ok();
+                            }
+                            send_raw(fs::new_error(id, err, prev_file_num), &tx);
+                        }
+                    });
+                }
+                received_digests.on_block(job, file_num);
                 if let Err(err) = job
                     .write(FileTransferBlock {
                         id,
@@ -892,6 +977,7 @@ async fn handle_fs(
                                     DigestCheckResult::NeedConfirm(mut digest) => {
                                         // upload to server, but server has the same file, request
                                         digest.is_upload = is_upload;
+                                        received_deltas.on_need_confirm(id, file_num);
                                         let mut msg_out = Message::new();
                                         let mut fr = FileResponse::new();
                                         fr.set_digest(digest);
@@ -912,9 +998,46 @@ async fn handle_fs(
                 }
             }
         }
+        ipc::FS::Delta {
+            id,
+            file_num,
+            blk_id,
+            data,
+        } => {
+            let Some(job) = fs::get_job(id, write_jobs) else {
+                return;
+            };
+            let req = if blk_id == crate::file_delta::REQUEST_BLK_ID {
+                if !received_deltas.take_confirming(id, file_num) {
+                    log::warn!("Ignored the delta request of a file not to be overwritten");
+                    return;
+                }
+                let tx = tx.clone();
+                if received_deltas.start(job, file_num, move |msg| send_raw(msg, &tx)) {
+                    return;
+                }
+                // No copy to apply a delta to.
+                crate::file_delta::confirm_request(id, file_num, false)
+            } else {
+                let block = FileTransferBlock {
+                    id,
+                    file_num,
+                    blk_id,
+                    data: data.into(),
+                    ..Default::default()
+                };
+                let Some(req) = received_deltas.on_block(&block).await else {
+                    return;
+                };
+                req
+            };
+            job.confirm(&req).await;
+            send_raw(new_send_confirm(req), tx);
+        }
         ipc::FS::SendConfirm(bytes) => {
             if let Ok(r) = FileTransferSendConfirmRequest::parse_from_bytes(&bytes) {
                 if let Some(job) = fs::get_job(r.id, write_jobs) {
+                    received_deltas.on_confirm(r.id, r.file_num);
                     job.confirm(&r).await;
                 }
             }